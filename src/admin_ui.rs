@@ -8,6 +8,13 @@ use std::{
     sync::{Arc, Mutex}
 };
 use crate::{
+    export,
+    html,
+    links::BrokenLink,
+    notify,
+    promise_util,
+    search,
+    site_settings::{Fallback, FeedSettings, Settings},
     storage::Storage,
     web_site_session,
     lmdb_web_site,
@@ -75,6 +82,112 @@ impl web_session::Server for AdminUiSession {
                     }
                     Ok(())
                 },
+                _ if path.starts_with("sites/") => {
+                    let (name, query) = match path.find('?') {
+                        Some(i) => (&path["sites/".len()..i], &path[i + 1..]),
+                        None => (&path["sites/".len()..], ""),
+                    };
+                    let start = search::query_param(query, "start");
+                    let lmdb_site = storage.lock().unwrap().get(name)?;
+                    let listing = lmdb_site.list("", start.as_deref(), SITE_LISTING_LIMIT)?;
+                    let next_url = listing.next.as_ref().map(|next| {
+                        format!("/sites/{}?start={}", name, search::percent_encode(next))
+                    });
+                    let settings = lmdb_site.settings()?;
+                    let (fallback_kind, fallback_path) = match settings.fallback.clone() {
+                        Fallback::None => ("none", String::new()),
+                        Fallback::Spa { path } => ("spa", path),
+                        Fallback::NotFound { path } => ("notFound", path),
+                    };
+                    let mut content = results.get().init_content();
+                    content.set_status_code(web_session::response::SuccessCode::Ok);
+                    content.set_mime_type("text/html");
+                    if !ignore_body {
+                        let body = Site {
+                            name: name,
+                            listing: listing,
+                            first_page: start.is_none(),
+                            next_url: next_url,
+                            fallback_kind: fallback_kind,
+                            fallback_path: fallback_path,
+                            default_language: settings.default_language,
+                            feed: settings.feed,
+                            site_url: settings.site_url,
+                        }.render().unwrap();
+                        content.get_body().set_bytes(body.as_bytes());
+                    }
+                    Ok(())
+                },
+                _ if path.starts_with("changes/") => {
+                    // Wait for the next change to the site, so the site's
+                    // page can refresh itself.
+                    let name = &path["changes/".len()..];
+                    let lmdb_site = storage.lock().unwrap().get(name)?;
+                    let (sender, receiver) = futures::channel::oneshot::channel();
+                    let _subscription = lmdb_site.subscribe("", Box::new(NextChange(Some(sender))));
+                    let change = receiver.await
+                        .map_err(|_| capnp::Error::disconnected(String::from("The site was closed")))?;
+                    let mut content = results.get().init_content();
+                    content.set_status_code(web_session::response::SuccessCode::Ok);
+                    content.set_mime_type("application/json");
+                    if !ignore_body {
+                        let body = serde_json::json!({
+                            "path": change.path,
+                            "version": change.version,
+                        });
+                        content.get_body().set_bytes(body.to_string().as_bytes());
+                    }
+                    Ok(())
+                },
+                _ if path.starts_with("links/") => {
+                    let name = &path["links/".len()..];
+                    let lmdb_site = storage.lock().unwrap().get(name)?;
+                    let broken = lmdb_site.broken_links()?;
+                    let mut content = results.get().init_content();
+                    content.set_status_code(web_session::response::SuccessCode::Ok);
+                    content.set_mime_type("text/html");
+                    if !ignore_body {
+                        let body = Links {
+                            name: name,
+                            broken: broken,
+                        }.render().unwrap();
+                        content.get_body().set_bytes(body.as_bytes());
+                    }
+                    Ok(())
+                },
+                _ if path.starts_with("export/") => {
+                    let file_name = &path["export/".len()..];
+                    let archive = match file_name.rfind('.') {
+                        Some(i) => export::Format::from_name(&file_name[i+1..])
+                            .map(|format| (&file_name[..i], format)),
+                        None => None,
+                    };
+                    match archive {
+                        Some((name, format)) => {
+                            let lmdb_site = storage.lock().unwrap().get(name)?;
+                            let mut content = results.get().init_content();
+                            content.set_status_code(web_session::response::SuccessCode::Ok);
+                            content.set_mime_type(format.mime_type());
+                            content.reborrow().get_disposition().set_download(file_name);
+                            if !ignore_body {
+                                let body = export::export(&lmdb_site, format, std::io::Cursor::new(vec![]))
+                                    .map_err(|e| capnp::Error::failed(format!("{:?}", e)))?
+                                    .into_inner();
+                                content.get_body().set_bytes(&body);
+                            }
+                        },
+                        None => {
+                            let mut client_error = results.get().init_client_error();
+                            client_error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                            if !ignore_body {
+                                client_error.set_description_html(
+                                    include_str!("../static/not-found.html")
+                                );
+                            }
+                        },
+                    }
+                    Ok(())
+                },
                 _ => {
                     let mut client_error = results.get().init_client_error();
                     client_error.set_status_code(web_session::response::ClientErrorCode::NotFound);
@@ -101,9 +214,8 @@ impl web_session::Server for AdminUiSession {
                 "offer-site" => {
                     let content = params.get_content()?.get_content()?;
                     let content_str = std::str::from_utf8(content)?;
-                    let lmdb_site = storage.lock().unwrap().get(content_str)
-                        .map_err(lmdb_web_site::db_err)?;
-                    let session = web_site_session::new(capnp_rpc::new_client(lmdb_site));
+                    let lmdb_site = storage.lock().unwrap().get(content_str)?;
+                    let session = web_site_session::new(lmdb_site);
                     let mut req = session_ctx.offer_request();
                     let ws_client: web_session::Client = capnp_rpc::new_client(session);
                     req.get().get_cap().set_as_capability(ws_client.client.hook);
@@ -111,6 +223,45 @@ impl web_session::Server for AdminUiSession {
                     req.send().promise.await?;
                     Ok(())
                 },
+                _ if path.starts_with("compact/") => {
+                    let name = &path["compact/".len()..];
+                    let lmdb_site = storage.lock().unwrap().get(name)?;
+                    // Copying the database may take a while, so it's done
+                    // off the event loop.
+                    let compaction = tokio::task::spawn_blocking(move || {
+                        let compaction = lmdb_site.claim().and_then(|()| lmdb_site.compact());
+                        lmdb_site.release()?;
+                        compaction
+                    }).await.map_err(|e| capnp::Error::failed(format!("Compacting the site failed: {}", e)))??;
+                    let mut content = results.get().init_content();
+                    content.set_status_code(web_session::response::SuccessCode::Ok);
+                    content.set_mime_type("text/plain");
+                    content.get_body().set_bytes(format!(
+                        "Reclaimed {} bytes; the database is now {} bytes.",
+                        compaction.reclaimed(),
+                        compaction.after,
+                    ).as_bytes());
+                    Ok(())
+                },
+                _ if path.starts_with("settings/") => {
+                    let name = &path["settings/".len()..];
+                    let content = params.get_content()?.get_content()?;
+                    match serde_json::from_slice::<Settings>(content) {
+                        Ok(settings) => {
+                            storage.lock().unwrap().get(name)?
+                                .set_settings(&settings)?;
+                            let mut content = results.get().init_content();
+                            content.set_status_code(web_session::response::SuccessCode::Ok);
+                            content.set_mime_type("text/plain");
+                        },
+                        Err(e) => {
+                            let mut client_error = results.get().init_client_error();
+                            client_error.set_status_code(web_session::response::ClientErrorCode::BadRequest);
+                            client_error.set_description_html(&format!("Invalid settings: {}", html::escape(&e.to_string())));
+                        },
+                    }
+                    Ok(())
+                },
                 _ => {
                     // TODO(cleanup): dedup from get()
                     let mut client_error = results.get().init_client_error();
@@ -125,8 +276,48 @@ impl web_session::Server for AdminUiSession {
     }
 }
 
+/// Passes on the first change it is told about.
+struct NextChange(Option<futures::channel::oneshot::Sender<notify::Change>>);
+
+impl notify::Observer for NextChange {
+    fn changed(&mut self, change: &notify::Change) -> promise_util::Promise {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.send(change.clone());
+        }
+        promise_util::ok()
+    }
+}
+
+/// Maximum number of paths to show on each page of a site's listing.
+const SITE_LISTING_LIMIT: usize = 1000;
+
 #[derive(Debug, Template)]
 #[template(path = "index.html")]
 struct Index {
     sites: Vec<String>,
 }
+
+#[derive(Debug, Template)]
+#[template(path = "site.html")]
+struct Site<'a> {
+    name: &'a str,
+    listing: lmdb_web_site::Listing,
+
+    /// Whether the listing starts at the first path.
+    first_page: bool,
+
+    /// Where to get the next page of the listing, if there is one.
+    next_url: Option<String>,
+    fallback_kind: &'a str,
+    fallback_path: String,
+    default_language: String,
+    feed: FeedSettings,
+    site_url: String,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "links.html")]
+struct Links<'a> {
+    name: &'a str,
+    broken: Vec<BrokenLink>,
+}
@@ -0,0 +1,126 @@
+use crate::lmdb_web_site::LMDBWebSite;
+use capnp::Error;
+use futures::{
+    future::{self, BoxFuture, FutureExt, LocalBoxFuture},
+    stream::{self, StreamExt, TryStreamExt},
+};
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Where `reads()` reads the entities.
+#[derive(Clone, Copy, Debug)]
+pub enum Mode {
+    /// On the event loop, as every request was served before site handles
+    /// could leave the thread running the RPC system.
+    EventLoop,
+
+    /// On the blocking thread pool, with the event loop waiting on the
+    /// results, as `Getter.get()` does.
+    BlockingPool,
+
+    /// On the runtime's worker threads, each read through its own handle
+    /// to the site.
+    Workers,
+}
+
+impl Mode {
+    pub fn all() -> [Mode; 3] {
+        [Mode::EventLoop, Mode::BlockingPool, Mode::Workers]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::EventLoop => "event loop",
+            Mode::BlockingPool => "blocking pool",
+            Mode::Workers => "worker threads",
+        }
+    }
+}
+
+/// How a run of `reads()` went.
+#[derive(Clone, Debug)]
+pub struct Timing {
+    /// The number of reads made.
+    pub reads: usize,
+    pub elapsed: Duration,
+
+    /// The longest the event loop went without getting to run other
+    /// tasks, as seen by a timer ticking every millisecond.
+    pub max_stall: Duration,
+}
+
+impl Timing {
+    pub fn reads_per_sec(&self) -> f64 {
+        self.reads as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Read the entities at every path in `site` `rounds` times, with up to
+/// `concurrency` reads in flight, wherever `mode` says. Must be run on a
+/// `LocalSet` in a threaded runtime.
+pub async fn reads(site: &LMDBWebSite,
+                   mode: Mode,
+                   concurrency: usize,
+                   rounds: usize) -> Result<Timing, Error> {
+    let mut paths = vec![];
+    site.for_each_path("", |path, _| -> Result<(), Error> {
+        paths.push(String::from(path));
+        Ok(())
+    })?;
+
+    let done = Rc::new(Cell::new(false));
+    let ticker = tokio::task::spawn_local({
+        let done = done.clone();
+        // When the ticker should next get to run. It is due as soon as it's
+        // spawned, though reads on the event loop may not let it.
+        let mut due = Instant::now();
+        async move {
+            let tick = Duration::from_millis(1);
+            let mut max_stall = Duration::default();
+            loop {
+                max_stall = max_stall.max(Instant::now().saturating_duration_since(due));
+                if done.get() {
+                    return max_stall
+                }
+                due = Instant::now() + tick;
+                tokio::time::delay_for(tick).await;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let result = stream::iter(paths.iter().cycle().take(paths.len() * rounds))
+        .map(|path| read(site, path, mode))
+        .buffer_unordered(concurrency.max(1))
+        .try_for_each(|()| future::ready(Ok(())))
+        .await;
+    let elapsed = start.elapsed();
+    done.set(true);
+    let max_stall = ticker.await.map_err(|e| Error::failed(e.to_string()))?;
+    result?;
+    Ok(Timing {
+        reads: paths.len() * rounds,
+        elapsed: elapsed,
+        max_stall: max_stall,
+    })
+}
+
+fn read(site: &LMDBWebSite, path: &str, mode: Mode) -> LocalBoxFuture<'static, Result<(), Error>> {
+    let site = site.clone();
+    let path = String::from(path);
+    match mode {
+        Mode::EventLoop => async move { site.read_entities(&path, false).await }.boxed_local(),
+        Mode::BlockingPool => async move { site.read_entities(&path, true).await }.boxed_local(),
+        Mode::Workers => {
+            let read: BoxFuture<'static, _> = async move {
+                site.read_entities(&path, false).await
+            }.boxed();
+            tokio::spawn(read)
+                .map(|result| result.map_err(|e| Error::failed(e.to_string()))?)
+                .boxed_local()
+        },
+    }
+}
@@ -0,0 +1,119 @@
+use lmdb::Transaction;
+use sha2::{Digest, Sha256};
+
+/// The length of the hashes bodies are stored under.
+pub const HASH_LEN: usize = 32;
+
+/// Get the hash `body` is stored under.
+pub fn hash(body: &[u8]) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    hash.copy_from_slice(&Sha256::digest(body));
+    hash
+}
+
+/// Bodies of entities, keyed by their hashes, so each is only stored once
+/// however many paths have it. Each body has a count of the references to
+/// it, and is deleted once there are none left.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BlobTable {
+    pub blobs: lmdb::Database,
+
+    /// The number of references to each body, as a little-endian u64.
+    pub refs: lmdb::Database,
+}
+
+impl BlobTable {
+    /// Get the body stored under `hash`.
+    pub fn get<'txn, T: Transaction>(&self, txn: &'txn T, hash: &[u8]) -> lmdb::Result<&'txn [u8]> {
+        txn.get(self.blobs, &hash)
+    }
+
+    /// Add a reference to `body`, storing it if nothing else refers to it.
+    /// Returns its hash.
+    pub fn acquire(&self, txn: &mut lmdb::RwTransaction, body: &[u8]) -> lmdb::Result<[u8; HASH_LEN]> {
+        let hash = hash(body);
+        let count = self.count(txn, &hash)?;
+        if count == 0 {
+            txn.put(self.blobs, &hash, &body, lmdb::WriteFlags::empty())?;
+        }
+        txn.put(self.refs, &hash, &(count + 1).to_le_bytes(), lmdb::WriteFlags::empty())?;
+        Ok(hash)
+    }
+
+    /// Drop a reference to the body stored under `hash`, deleting it if
+    /// that was the last one.
+    pub fn release(&self, txn: &mut lmdb::RwTransaction, hash: &[u8]) -> lmdb::Result<()> {
+        match self.count(txn, hash)? {
+            0 => Ok(()),
+            1 => {
+                txn.del(self.refs, &hash, None)?;
+                txn.del(self.blobs, &hash, None)
+            },
+            count => txn.put(self.refs, &hash, &(count - 1).to_le_bytes(), lmdb::WriteFlags::empty()),
+        }
+    }
+
+    fn count(&self, txn: &lmdb::RwTransaction, hash: &[u8]) -> lmdb::Result<u64> {
+        match txn.get(self.refs, &hash) {
+            Ok(bytes) if bytes.len() == 8 => {
+                let mut count = [0; 8];
+                count.copy_from_slice(bytes);
+                Ok(u64::from_le_bytes(count))
+            },
+            Ok(_) => Err(lmdb::Error::Corrupted),
+            Err(lmdb::Error::NotFound) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn bodies_are_stored_once_and_deleted_with_their_last_reference() {
+        let dir = std::env::temp_dir().join(format!("webpub-blobs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let env = lmdb::Environment::new().set_max_dbs(2).open(&dir).unwrap();
+        let table = BlobTable {
+            blobs: env.create_db(Some("blobs"), lmdb::DatabaseFlags::empty()).unwrap(),
+            refs: env.create_db(Some("refs"), lmdb::DatabaseFlags::empty()).unwrap(),
+        };
+        let stored = |hash: &[u8]| -> Option<(Vec<u8>, u64)> {
+            let txn = env.begin_rw_txn().unwrap();
+            let body = table.get(&txn, hash).ok()?.to_vec();
+            let count = table.count(&txn, hash).unwrap();
+            txn.abort();
+            Some((body, count))
+        };
+
+        let mut txn = env.begin_rw_txn().unwrap();
+        let font = table.acquire(&mut txn, b"font").unwrap();
+        assert_eq!(table.acquire(&mut txn, b"font").unwrap(), font);
+        let script = table.acquire(&mut txn, b"script").unwrap();
+        assert_ne!(font, script);
+        assert_eq!(font, hash(b"font"));
+        txn.commit().unwrap();
+        assert_eq!(stored(&font), Some((b"font".to_vec(), 2)));
+        assert_eq!(stored(&script), Some((b"script".to_vec(), 1)));
+
+        let mut txn = env.begin_rw_txn().unwrap();
+        table.release(&mut txn, &font).unwrap();
+        table.release(&mut txn, &script).unwrap();
+        // Releasing something which isn't stored is harmless.
+        table.release(&mut txn, &script).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(stored(&font), Some((b"font".to_vec(), 1)));
+        assert_eq!(stored(&script), None);
+
+        let mut txn = env.begin_rw_txn().unwrap();
+        table.release(&mut txn, &font).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(stored(&font), None);
+        drop(env);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+use crate::{
+    feed,
+    language,
+    lmdb_web_site::LMDBWebSite,
+    shortcuts::entity_list,
+    upload_fs::{self, markdown, mime},
+    url_policy::UrlPolicy,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs,
+    io,
+    path,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Capnp(capnp::Error),
+    Yaml(serde_yaml::Error),
+    Toml(toml::de::Error),
+    Template(tera::Error),
+    Glob(globset::Error),
+    NonUnicodePath,
+    /// A page's front matter is missing its closing line.
+    UnterminatedFrontMatter(String),
+    /// A page's date couldn't be parsed.
+    InvalidDate(String, String),
+    /// Two tags would have their pages at the same path.
+    TagCollision(String, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Capnp(e) => write!(f, "{}", e),
+            Error::Yaml(e) => write!(f, "invalid front matter: {}", e),
+            Error::Toml(e) => write!(f, "invalid TOML: {}", e),
+            Error::Template(e) => write!(f, "rendering template: {}", e),
+            Error::Glob(e) => write!(f, "{}", e),
+            Error::NonUnicodePath => write!(f, "path is not valid unicode"),
+            Error::UnterminatedFrontMatter(p) => write!(f, "{}: front matter is not terminated", p),
+            Error::InvalidDate(p, date) => write!(f, "{}: invalid date {:?}", p, date),
+            Error::TagCollision(l, r) => {
+                write!(f, "tags {:?} and {:?} would both be listed at {}", l, r, tag_path(l))
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Capnp(e) => Some(e),
+            Error::Yaml(e) => Some(e),
+            Error::Toml(e) => Some(e),
+            Error::Template(e) => Some(e),
+            Error::Glob(e) => Some(e),
+            Error::NonUnicodePath
+                | Error::UnterminatedFrontMatter(_)
+                | Error::InvalidDate(_, _)
+                | Error::TagCollision(_, _) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<capnp::Error> for Error {
+    fn from(e: capnp::Error) -> Self {
+        Error::Capnp(e)
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(e: serde_yaml::Error) -> Self {
+        Error::Yaml(e)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::Toml(e)
+    }
+}
+
+impl From<tera::Error> for Error {
+    fn from(e: tera::Error) -> Self {
+        Error::Template(e)
+    }
+}
+
+impl From<globset::Error> for Error {
+    fn from(e: globset::Error) -> Self {
+        Error::Glob(e)
+    }
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+const CONFIG_FILE_NAME: &str = "site.toml";
+const CONTENT_DIR: &str = "content";
+const LAYOUTS_DIR: &str = "layouts";
+const STATIC_DIR: &str = "static";
+
+const PAGE_LAYOUT: &str = "page.html";
+const INDEX_LAYOUT: &str = "index.html";
+const TAG_LAYOUT: &str = "tag.html";
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Publish pages marked as drafts.
+    pub drafts: bool,
+
+    /// Refuse to publish if the new version of the site has broken links
+    /// which the old one didn't. Only builds are checked like this, since
+    /// they publish everything in one transaction; uploads set each path
+    /// on its own, so there is no one point at which to refuse them.
+    pub check_links: bool,
+}
+
+/// What a build published.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub pages: usize,
+    pub tags: usize,
+    pub files: usize,
+
+    /// Whether feeds were generated.
+    pub feeds: bool,
+    pub version: u64,
+}
+
+/// The contents of `site.toml`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+    title: String,
+
+    /// The absolute url the site is published at; needed for feeds.
+    url: String,
+    description: String,
+}
+
+/// A date in front matter. YAML dates are just strings, but TOML has its
+/// own type for them.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum Date {
+    Text(String),
+    Toml(toml::value::Datetime),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct FrontMatter {
+    title: Option<String>,
+    date: Option<Date>,
+    tags: Vec<String>,
+    summary: Option<String>,
+    layout: Option<String>,
+    draft: bool,
+}
+
+/// What templates get to know about a page.
+#[derive(Clone, Debug, Serialize)]
+struct Page {
+    title: String,
+    date: Option<String>,
+    tags: Vec<String>,
+    summary: String,
+
+    /// The url of the page, relative to the root of the site, starting
+    /// with `/`.
+    path: String,
+    language: String,
+    #[serde(skip)]
+    layout: String,
+    #[serde(skip)]
+    content: String,
+    #[serde(skip)]
+    aliases: Vec<String>,
+}
+
+/// One entity of the generated site.
+struct Output {
+    mime_type: String,
+    language: String,
+    body: Vec<u8>,
+    redirect_to: Option<String>,
+}
+
+/// Build the site in `source` and publish it into `site`, in a single
+/// transaction.
+///
+/// The source directory looks like:
+///
+/// ```text
+/// site.toml          title, url and description of the site (optional)
+/// content/           pages, in Markdown or html, with front matter
+/// layouts/           templates for pages, using the same syntax as ours
+/// static/            files published as they are
+/// ```
+///
+/// Pages start with front matter, in YAML between `---` lines or TOML
+/// between `+++` lines, holding any of `title`, `date`, `tags`, `summary`,
+/// `layout` and `draft`. A page is rendered with the template named by its
+/// `layout`, or `page.html`. If there are templates named `index.html` and
+/// `tag.html`, we also generate an index of pages at the root of the site
+/// (unless a page is published there already) and a page for each tag.
+/// If `site.toml` gives the site's `url`, we also generate Atom and RSS
+/// feeds of the dated pages.
+pub fn build(source: &path::Path, site: &LMDBWebSite, options: &Options) -> Result<Report> {
+    let mut report = Report::default();
+    let config_path = source.join(CONFIG_FILE_NAME);
+    let config: Config = if config_path.is_file() {
+        toml::from_str(&fs::read_to_string(config_path)?)?
+    } else {
+        Config::default()
+    };
+    let templates = load_templates(&source.join(LAYOUTS_DIR))?;
+    let detector = mime::Detector::new(&Default::default())?;
+
+    let mut outputs: BTreeMap<String, Vec<Output>> = BTreeMap::new();
+    let mut pages = vec![];
+    for (rel, file) in walk(&source.join(CONTENT_DIR))? {
+        let contents = fs::read(&file)?;
+        if !is_page_source(&rel) {
+            let mime_type = detector.detect(&rel, &contents);
+            add_file(&mut outputs, rel, mime_type, contents);
+            report.files += 1;
+            continue
+        }
+        let page = read_page(&rel, &String::from_utf8_lossy(&contents))?;
+        if let Some((page, draft)) = page {
+            if !draft || options.drafts {
+                pages.push(page);
+            }
+        }
+    }
+    for (rel, file) in walk(&source.join(STATIC_DIR))? {
+        let contents = fs::read(&file)?;
+        let mime_type = detector.detect(&rel, &contents);
+        add_file(&mut outputs, rel, mime_type, contents);
+        report.files += 1;
+    }
+
+    // Newest first; undated pages sort last, and aren't in feeds. Dates
+    // can be written in more than one format, so compare them parsed.
+    pages.sort_by_key(|page| std::cmp::Reverse(page.date.as_deref().and_then(feed::parse_date)));
+    let site_context = |context: &mut tera::Context| {
+        context.insert("site", &config);
+        context.insert("pages", &pages);
+    };
+
+    for page in pages.iter() {
+        let mut context = tera::Context::new();
+        site_context(&mut context);
+        context.insert("page", page);
+        context.insert("title", &page.title);
+        context.insert("path", &page.path);
+        context.insert("content", &page.content);
+        let html = templates.render(&page.layout, &context)?;
+        let canonical = String::from(page.path.trim_start_matches('/'));
+        outputs.entry(canonical.clone()).or_default().push(Output {
+            mime_type: String::from("text/html; charset=utf-8"),
+            language: page.language.clone(),
+            body: html.into_bytes(),
+            redirect_to: None,
+        });
+        for alias in page.aliases.iter() {
+            add_redirect(&mut outputs, alias, &canonical);
+        }
+        report.pages += 1;
+    }
+
+    let has_template = |name: &str| templates.get_template_names().any(|t| t == name);
+    if has_template(INDEX_LAYOUT) && !outputs.contains_key("/") {
+        let mut context = tera::Context::new();
+        site_context(&mut context);
+        context.insert("title", &config.title);
+        context.insert("path", "/");
+        add_html(&mut outputs, "/", templates.render(INDEX_LAYOUT, &context)?);
+        add_redirect(&mut outputs, "", "/");
+    }
+
+    if has_template(TAG_LAYOUT) {
+        let mut tags: BTreeMap<&str, Vec<&Page>> = BTreeMap::new();
+        for page in pages.iter() {
+            for tag in page.tags.iter() {
+                tags.entry(&tag[..]).or_default().push(page);
+            }
+        }
+        // Tags which differ only in case or punctuation get the same path.
+        let mut paths: BTreeMap<String, &str> = BTreeMap::new();
+        for tag in tags.keys() {
+            if let Some(other) = paths.insert(tag_path(tag), *tag) {
+                return Err(Error::TagCollision(String::from(other), String::from(*tag)))
+            }
+        }
+        for (tag, tagged) in tags.iter() {
+            let mut context = tera::Context::new();
+            context.insert("site", &config);
+            context.insert("tag", tag);
+            context.insert("pages", tagged);
+            context.insert("title", tag);
+            let path = tag_path(tag);
+            context.insert("path", &format!("/{}", path));
+            add_html(&mut outputs, &path, templates.render(TAG_LAYOUT, &context)?);
+            add_redirect(&mut outputs, path.trim_end_matches('/'), &path);
+            report.tags += 1;
+        }
+    }
+
+    if config.url != "" {
+        let feed = site_feed(&config, &pages);
+        add_file(&mut outputs,
+                 String::from("atom.xml"),
+                 String::from("application/atom+xml"),
+                 feed.atom(&feed::absolute_url(&config.url, "atom.xml")).into_bytes());
+        add_file(&mut outputs,
+                 String::from("rss.xml"),
+                 String::from("application/rss+xml"),
+                 feed.rss().into_bytes());
+        report.feeds = true;
+    }
+
+    report.version = publish(site, &outputs, options.check_links)?;
+    Ok(report)
+}
+
+/// Write `outputs` into `site`, in place of whatever the last build
+/// published.
+fn publish(site: &LMDBWebSite,
+           outputs: &BTreeMap<String, Vec<Output>>,
+           check_links: bool) -> Result<u64> {
+    let mut messages = Vec::with_capacity(outputs.len());
+    for (path, entities) in outputs.iter() {
+        let mut msg = capnp::message::Builder::new_default();
+        {
+            let mut list: entity_list::Builder = msg.initn_root(entities.len() as u32);
+            for (i, output) in entities.iter().enumerate() {
+                let mut entity = list.reborrow().get(i as u32);
+                match output.redirect_to {
+                    Some(ref to) => entity.set_redirect_to(to),
+                    None => {
+                        entity.set_mime_type(&output.mime_type);
+                        entity.set_language(&output.language);
+                        entity.reborrow().get_body().set_bytes(&output.body);
+                    },
+                }
+            }
+        }
+        messages.push((&path[..], msg));
+    }
+    let mut readers = Vec::with_capacity(messages.len());
+    for (path, msg) in messages.iter() {
+        readers.push((*path, msg.get_root_as_reader::<entity_list::Reader>()?));
+    }
+    Ok(site.publish_build(readers, check_links)?)
+}
+
+/// Load the site's layouts from `dir`. These are read at run time, so
+/// unlike our own templates they can't be askama ones, which are compiled
+/// in; tera uses nearly the same (Jinja-like) syntax, so they read alike.
+fn load_templates(dir: &path::Path) -> Result<tera::Tera> {
+    let mut templates = tera::Tera::default();
+    if dir.is_dir() {
+        for (name, file) in walk(dir)? {
+            templates.add_raw_template(&name, &fs::read_to_string(file)?)?;
+        }
+    }
+    if !templates.get_template_names().any(|name| name == PAGE_LAYOUT) {
+        templates.add_raw_template(PAGE_LAYOUT, markdown::DEFAULT_LAYOUT)?;
+    }
+    Ok(templates)
+}
+
+/// List the files under `dir`, along with their paths relative to it.
+/// Hidden files are skipped. If `dir` doesn't exist, there are no files.
+/// Symlinks are followed, except to a directory enclosing the link.
+fn walk(dir: &path::Path) -> Result<Vec<(String, path::PathBuf)>> {
+    let mut files = vec![];
+    if !dir.is_dir() {
+        return Ok(files)
+    }
+    // As in `upload_fs`, each directory comes with the ids of it and its
+    // ancestors, so links back up the tree aren't followed round forever.
+    let mut stack = vec![(dir.to_path_buf(), vec![upload_fs::dir_id(&fs::metadata(dir)?)])];
+    while let Some((next, ancestors)) = stack.pop() {
+        for entry in fs::read_dir(&next)? {
+            let path = entry?.path();
+            let rel = path.strip_prefix(dir).map_err(|_| Error::NonUnicodePath)?;
+            let rel = rel.to_str().ok_or(Error::NonUnicodePath)?;
+            if rel.split('/').any(|part| part.starts_with('.')) {
+                continue
+            }
+            let metadata = fs::metadata(&path)?;
+            if metadata.is_dir() {
+                let id = upload_fs::dir_id(&metadata);
+                if !ancestors.contains(&id) {
+                    let mut dir_ancestors = ancestors.clone();
+                    dir_ancestors.push(id);
+                    stack.push((path.clone(), dir_ancestors));
+                }
+            } else {
+                files.push((String::from(rel), path.clone()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn is_page_source(rel: &str) -> bool {
+    markdown::is_markdown(rel) || rel.ends_with(".html") || rel.ends_with(".htm")
+}
+
+/// Read the page at `rel` (relative to the content directory). Returns
+/// the page and whether it's a draft.
+fn read_page(rel: &str, text: &str) -> Result<Option<(Page, bool)>> {
+    let (front_matter, body) = split_front_matter(rel, text)?;
+    let (html_path, content) = if markdown::is_markdown(rel) {
+        let mut content = String::new();
+        pulldown_cmark::html::push_html(
+            &mut content,
+            pulldown_cmark::Parser::new_ext(body, pulldown_cmark::Options::all()),
+        );
+        (markdown::html_path(rel), content)
+    } else {
+        (String::from(rel), String::from(body))
+    };
+    let (page_path, language) = match language::split_variant(&html_path) {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let policy = UrlPolicy {
+        strip_html: true,
+        ..Default::default()
+    };
+    let placement = policy.place(&page_path);
+    let date = match front_matter.date {
+        None => None,
+        Some(Date::Toml(date)) => Some(date.to_string()),
+        Some(Date::Text(date)) => Some(date),
+    };
+    if let Some(ref date) = date {
+        if feed::parse_date(date).is_none() {
+            return Err(Error::InvalidDate(String::from(rel), date.clone()))
+        }
+    }
+    let title = front_matter.title.unwrap_or_else(|| {
+        let name = page_path.rsplit('/').next().unwrap_or(&page_path);
+        String::from(name.trim_end_matches(".html"))
+    });
+    let page = Page {
+        title: title,
+        date: date,
+        tags: front_matter.tags,
+        summary: front_matter.summary.unwrap_or_default(),
+        path: format!("/{}", placement.canonical.trim_start_matches('/')),
+        language: language,
+        layout: front_matter.layout.unwrap_or_else(|| String::from(PAGE_LAYOUT)),
+        content: content,
+        aliases: placement.aliases,
+    };
+    Ok(Some((page, front_matter.draft)))
+}
+
+/// Split a page into its front matter and body.
+fn split_front_matter<'a>(rel: &str, text: &'a str) -> Result<(FrontMatter, &'a str)> {
+    let (front_matter, body) = markdown::split_front_matter(text)
+        .ok_or_else(|| Error::UnterminatedFrontMatter(String::from(rel)))?;
+    let front_matter = match front_matter {
+        None => FrontMatter::default(),
+        Some(markdown::FrontMatter::Toml(raw)) => toml::from_str(raw)?,
+        Some(markdown::FrontMatter::Yaml(raw)) if raw.trim() == "" => FrontMatter::default(),
+        Some(markdown::FrontMatter::Yaml(raw)) => serde_yaml::from_str(raw)?,
+    };
+    Ok((front_matter, body))
+}
+
+fn add_file(outputs: &mut BTreeMap<String, Vec<Output>>, path: String, mime_type: String, body: Vec<u8>) {
+    outputs.entry(path).or_default().push(Output {
+        mime_type: mime_type,
+        language: String::new(),
+        body: body,
+        redirect_to: None,
+    });
+}
+
+fn add_html(outputs: &mut BTreeMap<String, Vec<Output>>, path: &str, html: String) {
+    add_file(outputs, String::from(path), String::from("text/html; charset=utf-8"), html.into_bytes());
+}
+
+fn add_redirect(outputs: &mut BTreeMap<String, Vec<Output>>, from: &str, to: &str) {
+    outputs.entry(String::from(from)).or_insert_with(|| vec![Output {
+        mime_type: String::new(),
+        language: String::new(),
+        body: vec![],
+        redirect_to: Some(String::from(to)),
+    }]);
+}
+
+/// The path of the page listing the pages tagged `tag`.
+fn tag_path(tag: &str) -> String {
+    let slug: String = tag.chars().map(|c| {
+        if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '-' }
+    }).collect();
+    format!("tags/{}/", slug)
+}
+
+fn site_feed(config: &Config, pages: &[Page]) -> feed::Feed {
+    feed::Feed {
+        title: config.title.clone(),
+        link: config.url.clone(),
+        description: config.description.clone(),
+        entries: pages.iter().filter_map(|page| {
+            let date = feed::parse_date(page.date.as_ref()?)?;
+            Some(feed::Entry {
+                title: page.title.clone(),
+                link: feed::absolute_url(&config.url, &page.path),
+                date: date,
+                summary: page.summary.clone(),
+            })
+        }).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walk_doesnt_follow_links_up_the_tree() {
+        let dir = std::env::temp_dir().join(format!("webpub-walk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::write(dir.join("a/page.md"), "").unwrap();
+        std::os::unix::fs::symlink("..", dir.join("a/up")).unwrap();
+        let files = walk(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<String> = files.unwrap().into_iter().map(|(rel, _)| rel).collect();
+        assert_eq!(names, vec!["a/page.md"]);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{
+    lmdb_web_site::LMDBWebSite,
+    manifest::{self, Manifest},
+};
+use std::{
+    fmt,
+    io::{self, Write},
+};
+use sandstorm::web_publishing_capnp::web_site;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Capnp(capnp::Error),
+    Json(serde_json::Error),
+    Zip(zip::result::ZipError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Capnp(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "{}", e),
+            Error::Zip(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Capnp(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Zip(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<capnp::Error> for Error {
+    fn from(e: capnp::Error) -> Self {
+        Error::Capnp(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        Error::Zip(e)
+    }
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// An archive format we can export to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    Zip,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tar" => Some(Format::Tar),
+            "zip" => Some(Format::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Tar => "tar",
+            Format::Zip => "zip",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Format::Tar => "application/x-tar",
+            Format::Zip => "application/zip",
+        }
+    }
+}
+
+enum Writer<W: Write + io::Seek> {
+    Tar(tar::Builder<W>),
+    Zip(zip::ZipWriter<W>),
+}
+
+impl<W: Write + io::Seek> Writer<W> {
+    fn new(format: Format, out: W) -> Self {
+        match format {
+            Format::Tar => Writer::Tar(tar::Builder::new(out)),
+            Format::Zip => Writer::Zip(zip::ZipWriter::new(out)),
+        }
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        match self {
+            Writer::Tar(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, name, data)?;
+            },
+            Writer::Zip(writer) => {
+                writer.start_file(name, zip::write::FileOptions::default())?;
+                writer.write_all(data)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<W> {
+        match self {
+            Writer::Tar(builder) => Ok(builder.into_inner()?),
+            Writer::Zip(mut writer) => Ok(writer.finish()?),
+        }
+    }
+}
+
+/// Write the entire contents of `site` to `out` as an archive, which
+/// `upload_fs` can import again.
+///
+/// Each body goes in a file named after its url path, and everything else
+/// goes in the manifest (see the `manifest` module).
+pub fn export<W: Write + io::Seek>(site: &LMDBWebSite, format: Format, out: W) -> Result<W> {
+    let mut writer = Writer::new(format, out);
+    let mut manifest = Manifest::default();
+    let mut file_names = manifest::FileNames::default();
+    site.for_each_path("", |path, entities| {
+        let mut manifest_entities = Vec::with_capacity(entities.len() as usize);
+        for (n, entity) in entities.iter().enumerate() {
+            let mut manifest_entity = manifest::Entity {
+                mime_type: String::from(entity.get_mime_type()?),
+                language: String::from(entity.get_language()?),
+                encoding: String::from(entity.get_encoding()?),
+                file: None,
+                redirect_to: None,
+            };
+            if entity.has_redirect_to() {
+                manifest_entity.redirect_to = Some(String::from(entity.get_redirect_to()?));
+            } else if let web_site::entity::body::Bytes(bytes) = entity.get_body().which()? {
+                let file = file_names.assign(path, n);
+                writer.add(&file, bytes?)?;
+                manifest_entity.file = Some(file);
+            }
+            manifest_entities.push(manifest_entity);
+        }
+        manifest.paths.insert(String::from(path), manifest_entities);
+        Ok(())
+    })?;
+    writer.add(manifest::FILE_NAME, &serde_json::to_vec_pretty(&manifest)?)?;
+    writer.finish()
+}
@@ -0,0 +1,178 @@
+use crate::html::escape;
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+
+/// One item in a feed.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub title: String,
+
+    /// The absolute url of the page.
+    pub link: String,
+    pub date: DateTime<FixedOffset>,
+    pub summary: String,
+}
+
+/// A feed of a site's pages, which can be written as Atom or RSS.
+#[derive(Clone, Debug)]
+pub struct Feed {
+    pub title: String,
+
+    /// The absolute url of the site.
+    pub link: String,
+    pub description: String,
+
+    /// Newest first.
+    pub entries: Vec<Entry>,
+}
+
+/// Parse a date as given in a page's metadata: either RFC 3339, or just
+/// `YYYY-MM-DD`, which is taken to be midnight UTC.
+pub fn parse_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    let s = s.trim();
+    DateTime::parse_from_rfc3339(s).ok().or_else(|| {
+        let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+        Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?).into())
+    })
+}
+
+impl Feed {
+    /// When the feed last changed: the date of the newest entry.
+    fn updated(&self) -> DateTime<FixedOffset> {
+        self.entries.iter().map(|entry| entry.date).max()
+            .unwrap_or_else(|| Utc::now().into())
+    }
+
+    /// Write the feed as Atom. `self_link` is the absolute url the feed
+    /// will be published at.
+    pub fn atom(&self, self_link: &str) -> String {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        out.push_str(&format!("  <title>{}</title>\n", escape(&self.title)));
+        out.push_str(&format!("  <id>{}</id>\n", escape(&self.link)));
+        out.push_str(&format!("  <link href=\"{}\"/>\n", escape(&self.link)));
+        out.push_str(&format!("  <link rel=\"self\" href=\"{}\"/>\n", escape(self_link)));
+        out.push_str(&format!("  <updated>{}</updated>\n", self.updated().to_rfc3339()));
+        if self.description != "" {
+            out.push_str(&format!("  <subtitle>{}</subtitle>\n", escape(&self.description)));
+        }
+        for entry in self.entries.iter() {
+            out.push_str("  <entry>\n");
+            out.push_str(&format!("    <title>{}</title>\n", escape(&entry.title)));
+            out.push_str(&format!("    <id>{}</id>\n", escape(&entry.link)));
+            out.push_str(&format!("    <link href=\"{}\"/>\n", escape(&entry.link)));
+            out.push_str(&format!("    <updated>{}</updated>\n", entry.date.to_rfc3339()));
+            if entry.summary != "" {
+                out.push_str(&format!("    <summary>{}</summary>\n", escape(&entry.summary)));
+            }
+            out.push_str("  </entry>\n");
+        }
+        out.push_str("</feed>\n");
+        out
+    }
+
+    /// Write the feed as RSS 2.0.
+    pub fn rss(&self) -> String {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        out.push_str("<rss version=\"2.0\">\n<channel>\n");
+        out.push_str(&format!("  <title>{}</title>\n", escape(&self.title)));
+        out.push_str(&format!("  <link>{}</link>\n", escape(&self.link)));
+        out.push_str(&format!("  <description>{}</description>\n", escape(&self.description)));
+        out.push_str(&format!("  <lastBuildDate>{}</lastBuildDate>\n", self.updated().to_rfc2822()));
+        for entry in self.entries.iter() {
+            out.push_str("  <item>\n");
+            out.push_str(&format!("    <title>{}</title>\n", escape(&entry.title)));
+            out.push_str(&format!("    <link>{}</link>\n", escape(&entry.link)));
+            out.push_str(&format!("    <guid>{}</guid>\n", escape(&entry.link)));
+            out.push_str(&format!("    <pubDate>{}</pubDate>\n", entry.date.to_rfc2822()));
+            if entry.summary != "" {
+                out.push_str(&format!("    <description>{}</description>\n", escape(&entry.summary)));
+            }
+            out.push_str("  </item>\n");
+        }
+        out.push_str("</channel>\n</rss>\n");
+        out
+    }
+}
+
+/// Join a site's url and a path within it into an absolute url.
+pub fn absolute_url(site_url: &str, path: &str) -> String {
+    format!("{}/{}", site_url.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed() -> Feed {
+        Feed {
+            title: String::from("News & notes"),
+            link: String::from("https://example.com/"),
+            description: String::new(),
+            entries: vec![
+                Entry {
+                    title: String::from("<Second>"),
+                    link: absolute_url("https://example.com/", "/posts/2.html"),
+                    date: parse_date("2020-03-04T05:06:07+01:00").unwrap(),
+                    summary: String::from("More \"news\""),
+                },
+                Entry {
+                    title: String::from("First"),
+                    link: absolute_url("https://example.com", "posts/1.html"),
+                    date: parse_date("2020-01-02").unwrap(),
+                    summary: String::new(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn dates_may_be_rfc_3339_or_just_a_day() {
+        assert_eq!(parse_date(" 2020-01-02 ").unwrap().to_rfc3339(), "2020-01-02T00:00:00+00:00");
+        assert_eq!(parse_date("2020-03-04T05:06:07+01:00").unwrap().to_rfc3339(), "2020-03-04T05:06:07+01:00");
+        assert_eq!(parse_date("2020-03-04T05:06:07Z").unwrap().to_rfc3339(), "2020-03-04T05:06:07+00:00");
+        assert!(parse_date("").is_none());
+        assert!(parse_date("yesterday").is_none());
+        assert!(parse_date("2020-02-30").is_none());
+    }
+
+    #[test]
+    fn atom_feeds_are_escaped_and_updated_with_the_newest_entry() {
+        assert_eq!(feed().atom("https://example.com/feed.xml"), "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<feed xmlns=\"http://www.w3.org/2005/Atom\">
+  <title>News &amp; notes</title>
+  <id>https://example.com/</id>
+  <link href=\"https://example.com/\"/>
+  <link rel=\"self\" href=\"https://example.com/feed.xml\"/>
+  <updated>2020-03-04T05:06:07+01:00</updated>
+  <entry>
+    <title>&lt;Second&gt;</title>
+    <id>https://example.com/posts/2.html</id>
+    <link href=\"https://example.com/posts/2.html\"/>
+    <updated>2020-03-04T05:06:07+01:00</updated>
+    <summary>More &quot;news&quot;</summary>
+  </entry>
+  <entry>
+    <title>First</title>
+    <id>https://example.com/posts/1.html</id>
+    <link href=\"https://example.com/posts/1.html\"/>
+    <updated>2020-01-02T00:00:00+00:00</updated>
+  </entry>
+</feed>
+");
+    }
+
+    #[test]
+    fn rss_feeds_use_rfc_2822_dates() {
+        let rss = feed().rss();
+        assert!(rss.starts_with("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<rss version=\"2.0\">\n<channel>\n"));
+        assert!(rss.contains("  <title>News &amp; notes</title>\n  <link>https://example.com/</link>\n"));
+        assert!(rss.contains("  <description></description>\n"));
+        assert!(rss.contains("  <lastBuildDate>Wed, 4 Mar 2020 05:06:07 +0100</lastBuildDate>\n"));
+        assert!(rss.contains("    <guid>https://example.com/posts/2.html</guid>\n"));
+        assert!(rss.contains("    <pubDate>Thu, 2 Jan 2020 00:00:00 +0000</pubDate>\n"));
+        assert!(rss.contains("    <description>More &quot;news&quot;</description>\n"));
+        assert_eq!(rss.matches("<description>").count(), 2);
+        assert!(rss.ends_with("  </item>\n</channel>\n</rss>\n"));
+    }
+}
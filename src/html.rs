@@ -0,0 +1,45 @@
+/// Escape `s` for use in html or xml text, or in a quoted attribute.
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Undo the escaping of the characters `escape()` escapes. Other
+/// character references are left alone.
+pub fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping_round_trips() {
+        let text = r#"<a href="x">Tom & Jerry's</a>"#;
+        let escaped = escape(text);
+        assert_eq!(escaped, "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;");
+        assert_eq!(unescape(&escaped), text);
+    }
+
+    #[test]
+    fn ampersands_are_unescaped_last() {
+        assert_eq!(unescape("&amp;lt;"), "&lt;");
+        assert_eq!(unescape("&#39;&copy;"), "'&copy;");
+    }
+}
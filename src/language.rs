@@ -0,0 +1,177 @@
+use std::cmp::Ordering;
+
+/// If `path` is that of a page (an `.html` file), split it into the path
+/// the page should be published under and the language it is in, which
+/// is given by a tag before the extension, e.g. `about/index.de.html` is
+/// the German variant of `about/index.html`. Pages without a tag have an
+/// empty language.
+pub fn split_variant(path: &str) -> Option<(String, String)> {
+    let (dir, name) = match path.rfind('/') {
+        Some(i) => path.split_at(i + 1),
+        None => ("", path),
+    };
+    let ext_start = name.rfind('.')?;
+    let ext = &name[ext_start..];
+    if ext != ".html" && ext != ".htm" {
+        return None
+    }
+    let stem = &name[..ext_start];
+    match stem.rfind('.') {
+        Some(i) if i > 0 && is_language_tag(&stem[i + 1..]) => {
+            let page = format!("{}{}{}", dir, &stem[..i], ext);
+            Some((page, stem[i + 1..].to_ascii_lowercase()))
+        },
+        _ => Some((String::from(path), String::new())),
+    }
+}
+
+/// Whether `tag` looks like a language tag we'd expect in a file name: an
+/// ISO 639-1 language code, optionally followed by a region or script,
+/// e.g. `en`, `pt-BR` or `zh-Hant`.
+fn is_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let primary = parts.next().unwrap_or("");
+    let subtag_ok = match (parts.next(), parts.next()) {
+        (None, _) => true,
+        (Some(sub), None) => {
+            (2..=4).contains(&sub.len()) && sub.chars().all(|c| c.is_ascii_alphanumeric())
+        },
+        (Some(_), Some(_)) => false,
+    };
+    LANGUAGE_CODES.binary_search(&&primary.to_ascii_lowercase()[..]).is_ok() && subtag_ok
+}
+
+/// The ISO 639-1 language codes, in order. Other two letter extensions
+/// (like `foo.ui.html`) aren't taken for languages.
+const LANGUAGE_CODES: &[&str] = &[
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az",
+    "ba", "be", "bg", "bi", "bm", "bn", "bo", "br", "bs", "ca", "ce", "ch",
+    "co", "cr", "cs", "cu", "cv", "cy", "da", "de", "dv", "dz", "ee", "el",
+    "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr", "fy",
+    "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr", "ht",
+    "hu", "hy", "hz", "ia", "id", "ie", "ig", "ii", "ik", "io", "is", "it",
+    "iu", "ja", "jv", "ka", "kg", "ki", "kj", "kk", "kl", "km", "kn", "ko",
+    "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln", "lo",
+    "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt",
+    "my", "na", "nb", "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny",
+    "oc", "oj", "om", "or", "os", "pa", "pi", "pl", "ps", "pt", "qu", "rm",
+    "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk", "sl",
+    "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta", "te",
+    "tg", "th", "ti", "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty",
+    "ug", "uk", "ur", "uz", "ve", "vi", "vo", "wa", "wo", "xh", "yi", "yo",
+    "za", "zh", "zu",
+];
+
+/// Parse an `Accept-Language` header into its language ranges, most
+/// preferred first. Ranges with a q-value of zero are dropped.
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut ranges: Vec<(String, f32)> = header.split(',').filter_map(|item| {
+        let mut parts = item.split(';');
+        let range = parts.next()?.trim().to_ascii_lowercase();
+        let mut q = 1.0;
+        for param in parts {
+            let param = param.trim();
+            if param.starts_with("q=") {
+                q = param[2..].parse().unwrap_or(0.0);
+            }
+        }
+        if range == "" || q <= 0.0 {
+            None
+        } else {
+            Some((range, q))
+        }
+    }).collect();
+    // Stable, so ties keep the client's order.
+    ranges.sort_by(|l, r| r.1.partial_cmp(&l.1).unwrap_or(Ordering::Equal));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+/// Pick the best of `available` (which are compared case-insensitively)
+/// for a client which accepts `accepted`, as returned by
+/// `parse_accept_language()`. If nothing matches, use `default` if it is
+/// available.
+pub fn choose<'a>(available: &[&'a str], accepted: &[String], default: &str) -> Option<&'a str> {
+    let find = |matches: &dyn Fn(&str) -> bool| {
+        available.iter().cloned().find(|lang| matches(&lang.to_ascii_lowercase()))
+    };
+    for range in accepted.iter() {
+        let found = find(&|lang| {
+            range == "*" || lang == range || lang.starts_with(&format!("{}-", range))
+        });
+        if found.is_some() {
+            return found
+        }
+    }
+    // Failing an exact match, a page in the same language is better than
+    // one in another language, even if the region differs.
+    for range in accepted.iter() {
+        let primary = range.split('-').next().unwrap_or("");
+        let found = find(&|lang| lang.split('-').next() == Some(primary));
+        if found.is_some() {
+            return found
+        }
+    }
+    let default = default.to_ascii_lowercase();
+    find(&|lang| lang == default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(path: &str) -> Option<(String, String)> {
+        split_variant(path)
+    }
+
+    fn variant(page: &str, language: &str) -> Option<(String, String)> {
+        Some((String::from(page), String::from(language)))
+    }
+
+    #[test]
+    fn split_variant_takes_the_tag_before_the_extension() {
+        assert_eq!(split("about/index.de.html"), variant("about/index.html", "de"));
+        assert_eq!(split("a.pt-BR.htm"), variant("a.htm", "pt-br"));
+        assert_eq!(split("a.zh-Hant.html"), variant("a.html", "zh-hant"));
+        assert_eq!(split("about/index.html"), variant("about/index.html", ""));
+        assert_eq!(split("style.css"), None);
+        assert_eq!(split("de.css/index"), None);
+    }
+
+    #[test]
+    fn split_variant_ignores_things_which_arent_languages() {
+        assert_eq!(split("foo.ui.html"), variant("foo.ui.html", ""));
+        assert_eq!(split("foo.min.html"), variant("foo.min.html", ""));
+        assert_eq!(split("foo.en-toolong.html"), variant("foo.en-toolong.html", ""));
+        assert_eq!(split("foo.en-US-x.html"), variant("foo.en-US-x.html", ""));
+        assert_eq!(split(".de.html"), variant(".de.html", ""));
+        assert_eq!(split("de.x/.html"), variant("de.x/.html", ""));
+    }
+
+    #[test]
+    fn language_codes_are_sorted() {
+        assert!(LANGUAGE_CODES.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn parse_accept_language_orders_by_q() {
+        assert_eq!(
+            parse_accept_language("fr;q=0.5, EN-us , de;q=0.8,, it;q=0, *;q=0.1"),
+            vec!["en-us", "de", "fr", "*"],
+        );
+        assert_eq!(parse_accept_language("da, en;q=1"), vec!["da", "en"]);
+        assert_eq!(parse_accept_language(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn choose_prefers_exact_matches() {
+        let available = ["en-GB", "en-US", "de", ""];
+        let accept = |header| parse_accept_language(header);
+        assert_eq!(choose(&available, &accept("en-us, de"), ""), Some("en-US"));
+        assert_eq!(choose(&available, &accept("en"), ""), Some("en-GB"));
+        assert_eq!(choose(&available, &accept("de-AT, en-US;q=0.5"), ""), Some("en-US"));
+        assert_eq!(choose(&available, &accept("de-AT, fr;q=0.5"), ""), Some("de"));
+        assert_eq!(choose(&available, &accept("fr"), "DE"), Some("de"));
+        assert_eq!(choose(&available, &accept("fr"), ""), Some(""));
+        assert_eq!(choose(&["de"], &accept("fr"), "en"), None);
+    }
+}
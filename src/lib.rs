@@ -3,8 +3,24 @@ pub mod webpub;
 pub mod promise_util;
 pub mod web_site_session;
 pub mod lmdb_web_site;
+pub mod notify;
+pub mod page_meta;
+pub mod html;
+pub mod language;
+pub mod redirects;
+pub mod site_settings;
 
 pub mod upload_fs;
+pub mod url_policy;
+pub mod manifest;
+pub mod export;
+pub mod build;
+pub mod feed;
+pub mod sitemap;
+pub mod search;
+pub mod links;
+pub mod blobs;
+pub mod bench;
 
 pub mod shortcuts;
 
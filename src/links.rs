@@ -0,0 +1,223 @@
+use crate::{page_meta, search};
+use serde::Serialize;
+
+/// A link on one of a site's pages which leads nowhere.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct BrokenLink {
+    /// The path of the page the link is on, relative to the root of the
+    /// site.
+    pub page: String,
+
+    /// The link as written on the page.
+    pub link: String,
+}
+
+impl BrokenLink {
+    /// The url path of the page the link is on.
+    pub fn page_url(&self) -> String {
+        format!("/{}", self.page.trim_start_matches('/'))
+    }
+}
+
+/// Get the links in an html page: the `href` and `src` attributes of its
+/// elements, and any urls in its styles. Links in comments and scripts
+/// are left out.
+pub fn html_links(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut links = vec![];
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find('<') {
+        let start = offset + start;
+        if lower[start..].starts_with("<!--") {
+            offset = match lower[start..].find("-->") {
+                Some(len) => start + len + "-->".len(),
+                None => break,
+            };
+            continue
+        }
+        let end = match lower[start..].find('>') {
+            Some(len) => start + len,
+            None => break,
+        };
+        offset = end + 1;
+        let tag = &html[start + 1..end];
+        let name_len = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+        let name = tag[..name_len].to_ascii_lowercase();
+        if name.starts_with('/') || name == "base" {
+            continue
+        }
+        let attributes = &tag[name_len..];
+        for attribute in ["href", "src"].iter() {
+            if let Some(link) = page_meta::attribute(attributes, attribute) {
+                links.push(link);
+            }
+        }
+        if let Some(style) = page_meta::attribute(attributes, "style") {
+            links.extend(css_links(&style));
+        }
+        for skipped in ["script", "style"].iter() {
+            if name == *skipped {
+                let content_end = match lower[offset..].find(&format!("</{}", skipped)) {
+                    Some(len) => offset + len,
+                    None => html.len(),
+                };
+                if name == "style" {
+                    links.extend(css_links(&html[offset..content_end]));
+                }
+                offset = content_end;
+            }
+        }
+    }
+    links
+}
+
+/// Get the links in a stylesheet: its `url()`s and `@import`s.
+pub fn css_links(css: &str) -> Vec<String> {
+    let lower = css.to_ascii_lowercase();
+    let mut links = vec![];
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("url(") {
+        let start = offset + start + "url(".len();
+        let end = match lower[start..].find(')') {
+            Some(len) => start + len,
+            None => break,
+        };
+        links.push(String::from(unquote(css[start..end].trim())));
+        offset = end;
+    }
+    offset = 0;
+    while let Some(start) = lower[offset..].find("@import") {
+        let start = offset + start + "@import".len();
+        offset = start;
+        let rest = css[start..].trim_start();
+        if let Some(quote) = rest.chars().next().filter(|&c| c == '"' || c == '\'') {
+            if let Some(len) = rest[1..].find(quote) {
+                links.push(String::from(&rest[1..1 + len]));
+            }
+        }
+    }
+    links
+}
+
+fn unquote(s: &str) -> &str {
+    for &quote in ['"', '\''].iter() {
+        if s.len() >= 2 && s.starts_with(quote) && s.ends_with(quote) {
+            return &s[1..s.len() - 1]
+        }
+    }
+    s
+}
+
+/// Resolve `link`, found on the page at `page`, to a path relative to the
+/// root of the site. Returns `None` for links which don't point into the
+/// site, like those to other hosts or fragments of the same page.
+pub fn resolve(page: &str, link: &str) -> Option<String> {
+    let link = link.trim();
+    let link = &link[..link.find(|c: char| c == '#' || c == '?').unwrap_or(link.len())];
+    if link == "" || link.starts_with("//") || has_scheme(link) {
+        return None
+    }
+    let joined = if link.starts_with('/') {
+        String::from(link)
+    } else {
+        let dir = match page.rfind('/') {
+            Some(i) => &page[..i + 1],
+            None => "",
+        };
+        format!("{}{}", dir, link)
+    };
+    let mut segments: Vec<&str> = vec![];
+    for part in joined.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            },
+            part => segments.push(part),
+        }
+    }
+    let mut path = segments.join("/");
+    // Keep any trailing slash, which is part of the url.
+    let last = joined.rsplit('/').next().unwrap_or("");
+    if path != "" && (last == "" || last == "." || last == "..") {
+        path.push('/');
+    }
+    Some(search::percent_decode(&path))
+}
+
+/// Whether `link` starts with a url scheme, like `https:` or `mailto:`.
+fn has_scheme(link: &str) -> bool {
+    match link.find(':') {
+        Some(i) => {
+            let scheme = &link[..i];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(page: &str, link: &str) -> Option<String> {
+        resolve(page, link)
+    }
+
+    #[test]
+    fn root_links_resolve_to_the_root() {
+        for link in ["/", "./", ".", "../", "/#top", "/?q=1"].iter() {
+            assert_eq!(resolved("", link).as_deref(), Some(""), "{:?}", link);
+            assert_eq!(resolved("/", link).as_deref(), Some(""), "{:?}", link);
+        }
+        assert_eq!(resolved("", "index.html").as_deref(), Some("index.html"));
+    }
+
+    #[test]
+    fn relative_links_resolve_against_the_page_directory() {
+        assert_eq!(resolved("blog/post.html", "other.html").as_deref(), Some("blog/other.html"));
+        assert_eq!(resolved("blog/", "post.html").as_deref(), Some("blog/post.html"));
+        assert_eq!(resolved("blog", "post.html").as_deref(), Some("post.html"));
+        assert_eq!(resolved("blog/post.html", "/about").as_deref(), Some("about"));
+        assert_eq!(resolved("blog/post.html", "img/a%20b.png").as_deref(), Some("blog/img/a b.png"));
+    }
+
+    #[test]
+    fn dot_dot_segments_go_up_but_not_past_the_root() {
+        assert_eq!(resolved("a/b/c.html", "../d.html").as_deref(), Some("a/d.html"));
+        assert_eq!(resolved("a/b/c.html", "../../../d.html").as_deref(), Some("d.html"));
+        assert_eq!(resolved("a/b/c.html", "./../.").as_deref(), Some("a/"));
+    }
+
+    #[test]
+    fn queries_and_fragments_are_dropped() {
+        assert_eq!(resolved("a/", "b.html?x=1#y").as_deref(), Some("a/b.html"));
+        assert_eq!(resolved("a/", "b/#y").as_deref(), Some("a/b/"));
+        assert_eq!(resolved("a/", "#top"), None);
+        assert_eq!(resolved("a/", "?page=2"), None);
+    }
+
+    #[test]
+    fn trailing_slashes_are_kept() {
+        assert_eq!(resolved("", "docs/").as_deref(), Some("docs/"));
+        assert_eq!(resolved("", "docs").as_deref(), Some("docs"));
+        assert_eq!(resolved("docs/a/", "..").as_deref(), Some("docs/"));
+    }
+
+    #[test]
+    fn links_off_the_site_are_ignored() {
+        for link in ["https://example.com/", "//example.com/a", "mailto:a@b.c", "data:,x", ""].iter() {
+            assert_eq!(resolved("", link), None, "{:?}", link);
+        }
+    }
+
+    #[test]
+    fn links_are_found_in_html_and_css() {
+        let html = "<a href=\"a.html\">a</a><!-- <a href=\"no.html\"> -->\
+                    <img src='b.png' style=\"background: url(c.png)\">\
+                    <script>var x = '<a href=\"no.html\">';</script>\
+                    <style>@import \"d.css\"; p { background: url('e.png') }</style>";
+        assert_eq!(html_links(html), vec!["a.html", "b.png", "c.png", "e.png", "d.css"]);
+    }
+}
@@ -1,44 +1,1765 @@
-use crate::shortcuts::entity_list;
+use crate::{
+    blobs::{self, BlobTable},
+    feed,
+    links::{self, BrokenLink},
+    notify,
+    page_meta::{self, PageMeta},
+    redirects,
+    search::{self, Document},
+    shortcuts::entity_list,
+    site_settings::{Fallback, Settings},
+    sitemap,
+    url_policy,
+    web_site_session,
+};
 use lmdb;
 use lmdb::Transaction;
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::CString,
+    fs,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
     path,
-    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock, RwLockReadGuard,
+    },
+    time::Duration,
 };
 use capnp::{Error, capability::Promise};
+use capnp_rpc::pry;
 use sandstorm::{
     util_capnp::assignable,
     web_publishing_capnp::web_site,
 };
 
+/// A handle to a site, or a subtree of one. Handles may be sent to and
+/// shared between threads; the observers registered through them stay on
+/// the thread running the RPC system, which their capabilities belong to.
+/// Reads which may be slow are run on the blocking thread pool.
 #[derive(Clone, Debug)]
 pub struct LMDBWebSite {
-    db_name: String,
     url: String,
-    env: Rc<lmdb::Environment>,
-    db: Rc<lmdb::Database>,
+
+    /// The url of the whole site, which `url` is under.
+    root_url: String,
+
+    /// The directory holding the site's database.
+    path: path::PathBuf,
+    map_size: MapSize,
+
+    /// Shared by every handle to the site, so they all move over to the
+    /// new database when it is compacted.
+    tables: Arc<RwLock<Arc<Tables>>>,
+    observers: notify::Observers,
+
+    /// Whether a regeneration of the sitemap is scheduled; see
+    /// `regenerate_sitemap_soon()`.
+    sitemap_pending: Arc<AtomicBool>,
+
+    /// Whether the last deferred regeneration of the sitemap failed, in
+    /// which case the next write retries it.
+    sitemap_failed: Arc<AtomicBool>,
 }
 
+/// An open site database, and the tables in it.
+#[derive(Debug)]
+struct Tables {
+    env: lmdb::Environment,
+
+    /// The unnamed database, which holds the names of the others. Before
+    /// schema version 1, it also held the entities.
+    main_db: lmdb::Database,
+
+    /// The entities stored at each path, keyed by url.
+    db: lmdb::Database,
+
+    /// Compiled redirect rules, keyed by the url of the directory whose
+    /// `_redirects` file they came from.
+    redirects_db: lmdb::Database,
+
+    /// Site-wide settings, keyed by the url of the site.
+    settings_db: lmdb::Database,
+
+    /// Metadata about pages, keyed by the same urls as `db`.
+    meta_db: lmdb::Database,
+
+    /// Files we generate for each site, like its sitemap, keyed by the
+    /// same urls as `db`. Anything the user stores at the same url in
+    /// `db` takes precedence.
+    generated_db: lmdb::Database,
+
+    /// The full-text search index. For each page this holds a
+    /// `search::Document`, under `d\0<url>`, and for each term in the
+    /// page a `Posting`, under `t\0<site url>\0<term>\0<path>`, so the
+    /// pages containing a term can be found with one scan. Keys too long
+    /// for LMDB are shortened with `fit_key()`.
+    search_db: lmdb::Database,
+
+    /// The bodies of the entities in `db`.
+    blobs: BlobTable,
+
+    /// The version of the schema the database is in, under
+    /// `SCHEMA_VERSION_KEY`.
+    schema_db: lmdb::Database,
+
+    /// The paths published by each site's last build, keyed by the url of
+    /// the site, so the next build can delete the ones it doesn't publish.
+    builds_db: lmdb::Database,
+    map_size: MapSize,
+
+    /// Held shared by every transaction, on any thread, and exclusively
+    /// to change the size of the map, which LMDB only allows while no
+    /// transactions are open in the process.
+    txn_lock: RwLock<()>,
+
+    /// The site's lock file, which we hold a shared `flock()` on while the
+    /// database is open; see `LMDBWebSite::claim()`.
+    lock: fs::File,
+}
+
+impl Tables {
+    /// Open the database in `p`, creating it if need be, and bring it up
+    /// to the current schema version. `lock` is the site's lock file,
+    /// which we already hold a lock on.
+    fn open(p: &path::Path, map_size: MapSize, lock: fs::File) -> Result<Self, Error> {
+        let env = lmdb::Environment::new()
+            .set_max_dbs(MAX_DBS)
+            .set_map_size(map_size.initial)
+            .open(p)
+            .map_err(db_err)?;
+        let create = |name| env.create_db(Some(name), lmdb::DatabaseFlags::empty()).map_err(db_err);
+        let tables = Tables {
+            main_db: env.open_db(None).map_err(db_err)?,
+            db: create(ENTITIES_DB_NAME)?,
+            redirects_db: create(REDIRECTS_DB_NAME)?,
+            settings_db: create(SETTINGS_DB_NAME)?,
+            meta_db: create(META_DB_NAME)?,
+            generated_db: create(GENERATED_DB_NAME)?,
+            search_db: create(SEARCH_DB_NAME)?,
+            blobs: BlobTable {
+                blobs: create(BLOBS_DB_NAME)?,
+                refs: create(BLOB_REFS_DB_NAME)?,
+            },
+            schema_db: create(SCHEMA_DB_NAME)?,
+            builds_db: create(BUILDS_DB_NAME)?,
+            env: env,
+            map_size: map_size,
+            txn_lock: RwLock::new(()),
+            lock: lock,
+        };
+        tables.migrate()?;
+        Ok(tables)
+    }
+
+    /// Run the migrations the database hasn't had yet, each in its own
+    /// transaction, so an interrupted upgrade picks up where it left off.
+    fn migrate(&self) -> Result<(), Error> {
+        loop {
+            let done = self.write_txn(|txn| {
+                let version = match txn.get(self.schema_db, &SCHEMA_VERSION_KEY) {
+                    Ok(bytes) if bytes.len() == 4 => {
+                        let mut version = [0; 4];
+                        version.copy_from_slice(bytes);
+                        u32::from_le_bytes(version)
+                    },
+                    Ok(_) => return Err(db_err(lmdb::Error::Corrupted)),
+                    Err(lmdb::Error::NotFound) => 0,
+                    Err(e) => return Err(db_err(e)),
+                };
+                if version > SCHEMA_VERSION {
+                    return Err(Error::failed(format!(
+                        "The site's database has schema version {}, but this version of \
+                         the app only understands up to {}",
+                        version,
+                        SCHEMA_VERSION,
+                    )))
+                }
+                if version == SCHEMA_VERSION {
+                    return Ok(true)
+                }
+                MIGRATIONS[version as usize](self, txn)?;
+                txn.put(
+                    self.schema_db,
+                    &SCHEMA_VERSION_KEY,
+                    &(version + 1).to_le_bytes(),
+                    lmdb::WriteFlags::empty(),
+                ).map_err(db_err)?;
+                Ok(false)
+            })?;
+            if done {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Run `f` in a write transaction, and commit it. If the map fills up,
+    /// it is grown and `f` is run again in a new transaction.
+    fn write_txn<T, F>(&self, mut f: F) -> Result<T, Error>
+        where F: FnMut(&mut lmdb::RwTransaction) -> Result<T, Error>
+    {
+        loop {
+            let result = {
+                let _shared = self.txn_lock.read().unwrap();
+                match self.env.begin_rw_txn() {
+                    Err(e) => Err(e),
+                    Ok(mut txn) => Ok(f(&mut txn).and_then(|value| {
+                        txn.commit().map_err(db_err)?;
+                        Ok(value)
+                    })),
+                }
+            };
+            // The transaction has been committed or aborted, and the lock
+            // released, by now, so the map can be resized.
+            match result {
+                Err(lmdb::Error::MapResized) => self.set_map_size(0)?,
+                Err(e) => return Err(db_err(e)),
+                Ok(Err(ref e)) if is_map_full(e) => self.grow_map()?,
+                Ok(result) => return result,
+            }
+        }
+    }
+
+    /// Begin a read-only transaction, first adopting the new map size if
+    /// another process has grown the map.
+    fn begin_ro_txn(&self) -> Result<ReadTxn, Error> {
+        loop {
+            let shared = self.txn_lock.read().unwrap();
+            match self.env.begin_ro_txn() {
+                Ok(txn) => return Ok(ReadTxn {
+                    txn: txn,
+                    _shared: shared,
+                }),
+                Err(lmdb::Error::MapResized) => {
+                    drop(shared);
+                    self.set_map_size(0)?;
+                },
+                Err(e) => return Err(db_err(e)),
+            }
+        }
+    }
+
+    /// The current size of the map.
+    fn current_map_size(&self) -> usize {
+        let mut info = std::mem::MaybeUninit::<lmdb_sys::MDB_envinfo>::uninit();
+        unsafe {
+            lmdb_sys::mdb_env_info(self.env.env(), info.as_mut_ptr());
+            info.assume_init().me_mapsize
+        }
+    }
+
+    /// Change the size of the map. A size of zero picks up the size set by
+    /// another process. Waits for the transactions open in this process to
+    /// finish, so the calling thread mustn't have one open.
+    fn set_map_size(&self, size: usize) -> Result<(), Error> {
+        let _exclusive = self.txn_lock.write().unwrap();
+        match unsafe { lmdb_sys::mdb_env_set_mapsize(self.env.env(), size) } {
+            0 => Ok(()),
+            code => Err(db_err(lmdb::Error::from_err_code(code))),
+        }
+    }
+
+    /// Double the size of the map, up to the maximum. Fails if it is
+    /// already as large as it may get.
+    fn grow_map(&self) -> Result<(), Error> {
+        let current = self.current_map_size();
+        if current >= self.map_size.max {
+            return Err(db_err(lmdb::Error::MapFull))
+        }
+        self.set_map_size(current.saturating_mul(2).min(self.map_size.max))
+    }
+
+    /// Read the entities stored at `url`, or generated for it.
+    fn entities(&self, url: &str)
+        -> Result<Option<capnp::message::Builder<capnp::message::HeapAllocator>>, Error>
+    {
+        let txn = self.begin_ro_txn()?;
+        let stored = txn.get(self.db, &url).or_else(|e| match e {
+            lmdb::Error::NotFound => txn.get(self.generated_db, &url),
+            e => Err(e),
+        });
+        match stored {
+            Ok(bytes) => Ok(Some(decode_record(&txn, self.blobs, bytes)?)),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(db_err(e)),
+        }
+    }
+}
+
+/// A read-only transaction, which holds `Tables::txn_lock` until it ends.
+struct ReadTxn<'env> {
+    // Declared first, so the transaction ends before the lock is released.
+    txn: lmdb::RoTransaction<'env>,
+    _shared: RwLockReadGuard<'env, ()>,
+}
+
+impl<'env> Transaction for ReadTxn<'env> {
+    fn txn(&self) -> *mut lmdb_sys::MDB_txn {
+        self.txn.txn()
+    }
+
+    // The defaults forget `self` once the transaction has ended, which
+    // would leak the lock.
+    fn commit(self) -> lmdb::Result<()> {
+        self.txn.commit()
+    }
+
+    fn abort(self) {
+        self.txn.abort()
+    }
+}
+
+/// The result of `LMDBWebSite::compact()`.
 #[derive(Clone, Debug)]
-struct EntitiesCell(Rc<LMDBWebSite>);
+pub struct Compaction {
+    /// The size of the database file before compacting, in bytes.
+    pub before: u64,
 
-pub fn db_err(_: lmdb::Error) -> Error {
-    Error::failed(String::from("Database Error"))
+    /// Its size afterwards.
+    pub after: u64,
 }
 
-impl LMDBWebSite {
-    pub fn open(db_name: String, url: String, p: &path::Path) -> lmdb::Result<Self> {
-        let env = lmdb::Environment::new().open(p)?;
-        let db = env.open_db(Some(&db_name[..])).or_else(|_| {
-            env.create_db(None, lmdb::DatabaseFlags::empty())
+impl Compaction {
+    pub fn reclaimed(&self) -> u64 {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct EntitiesCell(LMDBWebSite);
+
+/// Serves a page of a site's listing at `LISTING_PATH`. `query` holds the
+/// parameters given in the path.
+#[derive(Clone, Debug)]
+struct ListingCell {
+    site: LMDBWebSite,
+    query: String,
+}
+
+/// Summary of a single entity, as reported by `LMDBWebSite::list()`.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntitySummary {
+    pub mime_type: String,
+    pub language: String,
+    pub encoding: String,
+
+    /// The size of the body, if it is stored inline.
+    pub size: Option<usize>,
+
+    pub redirect_to: Option<String>,
+}
+
+/// The entities stored at one path.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct PathSummary {
+    pub path: String,
+    pub entities: Vec<EntitySummary>,
+}
+
+/// One page of results from `LMDBWebSite::list()`.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Listing {
+    pub paths: Vec<PathSummary>,
+
+    /// If there are more results, the path to pass as `start` to get the
+    /// next page.
+    pub next: Option<String>,
+}
+
+/// The most named databases we can have. This leaves room for those added
+/// by future migrations.
+const MAX_DBS: u32 = 16;
+
+/// The name of the database holding each path's entities.
+const ENTITIES_DB_NAME: &str = "entities";
+
+/// The name of the database holding each site's redirect rules.
+const REDIRECTS_DB_NAME: &str = "redirects";
+
+/// The name of the database holding each site's settings.
+const SETTINGS_DB_NAME: &str = "settings";
+
+/// The name of the database holding metadata about pages.
+const META_DB_NAME: &str = "meta";
+
+/// The name of the database holding generated files.
+const GENERATED_DB_NAME: &str = "generated";
+
+/// The name of the database holding the search index.
+const SEARCH_DB_NAME: &str = "search";
+
+/// The names of the databases holding the bodies of entities, and the
+/// counts of references to them.
+const BLOBS_DB_NAME: &str = "blobs";
+const BLOB_REFS_DB_NAME: &str = "blob_refs";
+
+/// The name of the database holding the schema version.
+const SCHEMA_DB_NAME: &str = "schema";
+
+/// The name of the database holding what each site's last build published.
+const BUILDS_DB_NAME: &str = "builds";
+
+/// The names of all of our named databases.
+const DB_NAMES: &[&str] = &[
+    ENTITIES_DB_NAME,
+    REDIRECTS_DB_NAME,
+    SETTINGS_DB_NAME,
+    META_DB_NAME,
+    GENERATED_DB_NAME,
+    SEARCH_DB_NAME,
+    BLOBS_DB_NAME,
+    BLOB_REFS_DB_NAME,
+    SCHEMA_DB_NAME,
+    BUILDS_DB_NAME,
+];
+
+/// The key the schema version is stored under, as a little-endian u32.
+/// Databases without one are at version 0.
+const SCHEMA_VERSION_KEY: &str = "version";
+
+/// The schema version this version of the app writes.
+const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// The steps to upgrade a database to the current schema. Each upgrades
+/// from the version equal to its index to the next one. To change the
+/// schema, add one to the end; never change or remove existing ones, since
+/// databases may be at any version.
+const MIGRATIONS: &[fn(&Tables, &mut lmdb::RwTransaction) -> Result<(), Error>] = &[
+    move_entities_to_named_db,
+    move_feeds_to_generated_db,
+];
+
+/// Move the entities out of the unnamed database, where versions before
+/// the schema was versioned kept them, into `ENTITIES_DB_NAME`.
+fn move_entities_to_named_db(tables: &Tables, txn: &mut lmdb::RwTransaction) -> Result<(), Error> {
+    let mut entities = vec![];
+    scan(&*txn, tables.main_db, b"", |key, value| -> Result<bool, Error> {
+        // The unnamed database also holds the names of the named ones.
+        if !DB_NAMES.iter().any(|name| name.as_bytes() == key) {
+            entities.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(true)
+    })?;
+    for (key, value) in entities {
+        txn.put(tables.db, &key, &value, lmdb::WriteFlags::empty()).map_err(db_err)?;
+        txn.del(tables.main_db, &key, None).map_err(db_err)?;
+    }
+    Ok(())
+}
+
+/// Move the sites' feeds into `GENERATED_DB_NAME`. Versions before this
+/// one wrote them into `ENTITIES_DB_NAME`, over anything stored at their
+/// paths; feeds are the only records there with their bodies inline.
+fn move_feeds_to_generated_db(tables: &Tables, txn: &mut lmdb::RwTransaction) -> Result<(), Error> {
+    let mut feeds = vec![];
+    scan(&*txn, tables.settings_db, b"", |key, value| -> Result<bool, Error> {
+        let root_url = std::str::from_utf8(key)?;
+        let settings: Settings = serde_json::from_slice(value).map_err(|e| {
+            Error::failed(format!("Corrupt record for {}: {}", root_url, e))
         })?;
+        for path in [&settings.feed.atom_path, &settings.feed.rss_path].iter() {
+            if !path.is_empty() {
+                feeds.push(String::from(root_url) + path.trim_start_matches('/'));
+            }
+        }
+        Ok(true)
+    })?;
+    for key in feeds {
+        let value = match txn.get(tables.db, &key) {
+            Ok(value) if !split_record(value).1 => Vec::from(value),
+            Ok(_) | Err(lmdb::Error::NotFound) => continue,
+            Err(e) => return Err(db_err(e)),
+        };
+        txn.put(tables.generated_db, &key, &value, lmdb::WriteFlags::empty()).map_err(db_err)?;
+        txn.del(tables.db, &key, None).map_err(db_err)?;
+    }
+    Ok(())
+}
+
+/// Records written to `db` by `LMDBWebSite::write()` start with this. The
+/// bodies of their entities are replaced by their hashes, and kept in the
+/// blob table. Records without it, like those in `generated_db` and
+/// anything stored by older versions, hold their bodies inline. It is 8 bytes long
+/// so the message after it stays aligned.
+const BLOB_RECORD_MAGIC: &[u8] = b"wpblobs\0";
+
+/// The number of pages to include in a site's feed.
+const FEED_LENGTH: usize = 20;
+
+/// How many paths `listResources()` reads per transaction.
+const LIST_RESOURCES_BATCH: usize = 1000;
+
+/// The path, in any site or subsite, at which `getEntities()` serves a page
+/// of the site's listing, since `listResources()` can only return every
+/// name at once, without summaries. The page is a `Listing`, as JSON; the
+/// `prefix`, `start` and `limit` query parameters are as for `list()`,
+/// though no more than `LIST_RESOURCES_BATCH` paths are returned at once.
+pub const LISTING_PATH: &str = "_listing";
+
+/// How long to wait after a path is set before regenerating the sitemap,
+/// so that paths set together are covered by one regeneration.
+const SITEMAP_DELAY: Duration = Duration::from_millis(500);
+
+/// How many redirects the link checker follows before deciding a link
+/// leads nowhere.
+const MAX_REDIRECTS: usize = 8;
+
+/// The path of a hidden file or directory next to the site in `path`,
+/// named after the site with `suffix` appended. These are left out when
+/// listing sites.
+pub(crate) fn sibling(path: &path::Path, suffix: &str) -> path::PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Take (or change) a `flock()` lock on `file`; `operation` is as for
+/// `flock(2)`.
+fn flock(file: &fs::File, operation: libc::c_int) -> std::io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Convert an LMDB error to a capnp one, keeping what kind of error it
+/// was. A full map is reported as `Overloaded`, which nothing else in this
+/// module uses, so `write_txn()` can tell when to grow the map.
+pub fn db_err(e: lmdb::Error) -> Error {
+    match e {
+        lmdb::Error::MapFull => {
+            Error::overloaded(format!("The site is too large for its database: {}", e))
+        },
+        lmdb::Error::Corrupted |
+        lmdb::Error::PageNotFound |
+        lmdb::Error::Invalid |
+        lmdb::Error::VersionMismatch => {
+            Error::failed(format!("The site's database is corrupt: {}", e))
+        },
+        e => Error::failed(format!("Database error: {}", e)),
+    }
+}
+
+fn is_map_full(e: &Error) -> bool {
+    e.kind == capnp::ErrorKind::Overloaded
+}
+
+/// How large a site's database may get. LMDB maps the whole database
+/// into memory, and can't store more than fits in the map.
+#[derive(Clone, Copy, Debug)]
+pub struct MapSize {
+    /// The size of the map databases are opened with. Databases which are
+    /// already larger get a map big enough for them.
+    pub initial: usize,
+
+    /// The size the map may be grown to when it fills up. Writes which
+    /// need more space than this fail.
+    pub max: usize,
+}
+
+impl Default for MapSize {
+    fn default() -> Self {
+        MapSize {
+            initial: 64 << 20,
+            max: 16 << 30,
+        }
+    }
+}
+
+impl MapSize {
+    /// The defaults, overridden by the `WEBPUB_MAP_SIZE` and
+    /// `WEBPUB_MAX_MAP_SIZE` environment variables, which are in MiB.
+    pub fn from_env() -> Self {
+        let mib = |name| {
+            std::env::var(name).ok()
+                .and_then(|value| value.parse::<usize>().ok())
+                .map(|value| value << 20)
+        };
+        let default = MapSize::default();
+        let initial = mib("WEBPUB_MAP_SIZE").unwrap_or(default.initial);
+        MapSize {
+            initial: initial,
+            max: mib("WEBPUB_MAX_MAP_SIZE").unwrap_or(default.max).max(initial),
+        }
+    }
+}
+
+/// Walk the key/value pairs in `db` in order, starting with the first key
+/// which is >= `start`. Stops when `f` returns false.
+pub(crate) fn scan<T, F, E>(txn: &T, db: lmdb::Database, start: &[u8], mut f: F) -> Result<(), E>
+    where T: Transaction,
+          F: FnMut(&[u8], &[u8]) -> Result<bool, E>,
+          E: From<Error>,
+{
+    use lmdb::Cursor;
+    let cursor = txn.open_ro_cursor(db).map_err(db_err)?;
+    // LMDB rejects empty keys, so MDB_SET_RANGE won't work for an empty
+    // start; just go to the first item instead.
+    let (mut key, mut op) = if start.len() == 0 {
+        (None, lmdb_sys::MDB_FIRST)
+    } else {
+        (Some(start), lmdb_sys::MDB_SET_RANGE)
+    };
+    loop {
+        match cursor.get(key, None, op) {
+            Ok((k, v)) => {
+                if !f(k.unwrap_or(start), v)? {
+                    return Ok(())
+                }
+            },
+            Err(lmdb::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(db_err(e).into()),
+        }
+        key = None;
+        op = lmdb_sys::MDB_NEXT;
+    }
+}
+
+/// Read the JSON value stored at `key` in `db`, if there is one.
+fn get_json<T, X>(txn: &X, db: lmdb::Database, key: &str) -> Result<Option<T>, Error>
+    where T: serde::de::DeserializeOwned,
+          X: Transaction,
+{
+    match txn.get(db, &key) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(bytes).map_err(|e| {
+            Error::failed(format!("Corrupt record for {}: {}", key, e))
+        })?)),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => Err(db_err(e)),
+    }
+}
+
+/// The longest key LMDB can store, with the default page size.
+const MAX_KEY_SIZE: usize = 511;
+
+/// Shorten `key` if it is too long for LMDB, by cutting it short and
+/// appending a hash of the whole, so it stays unique and keeps the start
+/// scans look for. Returns the key, and whether it was shortened.
+fn fit_key(key: String) -> (String, bool) {
+    if key.len() <= MAX_KEY_SIZE {
+        return (key, false)
+    }
+    let hash = hex::encode(blobs::hash(key.as_bytes()));
+    let mut end = MAX_KEY_SIZE - hash.len() - 1;
+    while !key.is_char_boundary(end) {
+        end -= 1;
+    }
+    (format!("{}\u{1}{}", &key[..end], hash), true)
+}
+
+/// What the search index holds for a term in a page.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum Posting {
+    /// The number of times the term occurs.
+    Count(u32),
+
+    /// The same, for a page whose key had to be shortened, which can't
+    /// be relied on to hold its path.
+    Shortened {
+        count: u32,
+        path: String,
+    },
+}
+
+fn put_json<T: serde::Serialize>(txn: &mut lmdb::RwTransaction,
+                                 db: lmdb::Database,
+                                 key: &str,
+                                 value: &T) -> Result<(), Error> {
+    let json = serde_json::to_vec(value).map_err(|e| Error::failed(e.to_string()))?;
+    txn.put(db, &key, &json, lmdb::WriteFlags::empty()).map_err(db_err)
+}
+
+/// The version the site will have once `txn` is committed. The id of a
+/// write transaction is the id it will have once committed, so we use it
+/// as the site's version.
+fn txn_version(txn: &lmdb::RwTransaction) -> u64 {
+    unsafe { lmdb_sys::mdb_txn_id(txn.txn()) as u64 }
+}
+
+/// Serialize a list holding just one entity, with the given type and body.
+fn single_entity(mime_type: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut msg = capnp::message::Builder::new_default();
+    {
+        let list: entity_list::Builder = msg.initn_root(1);
+        let mut entity = list.get(0);
+        entity.set_mime_type(mime_type);
+        entity.reborrow().get_body().set_bytes(body);
+    }
+    let mut buffer = vec![];
+    capnp::serialize::write_message(&mut buffer, &msg)?;
+    Ok(buffer)
+}
+
+/// Split a record from `db` into the message it holds, and whether the
+/// bodies of its entities are in the blob table.
+fn split_record(bytes: &[u8]) -> (&[u8], bool) {
+    if bytes.starts_with(BLOB_RECORD_MAGIC) {
+        (&bytes[BLOB_RECORD_MAGIC.len()..], true)
+    } else {
+        (bytes, false)
+    }
+}
+
+/// Copy `src` into `dst`, except for its body, which is set to `body`.
+fn copy_entity(src: web_site::entity::Reader,
+               mut dst: web_site::entity::Builder,
+               body: Option<&[u8]>) -> Result<(), Error> {
+    dst.set_mime_type(src.get_mime_type()?);
+    dst.set_language(src.get_language()?);
+    dst.set_encoding(src.get_encoding()?);
+    if src.has_redirect_to() {
+        dst.set_redirect_to(src.get_redirect_to()?);
+    }
+    if let Some(body) = body {
+        dst.get_body().set_bytes(body);
+    }
+    Ok(())
+}
+
+/// Serialize `value` to be stored in `db`, with the bodies of its entities
+/// replaced by their hashes. Empty bodies are left as they are.
+fn encode_record(value: entity_list::Reader) -> Result<Vec<u8>, Error> {
+    let mut msg = capnp::message::Builder::new_default();
+    {
+        let mut list: entity_list::Builder = msg.initn_root(value.len());
+        for (i, entity) in value.iter().enumerate() {
+            let hash = match entity.get_body().which()? {
+                web_site::entity::body::Bytes(bytes) => {
+                    let bytes = bytes?;
+                    if bytes.is_empty() { None } else { Some(blobs::hash(bytes)) }
+                },
+                web_site::entity::body::Blob(_) => {
+                    return Err(Error::unimplemented(String::from("Blob bodies are not supported")))
+                },
+            };
+            copy_entity(entity, list.reborrow().get(i as u32), hash.as_ref().map(|hash| &hash[..]))?;
+        }
+    }
+    let mut buffer = Vec::from(BLOB_RECORD_MAGIC);
+    capnp::serialize::write_message(&mut buffer, &msg)?;
+    Ok(buffer)
+}
+
+/// Read a record from `db` or `generated_db`, with the bodies of its
+/// entities filled in from `blobs`.
+fn decode_record<T: Transaction>(txn: &T, blobs: BlobTable, bytes: &[u8])
+    -> Result<capnp::message::Builder<capnp::message::HeapAllocator>, Error>
+{
+    let (mut bytes, in_blobs) = split_record(bytes);
+    let stored =
+        capnp::serialize::read_message_from_flat_slice(
+            &mut bytes,
+            Default::default(),
+        )?;
+    let stored: entity_list::Reader = stored.get_root()?;
+    let mut msg = capnp::message::Builder::new_default();
+    if !in_blobs {
+        msg.set_root(stored)?;
+        return Ok(msg)
+    }
+    {
+        let mut list: entity_list::Builder = msg.initn_root(stored.len());
+        for (i, entity) in stored.iter().enumerate() {
+            let body = match entity.get_body().which()? {
+                web_site::entity::body::Bytes(hash) => {
+                    let hash = hash?;
+                    if hash.is_empty() { None } else { Some(blobs.get(txn, hash).map_err(db_err)?) }
+                },
+                web_site::entity::body::Blob(_) => None,
+            };
+            copy_entity(entity, list.reborrow().get(i as u32), body)?;
+        }
+    }
+    Ok(msg)
+}
+
+/// The hashes of the bodies a record from `db` refers to.
+fn record_hashes(bytes: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let (mut bytes, in_blobs) = split_record(bytes);
+    if !in_blobs {
+        return Ok(vec![])
+    }
+    let msg =
+        capnp::serialize::read_message_from_flat_slice(
+            &mut bytes,
+            Default::default(),
+        )?;
+    let entities: entity_list::Reader = msg.get_root()?;
+    let mut hashes = vec![];
+    for entity in entities.iter() {
+        if let web_site::entity::body::Bytes(hash) = entity.get_body().which()? {
+            let hash = hash?;
+            if !hash.is_empty() {
+                hashes.push(Vec::from(hash));
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+fn summarize<T: Transaction>(txn: &T, blobs: BlobTable, bytes: &[u8]) -> Result<Vec<EntitySummary>, Error> {
+    let (mut bytes, in_blobs) = split_record(bytes);
+    let msg =
+        capnp::serialize::read_message_from_flat_slice(
+            &mut bytes,
+            Default::default(),
+        )?;
+    let entities: entity_list::Reader = msg.get_root()?;
+    let mut ret = Vec::with_capacity(entities.len() as usize);
+    for entity in entities.iter() {
+        let size = match entity.get_body().which()? {
+            web_site::entity::body::Bytes(bytes) => {
+                let bytes = bytes?;
+                if in_blobs && !bytes.is_empty() {
+                    Some(blobs.get(txn, bytes).map_err(db_err)?.len())
+                } else {
+                    Some(bytes.len())
+                }
+            },
+            web_site::entity::body::Blob(_) => None,
+        };
+        let redirect_to = if entity.has_redirect_to() {
+            Some(String::from(entity.get_redirect_to()?))
+        } else {
+            None
+        };
+        ret.push(EntitySummary {
+            mime_type: String::from(entity.get_mime_type()?),
+            language: String::from(entity.get_language()?),
+            encoding: String::from(entity.get_encoding()?),
+            size: size,
+            redirect_to: redirect_to,
+        });
+    }
+    Ok(ret)
+}
+
+impl LMDBWebSite {
+    pub fn open(url: String, p: &path::Path, map_size: MapSize) -> Result<Self, Error> {
+        let lock = fs::OpenOptions::new().create(true).write(true).open(sibling(p, ".lock"))?;
+        // Wait out anyone who has claimed the site.
+        flock(&lock, libc::LOCK_SH)?;
+        let tables = Tables::open(p, map_size, lock)?;
         Ok(LMDBWebSite {
-            db_name: db_name,
+            root_url: url.clone(),
             url: url,
-            db: Rc::new(db),
-            env: Rc::new(env),
+            path: p.to_path_buf(),
+            map_size: map_size,
+            tables: Arc::new(RwLock::new(Arc::new(tables))),
+            observers: notify::Observers::default(),
+            sitemap_pending: Arc::new(AtomicBool::new(false)),
+            sitemap_failed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn tables(&self) -> Arc<Tables> {
+        self.tables.read().unwrap().clone()
+    }
+
+    /// Whether anything besides this handle is using the site's database,
+    /// like other handles held by capabilities, or reads in progress. The
+    /// database is closed once nothing is.
+    pub fn in_use(&self) -> bool {
+        // One reference to the tables is ours, and one is in the lock.
+        Arc::strong_count(&self.tables) > 1 || Arc::strong_count(&self.tables()) > 2
+    }
+
+    /// See `Tables::write_txn()`.
+    fn write_txn<T, F>(&self, f: F) -> Result<T, Error>
+        where F: FnMut(&mut lmdb::RwTransaction) -> Result<T, Error>
+    {
+        self.tables().write_txn(f)
+    }
+
+    /// Rewrite the site's database without the free pages left behind by
+    /// deleted and replaced entities, since LMDB never shrinks its files
+    /// on its own.
+    ///
+    /// The compacted copy is made next to the database, then the two are
+    /// swapped, and every handle to the site, including those held by live
+    /// capabilities, moves over to the copy. The old database is kept
+    /// open until nothing is using it, but other processes with the site
+    /// open (like the `build` command) must not write to it meanwhile, as
+    /// their changes would be lost.
+    pub fn compact(&self) -> Result<Compaction, Error> {
+        let copy = sibling(&self.path, ".compact");
+        let old = sibling(&self.path, ".old");
+        for dir in [&copy, &old].iter() {
+            match fs::remove_dir_all(dir) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        fs::create_dir(&copy)?;
+
+        let data_file = |dir: &path::Path| dir.join("data.mdb");
+        let before = fs::metadata(data_file(&self.path))?.len();
+        let tables = self.tables();
+        let copy_path = CString::new(copy.as_os_str().as_bytes())
+            .map_err(|e| Error::failed(e.to_string()))?;
+        let code = {
+            // The copy runs in a read transaction of its own.
+            let _shared = tables.txn_lock.read().unwrap();
+            unsafe {
+                lmdb_sys::mdb_env_copy2(tables.env.env(), copy_path.as_ptr(), lmdb_sys::MDB_CP_COMPACT)
+            }
+        };
+        if code != 0 {
+            return Err(db_err(lmdb::Error::from_err_code(code)))
+        }
+
+        // Swap the directories, rather than the files in them, so the new
+        // database gets its own lock file.
+        fs::rename(&self.path, &old)?;
+        fs::rename(&copy, &self.path)?;
+        // The new database shares the old one's lock, so if this process
+        // has claimed the site, it keeps it.
+        let compacted = match Tables::open(&self.path, self.map_size, tables.lock.try_clone()?) {
+            Ok(compacted) => compacted,
+            Err(e) => {
+                let _ = fs::rename(&self.path, &copy);
+                let _ = fs::rename(&old, &self.path);
+                return Err(e)
+            },
+        };
+        *self.tables.write().unwrap() = Arc::new(compacted);
+        drop(tables);
+        fs::remove_dir_all(&old)?;
+        Ok(Compaction {
+            before: before,
+            after: fs::metadata(data_file(&self.path))?.len(),
+        })
+    }
+
+    /// Make sure no other process has this site open, and keep any from
+    /// opening it until this process closes it. Tools which work on the
+    /// database directly, and would pull it out from under a running
+    /// grain, like compaction, should call this first.
+    pub fn claim(&self) -> Result<(), Error> {
+        match flock(&self.tables().lock, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Err(Error::failed(
+                String::from("The site is open in another process; stop the grain first")
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Give up a claim made with `claim()`, so other processes may open the
+    /// site again. This also takes back the shared lock every open site
+    /// holds, which a failed `claim()` may have dropped.
+    pub fn release(&self) -> Result<(), Error> {
+        Ok(flock(&self.tables().lock, libc::LOCK_SH)?)
+    }
+
+    /// Register `observer` to be told about every committed change to a
+    /// path in this site starting with `prefix`.
+    pub fn subscribe(&self,
+                     prefix: &str,
+                     observer: Box<dyn notify::Observer>) -> notify::Subscription {
+        self.observers.subscribe(
+            self.url.clone(),
+            notify::Target::Prefix(String::from(prefix)),
+            observer,
+        )
+    }
+
+    /// List the paths in this site which start with `prefix`, in order,
+    /// along with summaries of their entities. At most `limit` paths are
+    /// returned; to get the next page, pass the returned `next` as `start`.
+    pub fn list(&self,
+                prefix: &str,
+                start: Option<&str>,
+                limit: usize) -> Result<Listing, Error> {
+        let tables = self.tables();
+        let key_prefix = self.url.clone() + prefix;
+        let start_key = match start {
+            Some(start) if start > prefix => self.url.clone() + start,
+            _ => key_prefix.clone(),
+        };
+        let mut listing = Listing {
+            paths: vec![],
+            next: None,
+        };
+        let txn = tables.begin_ro_txn()?;
+        scan(&txn, tables.db, start_key.as_bytes(), |key, value| -> Result<bool, Error> {
+            if !key.starts_with(key_prefix.as_bytes()) {
+                return Ok(false)
+            }
+            let path = String::from(std::str::from_utf8(&key[self.url.len()..])?);
+            if listing.paths.len() >= limit {
+                listing.next = Some(path);
+                return Ok(false)
+            }
+            listing.paths.push(PathSummary {
+                path: path,
+                entities: summarize(&txn, tables.blobs, value)?,
+            });
+            Ok(true)
+        })?;
+        Ok(listing)
+    }
+
+    /// Call `f` on each path in this site starting with `prefix`, in order,
+    /// along with the entities stored there.
+    pub fn for_each_path<F, E>(&self, prefix: &str, mut f: F) -> Result<(), E>
+        where F: FnMut(&str, entity_list::Reader) -> Result<(), E>,
+              E: From<Error>,
+    {
+        let tables = self.tables();
+        let key_prefix = self.url.clone() + prefix;
+        let txn = tables.begin_ro_txn()?;
+        scan(&txn, tables.db, key_prefix.as_bytes(), |key, value| {
+            if !key.starts_with(key_prefix.as_bytes()) {
+                return Ok(false)
+            }
+            let path = std::str::from_utf8(&key[self.url.len()..]).map_err(Error::from)?;
+            let msg = decode_record(&txn, tables.blobs, value)?;
+            f(path, msg.get_root_as_reader()?)?;
+            Ok(true)
+        })
+    }
+
+    /// Find the redirect rule for `path`, from the `_redirects` file of
+    /// the nearest directory enclosing it which has a rule for it.
+    pub fn resolve_redirect(&self, path: &str) -> Result<Option<redirects::Match>, Error> {
+        let tables = self.tables();
+        let txn = tables.begin_ro_txn()?;
+        for dir in redirects::enclosing_dirs(path) {
+            let rules: Option<redirects::Rules> =
+                get_json(&txn, tables.redirects_db, &(self.root_url.clone() + dir))?;
+            if let Some(found) = rules.and_then(|rules| rules.resolve_in(dir, path)) {
+                return Ok(Some(found))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get this site's settings. Sites which have never been configured
+    /// get the defaults.
+    pub fn settings(&self) -> Result<Settings, Error> {
+        let tables = self.tables();
+        let txn = tables.begin_ro_txn()?;
+        Ok(get_json(&txn, tables.settings_db, &self.root_url)?.unwrap_or_default())
+    }
+
+    /// Change this site's settings, regenerating its feeds and sitemap to
+    /// match.
+    pub fn set_settings(&self, settings: &Settings) -> Result<(), Error> {
+        let tables = self.tables();
+        self.write_txn(|txn| {
+            // The feeds may be moving, or going away altogether.
+            let old: Settings = get_json(&*txn, tables.settings_db, &self.root_url)?.unwrap_or_default();
+            for path in [&old.feed.atom_path, &old.feed.rss_path].iter() {
+                if path.is_empty() {
+                    continue
+                }
+                let key = self.root_url.clone() + path.trim_start_matches('/');
+                match txn.del(tables.generated_db, &key, None) {
+                    Ok(()) | Err(lmdb::Error::NotFound) => (),
+                    Err(e) => return Err(db_err(e)),
+                }
+            }
+            put_json(txn, tables.settings_db, &self.root_url, settings)?;
+            self.regenerate_feeds(txn)?;
+            self.regenerate_sitemap(txn)?;
+            Ok(())
         })
     }
+
+    /// Bring the metadata table up to date with `value`, the new contents
+    /// of this path. Returns whether the site's feeds need regenerating.
+    fn update_meta(&self,
+                   txn: &mut lmdb::RwTransaction,
+                   value: entity_list::Reader) -> Result<bool, Error> {
+        let tables = self.tables();
+        let old: Option<PageMeta> = get_json(&*txn, tables.meta_db, &self.url)?;
+        let mut new = None;
+        for entity in value.iter() {
+            let mime_type = entity.get_mime_type()?;
+            if !mime_type.starts_with("text/html") {
+                continue
+            }
+            if let web_site::entity::body::Bytes(bytes) = entity.get_body().which()? {
+                let mut meta = page_meta::extract(&String::from_utf8_lossy(bytes?));
+                meta.modified = chrono::Utc::now().to_rfc3339();
+                new = Some(meta);
+                break
+            }
+        }
+        match new {
+            Some(ref meta) => put_json(txn, tables.meta_db, &self.url, meta)?,
+            None => match txn.del(tables.meta_db, &self.url, None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => (),
+                Err(e) => return Err(db_err(e)),
+            },
+        }
+        let in_feed = |meta: &Option<PageMeta>| meta.as_ref().map_or(false, PageMeta::is_feed_item);
+        let same_entry = match (&old, &new) {
+            (Some(old), Some(new)) => old.same_feed_entry(new),
+            (None, None) => true,
+            _ => false,
+        };
+        Ok(!same_entry && (in_feed(&old) || in_feed(&new)))
+    }
+
+    /// Rewrite the site's feeds in the generated table, from the metadata
+    /// table, as configured in its settings. Returns the keys of the feeds
+    /// written.
+    fn regenerate_feeds(&self, txn: &mut lmdb::RwTransaction) -> Result<Vec<String>, Error> {
+        let tables = self.tables();
+        let settings: Settings = get_json(&*txn, tables.settings_db, &self.root_url)?.unwrap_or_default();
+        let site_url = settings.site_url;
+        let settings = settings.feed;
+        if site_url == "" {
+            return Ok(vec![])
+        }
+        let mut entries = vec![];
+        let root = self.root_url.as_bytes();
+        scan(&*txn, tables.meta_db, root, |key, value| -> Result<bool, Error> {
+            if !key.starts_with(root) {
+                return Ok(false)
+            }
+            let meta: PageMeta = serde_json::from_slice(value).map_err(|e| {
+                Error::failed(format!("Corrupt page metadata: {}", e))
+            })?;
+            if let Some(date) = feed::parse_date(&meta.date) {
+                let path = std::str::from_utf8(&key[root.len()..])?;
+                entries.push(feed::Entry {
+                    title: meta.title,
+                    link: feed::absolute_url(&site_url, path),
+                    date: date,
+                    summary: meta.summary,
+                });
+            }
+            Ok(true)
+        })?;
+        entries.sort_by(|l, r| r.date.cmp(&l.date));
+        entries.truncate(FEED_LENGTH);
+        let feed = feed::Feed {
+            title: if settings.title == "" { site_url.clone() } else { settings.title.clone() },
+            link: site_url.clone(),
+            description: String::new(),
+            entries: entries,
+        };
+
+        let mut written = vec![];
+        let feeds = [
+            (&settings.atom_path, "application/atom+xml"),
+            (&settings.rss_path, "application/rss+xml"),
+        ];
+        for &(path, mime_type) in feeds.iter() {
+            if path == "" {
+                continue
+            }
+            let body = if mime_type == "application/atom+xml" {
+                feed.atom(&feed::absolute_url(&site_url, path))
+            } else {
+                feed.rss()
+            };
+            let buffer = single_entity(mime_type, body.as_bytes())?;
+            let key = self.root_url.clone() + path.trim_start_matches('/');
+            txn.put(tables.generated_db, &key, &buffer, lmdb::WriteFlags::empty()).map_err(db_err)?;
+            written.push(key);
+        }
+        Ok(written)
+    }
+
+    /// The key this path's document is stored under in the search index.
+    fn search_doc_key(&self) -> String {
+        fit_key(format!("d\0{}", self.url)).0
+    }
+
+    /// The key the `Posting` for `term` in this path is stored under in
+    /// the search index, and whether it had to be shortened.
+    fn search_term_key(&self, term: &str) -> (String, bool) {
+        let path = &self.url[self.root_url.len()..];
+        fit_key(format!("t\0{}\0{}\0{}", self.root_url, term, path))
+    }
+
+    /// Bring the search index up to date with `value`, the new contents of
+    /// this path. Html pages are indexed, as is Markdown if the path has
+    /// no html.
+    fn update_search(&self,
+                     txn: &mut lmdb::RwTransaction,
+                     value: entity_list::Reader) -> Result<(), Error> {
+        let tables = self.tables();
+        let doc_key = self.search_doc_key();
+        let old: Option<Document> = get_json(&*txn, tables.search_db, &doc_key)?;
+        let mut new = None;
+        for entity in value.iter() {
+            let mime_type = entity.get_mime_type()?;
+            let bytes = match entity.get_body().which()? {
+                web_site::entity::body::Bytes(bytes) => bytes?,
+                web_site::entity::body::Blob(_) => continue,
+            };
+            if mime_type.starts_with("text/html") {
+                new = Some(Document::from_html(&String::from_utf8_lossy(bytes)));
+                break
+            } else if mime_type.starts_with("text/markdown") && new.is_none() {
+                new = Some(Document::from_markdown(&String::from_utf8_lossy(bytes)));
+            }
+        }
+
+        if let Some(old) = old {
+            for term in old.terms.keys() {
+                match txn.del(tables.search_db, &self.search_term_key(term).0, None) {
+                    Ok(()) | Err(lmdb::Error::NotFound) => (),
+                    Err(e) => return Err(db_err(e)),
+                }
+            }
+        }
+        match new {
+            Some(new) => {
+                for (term, &count) in new.terms.iter() {
+                    let (key, shortened) = self.search_term_key(term);
+                    let posting = if shortened {
+                        Posting::Shortened {
+                            count: count,
+                            path: String::from(&self.url[self.root_url.len()..]),
+                        }
+                    } else {
+                        Posting::Count(count)
+                    };
+                    put_json(txn, tables.search_db, &key, &posting)?;
+                }
+                put_json(txn, tables.search_db, &doc_key, &new)
+            },
+            None => match txn.del(tables.search_db, &doc_key, None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+                Err(e) => Err(db_err(e)),
+            },
+        }
+    }
+
+    /// Search the pages of the site for `query`, returning at most `limit`
+    /// results, best first. The last word of the query also matches
+    /// longer words it is a prefix of, so results can be shown as the user
+    /// types.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<search::Hit>, Error> {
+        let tables = self.tables();
+        let terms = search::query_terms(query);
+        if terms.is_empty() {
+            return Ok(vec![])
+        }
+        let txn = tables.begin_ro_txn()?;
+
+        let doc_prefix = format!("d\0{}", self.root_url);
+        let mut total = 0;
+        scan(&txn, tables.search_db, doc_prefix.as_bytes(), |key, _| -> Result<bool, Error> {
+            if !key.starts_with(doc_prefix.as_bytes()) {
+                return Ok(false)
+            }
+            total += 1;
+            Ok(true)
+        })?;
+
+        let term_prefix = format!("t\0{}\0", self.root_url);
+        let mut scores: HashMap<String, f64> = HashMap::new();
+        for (i, term) in terms.iter().enumerate() {
+            let mut prefix = term_prefix.clone() + term;
+            if i + 1 < terms.len() {
+                prefix.push('\0');
+            }
+            // The pages containing each term matched, with their counts.
+            let mut postings: BTreeMap<String, Vec<(String, u32)>> = BTreeMap::new();
+            scan(&txn, tables.search_db, prefix.as_bytes(), |key, value| -> Result<bool, Error> {
+                if !key.starts_with(prefix.as_bytes()) {
+                    return Ok(false)
+                }
+                let rest = std::str::from_utf8(&key[term_prefix.len()..])?;
+                let mut parts = rest.splitn(2, '\0');
+                let term = parts.next().unwrap_or("");
+                let posting = serde_json::from_slice(value).map_err(|e| {
+                    Error::failed(format!("Corrupt search index: {}", e))
+                })?;
+                let (path, count) = match posting {
+                    Posting::Count(count) => (String::from(parts.next().unwrap_or("")), count),
+                    Posting::Shortened { count, path } => (path, count),
+                };
+                postings.entry(String::from(term)).or_default().push((path, count));
+                Ok(true)
+            })?;
+            for pages in postings.values() {
+                for (path, count) in pages.iter() {
+                    *scores.entry(path.clone()).or_insert(0.0) += search::score(*count, pages.len(), total);
+                }
+            }
+        }
+
+        let mut ranked: Vec<(String, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|l, r| {
+            r.1.partial_cmp(&l.1).unwrap_or(std::cmp::Ordering::Equal).then_with(|| l.0.cmp(&r.0))
+        });
+        ranked.truncate(limit);
+        let mut hits = Vec::with_capacity(ranked.len());
+        for (path, score) in ranked {
+            let key = fit_key(format!("d\0{}{}", self.root_url, path)).0;
+            let doc: Document = match get_json(&txn, tables.search_db, &key)? {
+                Some(doc) => doc,
+                None => continue,
+            };
+            hits.push(search::Hit {
+                snippet: search::snippet(&doc.text, &terms),
+                title: doc.title,
+                path: format!("/{}", path.trim_start_matches('/')),
+                score: score,
+            });
+        }
+        Ok(hits)
+    }
+
+    /// Rewrite the site's sitemap and robots.txt in the generated table,
+    /// from the metadata table. The sitemap lists every html page, and is
+    /// only generated if the site's url is set, since it needs absolute
+    /// urls. Returns the keys of the files written.
+    fn regenerate_sitemap(&self, txn: &mut lmdb::RwTransaction) -> Result<Vec<String>, Error> {
+        let tables = self.tables();
+        let settings: Settings = get_json(&*txn, tables.settings_db, &self.root_url)?.unwrap_or_default();
+        let sitemap_key = self.root_url.clone() + sitemap::SITEMAP_PATH;
+        let robots_key = self.root_url.clone() + sitemap::ROBOTS_PATH;
+        if settings.site_url == "" {
+            match txn.del(tables.generated_db, &sitemap_key, None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => (),
+                Err(e) => return Err(db_err(e)),
+            }
+        } else {
+            let mut pages = vec![];
+            let root = self.root_url.as_bytes();
+            scan(&*txn, tables.meta_db, root, |key, value| -> Result<bool, Error> {
+                if !key.starts_with(root) {
+                    return Ok(false)
+                }
+                let meta: PageMeta = serde_json::from_slice(value).map_err(|e| {
+                    Error::failed(format!("Corrupt page metadata: {}", e))
+                })?;
+                pages.push((String::from(std::str::from_utf8(&key[root.len()..])?), meta.modified));
+                Ok(true)
+            })?;
+            let body = sitemap::sitemap(
+                &settings.site_url,
+                pages.iter().map(|(path, modified)| (&path[..], &modified[..])),
+            );
+            let buffer = single_entity("application/xml", body.as_bytes())?;
+            txn.put(tables.generated_db, &sitemap_key, &buffer, lmdb::WriteFlags::empty()).map_err(db_err)?;
+        }
+        let buffer = single_entity("text/plain", sitemap::robots(&settings.site_url).as_bytes())?;
+        txn.put(tables.generated_db, &robots_key, &buffer, lmdb::WriteFlags::empty()).map_err(db_err)?;
+        Ok(vec![sitemap_key, robots_key])
+    }
+
+    /// Regenerate the sitemap shortly, unless that is already scheduled.
+    /// Uploads set many paths one after another, and the sitemap covers all
+    /// of them, so this rewrites it once for the lot rather than once for
+    /// each path. Outside of a runtime, the sitemap is regenerated now.
+    ///
+    /// Nobody waits on a deferred regeneration, so if it fails, the next
+    /// write retries it and reports the error; see `sitemap_failed`.
+    fn regenerate_sitemap_soon(&self) -> Result<(), Error> {
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return self.regenerate_sitemap_now(),
+        };
+        if self.sitemap_pending.swap(true, Ordering::SeqCst) {
+            return Ok(())
+        }
+        let site = self.clone();
+        runtime.spawn(async move {
+            tokio::time::delay_for(SITEMAP_DELAY).await;
+            site.sitemap_pending.store(false, Ordering::SeqCst);
+            let _ = tokio::task::spawn_blocking(move || {
+                if site.regenerate_sitemap_now().is_err() {
+                    site.sitemap_failed.store(true, Ordering::SeqCst);
+                }
+            }).await;
+        });
+        Ok(())
+    }
+
+    /// Regenerate the sitemap in a transaction of its own, and tell the
+    /// observers.
+    fn regenerate_sitemap_now(&self) -> Result<(), Error> {
+        let (changed, version) = self.write_txn(|txn| {
+            let changed = self.regenerate_sitemap(txn)?;
+            Ok((changed, txn_version(txn)))
+        })?;
+        for key in changed.iter() {
+            self.observers.notify(key, version);
+        }
+        Ok(())
+    }
+
+    /// If this site's url is that of a `_redirects` file, get the key its
+    /// rules are stored under in the redirects table.
+    fn redirects_key(&self) -> Option<String> {
+        let path = &self.url[self.root_url.len()..];
+        let dir = if path == redirects::FILE_NAME {
+            ""
+        } else if path.ends_with(&format!("/{}", redirects::FILE_NAME)) {
+            &path[..path.len() - redirects::FILE_NAME.len()]
+        } else {
+            return None
+        };
+        Some(self.root_url.clone() + dir)
+    }
+
+    /// Bring the redirects table up to date with `value`, the new contents
+    /// of this path, if it is a `_redirects` file.
+    fn update_redirects(&self,
+                        txn: &mut lmdb::RwTransaction,
+                        value: entity_list::Reader) -> Result<(), Error> {
+        let tables = self.tables();
+        let key = match self.redirects_key() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let mut text = None;
+        for entity in value.iter() {
+            if let web_site::entity::body::Bytes(bytes) = entity.get_body().which()? {
+                text = Some(std::str::from_utf8(bytes?)?);
+                break
+            }
+        }
+        match text {
+            None => match txn.del(tables.redirects_db, &key, None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+                Err(e) => Err(db_err(e)),
+            },
+            Some(text) => {
+                let rules = redirects::Rules::parse(text).map_err(|e| {
+                    Error::failed(format!("Invalid {}: {}", redirects::FILE_NAME, e))
+                })?;
+                put_json(txn, tables.redirects_db, &key, &rules)
+            },
+        }
+    }
+
+    /// Find the links on the site's pages and stylesheets which lead
+    /// nowhere, in order.
+    pub fn broken_links(&self) -> Result<Vec<BrokenLink>, Error> {
+        let tables = self.tables();
+        let txn = tables.begin_ro_txn()?;
+        self.find_broken_links(&txn)
+    }
+
+    /// Find the broken links in the site, as of `txn`. A link is broken if
+    /// the server would answer it with a 404, after following redirects.
+    fn find_broken_links<T: Transaction>(&self, txn: &T) -> Result<Vec<BrokenLink>, Error> {
+        let tables = self.tables();
+        let settings: Settings = get_json(txn, tables.settings_db, &self.root_url)?.unwrap_or_default();
+        // The redirect rules of each directory which has any.
+        let mut rules: HashMap<String, redirects::Rules> = HashMap::new();
+        let root = self.root_url.as_bytes();
+        scan(txn, tables.redirects_db, root, |key, value| -> Result<bool, Error> {
+            if !key.starts_with(root) {
+                return Ok(false)
+            }
+            let dir = String::from(std::str::from_utf8(&key[root.len()..])?);
+            let dir_rules = serde_json::from_slice(value).map_err(|e| {
+                Error::failed(format!("Corrupt record for {}: {}", dir, e))
+            })?;
+            rules.insert(dir, dir_rules);
+            Ok(true)
+        })?;
+
+        // Every path in the site, with the path it redirects to if it is a
+        // redirect.
+        let mut stored: HashMap<String, Option<String>> = HashMap::new();
+        let mut found = vec![];
+        scan(txn, tables.db, root, |key, value| -> Result<bool, Error> {
+            if !key.starts_with(root) {
+                return Ok(false)
+            }
+            let path = String::from(std::str::from_utf8(&key[root.len()..])?);
+            let msg = decode_record(txn, tables.blobs, value)?;
+            let entities: entity_list::Reader = msg.get_root_as_reader()?;
+            let mut redirect_to = None;
+            for entity in entities.iter() {
+                if entity.has_redirect_to() {
+                    redirect_to = Some(String::from(entity.get_redirect_to()?));
+                    continue
+                }
+                let bytes = match entity.get_body().which()? {
+                    web_site::entity::body::Bytes(bytes) => bytes?,
+                    web_site::entity::body::Blob(_) => continue,
+                };
+                let mime_type = entity.get_mime_type()?;
+                let page_links = if mime_type.starts_with("text/html") {
+                    links::html_links(&String::from_utf8_lossy(bytes))
+                } else if mime_type.starts_with("text/css") {
+                    links::css_links(&String::from_utf8_lossy(bytes))
+                } else {
+                    continue
+                };
+                found.extend(page_links.into_iter().map(|link| (path.clone(), link)));
+            }
+            stored.insert(path, redirect_to);
+            Ok(true)
+        })?;
+        scan(txn, tables.generated_db, root, |key, _| -> Result<bool, Error> {
+            if !key.starts_with(root) {
+                return Ok(false)
+            }
+            stored.entry(String::from(std::str::from_utf8(&key[root.len()..])?)).or_insert(None);
+            Ok(true)
+        })?;
+
+        let exists = |path: String| -> bool {
+            let mut path = path;
+            for _ in 0..MAX_REDIRECTS {
+                if path == search::SEARCH_PATH {
+                    return true
+                }
+                let rule = redirects::enclosing_dirs(&path).find_map(|dir| {
+                    rules.get(dir).and_then(|rules| rules.resolve_in(dir, &path))
+                });
+                if let Some(rule) = rule {
+                    if rule.status == 404 {
+                        return false
+                    }
+                    match links::resolve("", &rule.to) {
+                        Some(to) => path = to,
+                        // Redirects off the site are taken to work.
+                        None => return true,
+                    }
+                    continue
+                }
+                let here = std::iter::once(path.clone())
+                    .chain(url_policy::alternatives(&path))
+                    .find_map(|path| stored.get(&path));
+                match here {
+                    Some(Some(target)) => match links::resolve("", target) {
+                        // The root of the site is stored at "/", and ""
+                        // redirects there. Clients can't tell the two
+                        // apart, so the session serves the target
+                        // directly rather than following the redirect.
+                        Some(_) if web_site_session::same_url(&path, target) => path = target.clone(),
+                        Some(target) => path = target,
+                        None => return true,
+                    },
+                    Some(None) => return true,
+                    None => {
+                        let spa = match settings.fallback {
+                            Fallback::Spa { .. } => true,
+                            _ => false,
+                        };
+                        return spa && web_site_session::is_page(&path)
+                    },
+                }
+            }
+            false
+        };
+        let mut broken: Vec<BrokenLink> = found.into_iter().filter_map(|(page, link)| {
+            let target = links::resolve(&page, &link)?;
+            if exists(target) {
+                None
+            } else {
+                Some(BrokenLink {
+                    page: page,
+                    link: link,
+                })
+            }
+        }).collect();
+        broken.sort();
+        broken.dedup();
+        Ok(broken)
+    }
+
+    /// Publish the output of a build: store the entities at each of
+    /// `paths` in one transaction, so readers see all of the changes or
+    /// none of them, and delete whatever the site's previous build
+    /// published which this one doesn't. Returns the new version of the
+    /// site.
+    ///
+    /// If `check_links` is set, nothing is stored if the change would
+    /// break any links which weren't already broken.
+    ///
+    /// This is for tools which work on a site's database directly, like
+    /// the `build` command; observers are not notified, since they live in
+    /// the grain's own process.
+    pub fn publish_build<'a, I>(&self, paths: I, check_links: bool) -> Result<u64, Error>
+        where I: IntoIterator<Item = (&'a str, entity_list::Reader<'a>)>
+    {
+        // We may need to go through these more than once, if the map
+        // fills up.
+        let paths: Vec<_> = paths.into_iter().collect();
+        let mut nothing = capnp::message::Builder::new_default();
+        nothing.initn_root::<entity_list::Builder>(0);
+        let nothing: entity_list::Reader = nothing.get_root_as_reader()?;
+        self.write_txn(|txn| {
+            let tables = self.tables();
+            let broken_before = if check_links {
+                Some(self.find_broken_links(&*txn)?)
+            } else {
+                None
+            };
+            let published: BTreeSet<&str> = paths.iter().map(|&(path, _)| path).collect();
+            let previous: Vec<String> = get_json(&*txn, tables.builds_db, &self.root_url)?.unwrap_or_default();
+            for path in previous.iter().filter(|path| !published.contains(&path[..])) {
+                let mut site = self.clone();
+                site.url += path;
+                site.write(txn, nothing)?;
+            }
+            for &(path, value) in paths.iter() {
+                let mut site = self.clone();
+                site.url += path;
+                site.write(txn, value)?;
+            }
+            put_json(txn, tables.builds_db, &self.root_url, &published)?;
+            self.regenerate_sitemap(txn)?;
+            if let Some(broken_before) = broken_before {
+                let newly_broken: Vec<String> = self.find_broken_links(&*txn)?.into_iter()
+                    .filter(|link| !broken_before.contains(link))
+                    .map(|link| format!("\n  {}: {}", link.page_url(), link.link))
+                    .collect();
+                if !newly_broken.is_empty() {
+                    // The transaction is aborted.
+                    return Err(Error::failed(format!(
+                        "Not publishing, since this would break {} links:{}",
+                        newly_broken.len(),
+                        newly_broken.concat(),
+                    )))
+                }
+            }
+            Ok(txn_version(txn))
+        })
+    }
+
+    /// Replace the entities at this site's url with `value`, as part of
+    /// `txn`. Returns the keys of everything that changed as a result,
+    /// which includes any feeds this page is in.
+    ///
+    /// The sitemap isn't regenerated here, since it covers the whole site;
+    /// callers should do so once per batch of writes.
+    fn write(&self, txn: &mut lmdb::RwTransaction, value: entity_list::Reader) -> Result<Vec<String>, Error> {
+        let tables = self.tables();
+        let old = match txn.get(tables.db, &self.url) {
+            Ok(old) => Some(Vec::from(old)),
+            Err(lmdb::Error::NotFound) => None,
+            Err(e) => return Err(db_err(e)),
+        };
+        let mut changed = vec![self.url.clone()];
+        if value.len() == 0 {
+            // There may be nothing here, e.g. if a build is deleting a page
+            // which was since deleted some other way.
+            match txn.del(tables.db, &self.url, None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => (),
+                Err(e) => return Err(db_err(e)),
+            }
+        } else {
+            let buffer = encode_record(value)?;
+            if old.as_ref().map_or(false, |old| old[..] == buffer[..]) {
+                // Nothing to do, and the page keeps its modification time.
+                return Ok(changed)
+            }
+            // Take the new references before dropping the old ones, so
+            // bodies which are in both aren't deleted in between.
+            for entity in value.iter() {
+                if let web_site::entity::body::Bytes(bytes) = entity.get_body().which()? {
+                    let bytes = bytes?;
+                    if !bytes.is_empty() {
+                        tables.blobs.acquire(txn, bytes).map_err(db_err)?;
+                    }
+                }
+            }
+            txn.put(tables.db, &self.url, &buffer, lmdb::WriteFlags::empty()).map_err(db_err)?;
+        }
+        if let Some(old) = old {
+            for hash in record_hashes(&old)? {
+                tables.blobs.release(txn, &hash).map_err(db_err)?;
+            }
+        }
+        self.update_redirects(txn, value)?;
+        self.update_search(txn, value)?;
+        if self.update_meta(txn, value)? {
+            changed.extend(self.regenerate_feeds(txn)?);
+        }
+        Ok(changed)
+    }
+
+    /// Call `f` with the entities stored at this site's url, or `None` if
+    /// there are none.
+    fn with_entities<T, F>(&self, f: F) -> Result<T, Error>
+        where F: FnOnce(Option<entity_list::Reader>) -> Result<T, Error>
+    {
+        match self.tables().entities(&self.url)? {
+            Some(msg) => f(Some(msg.get_root_as_reader()?)),
+            None => f(None),
+        }
+    }
+
+    /// Like `with_entities()`, but the entities are read on the blocking
+    /// thread pool, so big bodies don't hold up the event loop.
+    async fn with_entities_blocking<T, F>(&self, f: F) -> Result<T, Error>
+        where F: FnOnce(Option<entity_list::Reader>) -> Result<T, Error>
+    {
+        let tables = self.tables();
+        let url = self.url.clone();
+        // Builders aren't Send, so the entities come back serialized.
+        let stored = tokio::task::spawn_blocking(move || -> Result<Option<Vec<u8>>, Error> {
+            match tables.entities(&url)? {
+                Some(msg) => {
+                    let mut buffer = vec![];
+                    capnp::serialize::write_message(&mut buffer, &msg)?;
+                    Ok(Some(buffer))
+                },
+                None => Ok(None),
+            }
+        }).await.map_err(|e| Error::failed(format!("Reading entities failed: {}", e)))??;
+        match stored {
+            Some(buffer) => {
+                let msg = capnp::serialize::read_message_from_flat_slice(
+                    &mut &buffer[..],
+                    Default::default(),
+                )?;
+                f(Some(msg.get_root()?))
+            },
+            None => f(None),
+        }
+    }
+
+    /// The names `listResources()` returns: every path in this site, or if
+    /// `shallow`, just its direct children, with everything under a
+    /// directory collapsed into "dir/".
+    fn resource_names(&self, shallow: bool) -> Result<Vec<String>, Error> {
+        let mut names: Vec<String> = vec![];
+        let mut start = None;
+        loop {
+            let listing = self.list("", start.as_deref(), LIST_RESOURCES_BATCH)?;
+            for item in listing.paths {
+                let name = match item.path.find('/') {
+                    Some(i) if shallow => String::from(&item.path[..i + 1]),
+                    _ => item.path,
+                };
+                // Paths are in order, so everything under a directory is
+                // together.
+                if names.last() != Some(&name) {
+                    names.push(name);
+                }
+            }
+            match listing.next {
+                Some(next) => start = Some(next),
+                None => return Ok(names),
+            }
+        }
+    }
+
+    /// Read the entities at `path`, and throw them away: on the blocking
+    /// thread pool if `blocking`, and otherwise on this thread. For
+    /// `bench::reads()`.
+    pub(crate) async fn read_entities(&self, path: &str, blocking: bool) -> Result<(), Error> {
+        let mut site = self.clone();
+        site.url += path;
+        if blocking {
+            site.with_entities_blocking(|_| Ok(())).await
+        } else {
+            site.with_entities(|_| Ok(()))
+        }
+    }
 }
 
 impl web_site::Server for LMDBWebSite {
@@ -60,13 +1781,42 @@ impl web_site::Server for LMDBWebSite {
         })
     }
 
+    fn list_resources(&mut self,
+                      params: web_site::ListResourcesParams,
+                      mut results: web_site::ListResourcesResults) -> Promise<(), Error> {
+        let shallow = pry!(params.get()).get_shallow();
+        let site = self.clone();
+        Promise::from_future(async move {
+            let names = tokio::task::spawn_blocking(move || site.resource_names(shallow))
+                .await
+                .map_err(|e| Error::failed(format!("Listing the site failed: {}", e)))??;
+            let mut list = results.get().init_names(names.len() as u32);
+            for (i, name) in names.iter().enumerate() {
+                list.set(i as u32, name);
+            }
+            Ok(())
+        })
+    }
+
     fn get_entities(&mut self,
                     params: web_site::GetEntitiesParams,
                     mut results: web_site::GetEntitiesResults) -> Promise<(), Error> {
         let mut site = self.clone();
         Promise::from_future(async move {
-            site.url += params.get()?.get_path()?;
-            results.get().set_entities(capnp_rpc::new_client(EntitiesCell(Rc::new(site))));
+            let path = params.get()?.get_path()?;
+            let (listing_path, query) = match path.find('?') {
+                Some(i) => (&path[..i], &path[i + 1..]),
+                None => (path, ""),
+            };
+            if listing_path == LISTING_PATH {
+                results.get().set_entities(capnp_rpc::new_client(ListingCell {
+                    site: site,
+                    query: String::from(query),
+                }));
+                return Ok(())
+            }
+            site.url += path;
+            results.get().set_entities(capnp_rpc::new_client(EntitiesCell(site)));
             Ok(())
         })
     }
@@ -95,27 +1845,30 @@ impl assignable::setter::Server<entity_list::Owned> for EntitiesCell {
         let entities = self.clone();
         Promise::from_future(async move {
             let value = params.get()?.get_value()?;
-            if value.len() == 0 {
-                let site = &*entities.0;
-                let env = &*site.env;
-                let db = *site.db;
-                let mut txn = env.begin_rw_txn().map_err(db_err)?;
-                txn.del(db, &site.url, None).map_err(db_err)?;
-                txn.commit().map_err(db_err)?
-            } else {
-                let mut msg = capnp::message::Builder::new_default();
-                msg.set_root(value)?;
-                let mut buffer = vec![];
-                capnp::serialize::write_message(&mut buffer, &msg)?;
-
-                let site = &*entities.0;
-                let env = &*site.env;
-                let db = *site.db;
-                let mut txn = env.begin_rw_txn().map_err(db_err)?;
-                txn.put(db, &site.url, &buffer, lmdb::WriteFlags::empty()).map_err(db_err)?;
-                txn.commit().map_err(db_err)?
+            let site = &entities.0;
+            let (changed, version) = site.write_txn(|txn| {
+                let changed = site.write(txn, value)?;
+                Ok((changed, txn_version(txn)))
+            })?;
+            for key in changed.iter() {
+                site.observers.notify(key, version);
             }
-            Ok(())
+            let sitemap = if site.sitemap_failed.swap(false, Ordering::SeqCst) {
+                // The last regeneration failed with nobody to tell, so
+                // retry it now, where this writer will hear if it fails
+                // again.
+                site.regenerate_sitemap_now().map_err(|e| {
+                    site.sitemap_failed.store(true, Ordering::SeqCst);
+                    e
+                })
+            } else {
+                site.regenerate_sitemap_soon()
+            };
+            sitemap.map_err(|e| Error {
+                kind: e.kind,
+                description: format!("The change was stored, but regenerating the sitemap failed: {}",
+                                     e.description),
+            })
         })
     }
 }
@@ -126,28 +1879,397 @@ impl assignable::getter::Server<entity_list::Owned> for EntitiesCell {
            mut results: assignable::getter::GetResults<entity_list::Owned>) -> Promise<(), Error> {
         let entities = self.clone();
         Promise::from_future(async move {
-            let site = &*entities.0;
-            let env = &*site.env;
-            let db = *site.db;
-            let txn = env.begin_ro_txn().map_err(db_err)?;
-            let mut bytes: &[u8] = match txn.get(db, &site.url) {
-                Ok(res) => res,
-                Err(lmdb::Error::NotFound) => {
-                    // Just return null
-                    return Ok(())
-                },
-                Err(e) => {
-                    return Err(db_err(e))
+            entities.0.with_entities_blocking(|value| {
+                if let Some(src_list) = value {
+                    results.get().set_value(src_list)?;
                 }
-            };
-            let msg =
-                capnp::serialize::read_message_from_flat_slice(
-                    &mut bytes,
-                    Default::default(),
-                )?;
-            let src_list: entity_list::Reader = msg.get_root()?;
-            results.get().set_value(src_list)?;
+                Ok(())
+            }).await
+        })
+    }
+
+    fn subscribe(&mut self,
+                 params: assignable::getter::SubscribeParams<entity_list::Owned>,
+                 mut results: assignable::getter::SubscribeResults<entity_list::Owned>) -> Promise<(), Error> {
+        let setter = pry!(pry!(params.get()).get_setter());
+        let site = &self.0;
+        let subscription = site.observers.subscribe(
+            site.url.clone(),
+            notify::Target::Path(String::new()),
+            Box::new(SetterObserver {
+                entities: self.clone(),
+                setter: setter,
+            }),
+        );
+        results.get().set_handle(capnp_rpc::new_client(subscription));
+        Promise::ok(())
+    }
+}
+
+impl ListingCell {
+    /// The page of the listing asked for, as JSON.
+    fn render(&self) -> Result<String, Error> {
+        let prefix = search::query_param(&self.query, "prefix").unwrap_or_default();
+        let start = search::query_param(&self.query, "start");
+        let limit = search::query_param(&self.query, "limit")
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(LIST_RESOURCES_BATCH)
+            .min(LIST_RESOURCES_BATCH);
+        let listing = self.site.list(&prefix, start.as_deref(), limit)?;
+        serde_json::to_string(&listing).map_err(|e| Error::failed(e.to_string()))
+    }
+}
+
+impl assignable::Server<entity_list::Owned> for ListingCell {
+    fn as_getter(&mut self,
+                 _params: assignable::AsGetterParams<entity_list::Owned>,
+                 mut results: assignable::AsGetterResults<entity_list::Owned>) -> Promise<(), Error> {
+        results.get().set_getter(capnp_rpc::new_client(self.clone()));
+        Promise::ok(())
+    }
+
+    fn as_setter(&mut self,
+                 _params: assignable::AsSetterParams<entity_list::Owned>,
+                 _results: assignable::AsSetterResults<entity_list::Owned>) -> Promise<(), Error> {
+        Promise::err(Error::failed(format!("{} is read-only", LISTING_PATH)))
+    }
+}
+
+impl assignable::getter::Server<entity_list::Owned> for ListingCell {
+    fn get(&mut self,
+           _params: assignable::getter::GetParams<entity_list::Owned>,
+           mut results: assignable::getter::GetResults<entity_list::Owned>) -> Promise<(), Error> {
+        let cell = self.clone();
+        Promise::from_future(async move {
+            let json = tokio::task::spawn_blocking(move || cell.render())
+                .await
+                .map_err(|e| Error::failed(format!("Listing the site failed: {}", e)))??;
+            let mut entity = results.get().initn_value(1).get(0);
+            entity.set_mime_type("application/json");
+            entity.get_body().set_bytes(json.as_bytes());
+            Ok(())
+        })
+    }
+}
+
+/// Forwards changes to a path on to a setter, as requested via
+/// `Getter.subscribe()`.
+struct SetterObserver {
+    entities: EntitiesCell,
+    setter: assignable::setter::Client<entity_list::Owned>,
+}
+
+impl notify::Observer for SetterObserver {
+    fn changed(&mut self, _change: &notify::Change) -> Promise<(), Error> {
+        let mut req = self.setter.set_request();
+        pry!(self.entities.0.with_entities(|value| {
+            if let Some(src_list) = value {
+                req.get().set_value(src_list)?;
+            }
+            Ok(())
+        }));
+        Promise::from_future(async move {
+            req.send().promise.await?;
             Ok(())
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::site_settings::FeedSettings;
+
+    /// A directory to put a database in, removed again when dropped.
+    struct TempDir(path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("webpub-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(dir: &TempDir) -> Result<Tables, Error> {
+        let lock = fs::File::create(dir.0.join("test.lock")).unwrap();
+        Tables::open(&dir.0, MapSize::default(), lock)
+    }
+
+    fn get(tables: &Tables, db: lmdb::Database, key: &str) -> Option<Vec<u8>> {
+        let txn = tables.begin_ro_txn().unwrap();
+        match txn.get(db, &key) {
+            Ok(value) => Some(value.to_vec()),
+            Err(lmdb::Error::NotFound) => None,
+            Err(e) => panic!("reading {}: {}", key, e),
+        }
+    }
+
+    #[test]
+    fn handles_can_be_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LMDBWebSite>();
+        assert_send_sync::<notify::Observers>();
+    }
+
+    #[test]
+    fn sitemap_is_regenerated_at_once_outside_a_runtime() {
+        let dir = TempDir::new("sitemap");
+        let db = dir.0.join("site");
+        fs::create_dir(&db).unwrap();
+        let site = LMDBWebSite::open(String::from("http://example.com/"), &db, MapSize::default()).unwrap();
+        site.regenerate_sitemap_soon().unwrap();
+        let tables = site.tables();
+        let robots = String::from("http://example.com/") + sitemap::ROBOTS_PATH;
+        assert!(get(&tables, tables.generated_db, &robots).is_some());
+        assert!(!site.sitemap_pending.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn claims_are_refused_while_another_process_has_the_site_open() {
+        let dir = TempDir::new("claim");
+        let db = dir.0.join("site");
+        fs::create_dir(&db).unwrap();
+        let site = LMDBWebSite::open(String::from("http://example.com/"), &db, MapSize::default()).unwrap();
+        // flock() locks belong to open files, so another open of the lock
+        // file stands in for another process.
+        let other = fs::File::open(sibling(&db, ".lock")).unwrap();
+        flock(&other, libc::LOCK_SH).unwrap();
+        assert!(site.claim().is_err());
+        site.release().unwrap();
+        drop(other);
+
+        site.claim().unwrap();
+        let other = fs::File::open(sibling(&db, ".lock")).unwrap();
+        assert!(flock(&other, libc::LOCK_SH | libc::LOCK_NB).is_err());
+        site.release().unwrap();
+        flock(&other, libc::LOCK_SH | libc::LOCK_NB).unwrap();
+    }
+
+    #[test]
+    fn long_keys_are_shortened_uniquely() {
+        let short = String::from("t\0http://example.com/\0word\0index.html");
+        assert_eq!(fit_key(short.clone()), (short, false));
+        let long = |end: &str| format!("t\0http://example.com/\0word\0{}{}", "é".repeat(300), end);
+        let (a, shortened) = fit_key(long("a"));
+        assert!(shortened);
+        assert!(a.len() <= MAX_KEY_SIZE);
+        assert!(a.starts_with("t\0http://example.com/\0word\0é"));
+        assert_ne!(a, fit_key(long("b")).0);
+    }
+
+    #[test]
+    fn pages_with_long_paths_can_be_searched() {
+        let dir = TempDir::new("search");
+        let db = dir.0.join("site");
+        fs::create_dir(&db).unwrap();
+        let site = LMDBWebSite::open(String::from("http://example.com/"), &db, MapSize::default()).unwrap();
+        let path = format!("{}index.html", "long/".repeat(95));
+        let mut page = site.clone();
+        page.url += &path;
+        let buffer = single_entity("text/html", b"<title>Deep</title><p>Hello there</p>").unwrap();
+        let msg = capnp::serialize::read_message_from_flat_slice(&mut &buffer[..], Default::default()).unwrap();
+        page.write_txn(|txn| page.update_search(txn, msg.get_root()?)).unwrap();
+
+        let hits = site.search("hello", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].path, format!("/{}", path));
+        assert_eq!(hits[0].title, "Deep");
+    }
+
+    #[test]
+    fn listings_are_paged_and_confined_to_subsites() {
+        let dir = TempDir::new("listing");
+        let db = dir.0.join("site");
+        fs::create_dir(&db).unwrap();
+        let site = LMDBWebSite::open(String::from("http://example.com/"), &db, MapSize::default()).unwrap();
+        put_page(&site, "a.html", b"a");
+        put_page(&site, "b/c.html", b"c");
+        put_page(&site, "b/d.html", b"d");
+        assert_eq!(site.resource_names(false).unwrap(), vec!["a.html", "b/c.html", "b/d.html"]);
+        assert_eq!(site.resource_names(true).unwrap(), vec!["a.html", "b/"]);
+
+        let render = |site: &LMDBWebSite, query: &str| -> serde_json::Value {
+            let cell = ListingCell {
+                site: site.clone(),
+                query: String::from(query),
+            };
+            serde_json::from_str(&cell.render().unwrap()).unwrap()
+        };
+        let page = render(&site, "limit=2");
+        assert_eq!(page["paths"][0]["path"], "a.html");
+        assert_eq!(page["paths"][0]["entities"][0]["mimeType"], "text/html");
+        assert_eq!(page["paths"][1]["path"], "b/c.html");
+        assert_eq!(page["next"], "b/d.html");
+
+        let mut subsite = site.clone();
+        subsite.url += "b/";
+        let page = render(&subsite, "start=d.html");
+        assert_eq!(page["paths"].as_array().unwrap().len(), 1);
+        assert_eq!(page["paths"][0]["path"], "d.html");
+        assert!(page["next"].is_null());
+        assert_eq!(render(&site, "prefix=b/&limit=1")["paths"][0]["path"], "b/c.html");
+    }
+
+    #[test]
+    fn unversioned_databases_are_migrated() {
+        let dir = TempDir::new("migrate");
+        let site = "http://example.com/";
+        let url = |path: &str| String::from(site) + path;
+        // Lay the database out as versions before the schema was versioned
+        // did, with everything in the unnamed database.
+        {
+            let env = lmdb::Environment::new().set_max_dbs(MAX_DBS).open(&dir.0).unwrap();
+            let main_db = env.open_db(None).unwrap();
+            let settings_db = env.create_db(Some(SETTINGS_DB_NAME), lmdb::DatabaseFlags::empty()).unwrap();
+            let settings = Settings {
+                feed: FeedSettings {
+                    atom_path: String::from("/feed.atom"),
+                    rss_path: String::from("/feed.rss"),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut stored_rss = Vec::from(BLOB_RECORD_MAGIC);
+            stored_rss.extend_from_slice(b"rss");
+            let mut txn = env.begin_rw_txn().unwrap();
+            let mut put = |db, key: &str, value: &[u8]| {
+                txn.put(db, &key, &value, lmdb::WriteFlags::empty()).unwrap()
+            };
+            put(settings_db, site, &serde_json::to_vec(&settings).unwrap());
+            put(main_db, &url("page"), b"page");
+            put(main_db, &url("feed.atom"), b"atom");
+            // Stored by the user, so it isn't a feed we generated.
+            put(main_db, &url("feed.rss"), &stored_rss);
+            txn.commit().unwrap();
+        }
+
+        let tables = open(&dir).unwrap();
+        assert_eq!(get(&tables, tables.main_db, &url("page")), None);
+        assert_eq!(get(&tables, tables.db, &url("page")), Some(b"page".to_vec()));
+        assert_eq!(get(&tables, tables.db, &url("feed.atom")), None);
+        assert_eq!(get(&tables, tables.generated_db, &url("feed.atom")), Some(b"atom".to_vec()));
+        assert!(get(&tables, tables.db, &url("feed.rss")).is_some());
+        assert_eq!(get(&tables, tables.generated_db, &url("feed.rss")), None);
+        assert_eq!(
+            get(&tables, tables.schema_db, SCHEMA_VERSION_KEY),
+            Some(SCHEMA_VERSION.to_le_bytes().to_vec()),
+        );
+
+        // Opening it again finds nothing left to do.
+        drop(tables);
+        let tables = open(&dir).unwrap();
+        assert_eq!(get(&tables, tables.db, &url("page")), Some(b"page".to_vec()));
+    }
+
+    #[test]
+    fn newer_schema_versions_are_refused() {
+        let dir = TempDir::new("newer-schema");
+        let tables = open(&dir).unwrap();
+        tables.write_txn(|txn| {
+            txn.put(
+                tables.schema_db,
+                &SCHEMA_VERSION_KEY,
+                &(SCHEMA_VERSION + 1).to_le_bytes(),
+                lmdb::WriteFlags::empty(),
+            ).map_err(db_err)
+        }).unwrap();
+        drop(tables);
+        assert!(open(&dir).is_err());
+    }
+
+    /// Store a file of type `mime_type` with `body` at `path` in `site`.
+    fn put(site: &LMDBWebSite, path: &str, mime_type: &str, body: &[u8]) {
+        let mut page = site.clone();
+        page.url += path;
+        let buffer = single_entity(mime_type, body).unwrap();
+        let msg = capnp::serialize::read_message_from_flat_slice(&mut &buffer[..], Default::default()).unwrap();
+        page.write_txn(|txn| page.write(txn, msg.get_root()?)).unwrap();
+    }
+
+    /// Store an html page with `body` at `path` in `site`.
+    fn put_page(site: &LMDBWebSite, path: &str, body: &[u8]) {
+        put(site, path, "text/html", body)
+    }
+
+    /// The body of the first entity stored or generated at `path`.
+    fn body(site: &LMDBWebSite, path: &str) -> Option<Vec<u8>> {
+        let msg = site.tables().entities(&(site.url.clone() + path)).unwrap()?;
+        let entities: entity_list::Reader = msg.get_root_as_reader().unwrap();
+        match entities.get(0).get_body().which().unwrap() {
+            web_site::entity::body::Bytes(bytes) => Some(bytes.unwrap().to_vec()),
+            web_site::entity::body::Blob(_) => None,
+        }
+    }
+
+    #[test]
+    fn feeds_are_regenerated_when_dated_pages_change() {
+        let dir = TempDir::new("feeds");
+        let db = dir.0.join("site");
+        fs::create_dir(&db).unwrap();
+        let site = LMDBWebSite::open(String::from("http://example.com/"), &db, MapSize::default()).unwrap();
+        site.set_settings(&Settings {
+            site_url: String::from("https://example.com"),
+            feed: FeedSettings {
+                title: String::from("News"),
+                atom_path: String::from("/feed.atom"),
+                rss_path: String::from("feed.rss"),
+            },
+            ..Default::default()
+        }).unwrap();
+        let feed = |path: &str| String::from_utf8(body(&site, path).unwrap()).unwrap();
+        assert!(feed("feed.atom").contains("<title>News</title>"));
+        assert!(!feed("feed.atom").contains("<entry>"));
+
+        put_page(&site, "posts/1.html", b"<title>First</title><meta name=\"date\" content=\"2020-01-02\">");
+        put_page(&site, "about.html", b"<title>About</title>");
+        let atom = feed("feed.atom");
+        assert!(atom.contains("<title>First</title>"));
+        assert!(atom.contains("<id>https://example.com/posts/1.html</id>"));
+        assert!(!atom.contains("About"));
+        assert!(feed("feed.rss").contains("<pubDate>Thu, 2 Jan 2020 00:00:00 +0000</pubDate>"));
+
+        // Losing its date takes a page out of the feeds.
+        put_page(&site, "posts/1.html", b"<title>First</title>");
+        assert!(!feed("feed.atom").contains("First"));
+        assert!(!feed("feed.rss").contains("First"));
+    }
+
+    #[test]
+    fn sitemaps_list_pages_unless_the_site_has_its_own() {
+        let dir = TempDir::new("sitemap-pages");
+        let db = dir.0.join("site");
+        fs::create_dir(&db).unwrap();
+        let site = LMDBWebSite::open(String::from("http://example.com/"), &db, MapSize::default()).unwrap();
+        put_page(&site, "index.html", b"<title>Home</title>");
+        put_page(&site, "docs/a.html", b"<title>A</title>");
+        put(&site, "style.css", "text/css", b"p {}");
+        site.write_txn(|txn| site.regenerate_sitemap(txn)).unwrap();
+        // Without the site's url, there's no sitemap to point at.
+        assert_eq!(body(&site, sitemap::SITEMAP_PATH), None);
+        assert_eq!(body(&site, sitemap::ROBOTS_PATH), Some(b"User-agent: *\nAllow: /\n".to_vec()));
+
+        site.set_settings(&Settings {
+            site_url: String::from("https://example.com"),
+            ..Default::default()
+        }).unwrap();
+        let urls = String::from_utf8(body(&site, sitemap::SITEMAP_PATH).unwrap()).unwrap();
+        assert!(urls.contains("<loc>https://example.com/docs/a.html</loc>"));
+        assert!(urls.contains("<loc>https://example.com/index.html</loc>"));
+        assert!(!urls.contains("style.css"));
+        assert_eq!(urls.matches("<lastmod>").count(), 2);
+        let robots = String::from_utf8(body(&site, sitemap::ROBOTS_PATH).unwrap()).unwrap();
+        assert!(robots.ends_with("\nSitemap: https://example.com/sitemap.xml\n"));
+
+        put(&site, sitemap::ROBOTS_PATH, "text/plain", b"User-agent: *\nDisallow: /\n");
+        site.write_txn(|txn| site.regenerate_sitemap(txn)).unwrap();
+        assert_eq!(body(&site, sitemap::ROBOTS_PATH), Some(b"User-agent: *\nDisallow: /\n".to_vec()));
+    }
+}
@@ -12,9 +12,18 @@ use sandstorm::{
         sandstorm_api,
     },
     sandstorm_http_bridge_capnp::sandstorm_http_bridge,
+    web_publishing_capnp::web_site,
 };
 
-use webpub::main_view;
+use webpub::{
+    bench,
+    build,
+    export,
+    main_view,
+    storage::Storage,
+    upload_fs,
+    url_policy,
+};
 
 
 pub fn run_sandstorm_app() {
@@ -49,29 +58,170 @@ pub fn run_sandstorm_app() {
     }).unwrap();
 }
 
-fn upload_dir(dir: &str, restore: &[u8]) {
-    let mut rt = tokio::runtime::Runtime::new().unwrap();
+/// Where `upload-fs` should get the files to upload from.
+enum UploadSource<'a> {
+    Path(&'a str),
+    Archive(&'a str),
+}
+
+fn upload_dir(source: UploadSource,
+              restore: &[u8],
+              options: &upload_fs::Options) -> Result<upload_fs::Report, Box<dyn std::error::Error>> {
+    let mut rt = tokio::runtime::Runtime::new()?;
     let local = tokio::task::LocalSet::new();
 
     local.block_on(&mut rt, async {
         let stream = tokio::net::UnixStream::connect(std::path::Path::new("/tmp/sandstorm-api"))
-            .await.unwrap();
+            .await?;
         let (read_half, write_half) = futures_tokio_compat::Compat::new(stream).split();
 
         let network =
             Box::new(twoparty::VatNetwork::new(read_half, write_half,
                                                rpc_twoparty_capnp::Side::Client,
                                                Default::default()));
-        let (_tx, rx) = ::futures::channel::oneshot::channel();
+        let mut rpc_system = RpcSystem::new(network, None);
         let bridge: sandstorm_http_bridge::Client =
-            ::capnp_rpc::new_promise_client(rx.map_err(|_e| capnp::Error::failed(format!("oneshot was canceled"))));
+            rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+        tokio::task::spawn_local(rpc_system.map_err(|e| println!("RPC error: {:?}", e)));
+
         let mut req = bridge
             .get_sandstorm_api_request().send()
             .pipeline.get_api().restore_request();
         let mut token_buf = req.init_token(restore.len());
         token_buf[..].clone_from_slice(restore);
-        let cap = req.send().pipeline.get_cap();
-        RpcSystem::new(network, None).await.unwrap();
+        let site = web_site::Client {
+            client: capnp::capability::Client::new(req.send().pipeline.get_cap().as_cap()),
+        };
+        let report = match source {
+            UploadSource::Path(path) => {
+                upload_fs::upload_path(std::path::Path::new(path), &site, options).await?
+            },
+            UploadSource::Archive("-") => {
+                upload_fs::upload_stream(std::io::stdin(), &site, options).await?
+            },
+            UploadSource::Archive(path) => {
+                let file = std::fs::File::open(path)?;
+                upload_fs::upload_archive(file, &site, options).await?
+            },
+        };
+        Ok::<_, Box<dyn std::error::Error>>(report)
+    })
+}
+
+/// Write a summary of `report` for people to read to `out`.
+fn print_upload_report(report: &upload_fs::Report, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+    writeln!(out, "Uploaded {} paths ({} bytes) in {:.2}s, {:.1} KiB/s",
+             report.uploaded,
+             report.bytes,
+             report.elapsed.as_secs_f64(),
+             report.throughput() / 1024.0)?;
+    if report.skipped.len() > 0 {
+        writeln!(out, "Skipped {} paths:", report.skipped.len())?;
+        for path in report.skipped.iter() {
+            writeln!(out, "  {}", path)?;
+        }
+    }
+    for warning in report.warnings.iter() {
+        writeln!(out, "Warning: {}", warning)?;
+    }
+    if report.failures.len() > 0 {
+        writeln!(out, "{} files failed to upload:", report.failures.len())?;
+        for failure in report.failures.iter() {
+            writeln!(out, "  {}", failure)?;
+        }
+    }
+    Ok(())
+}
+
+/// Open the directory holding the sites, as given by `WEB_SITES_DIR`.
+fn open_storage() -> Result<Storage, Box<dyn std::error::Error>> {
+    match std::env::var_os("WEB_SITES_DIR") {
+        Some(dir) => Ok(Storage::new(std::path::PathBuf::from(dir))),
+        None => Err("WEB_SITES_DIR must be set to the directory holding the sites".into()),
+    }
+}
+
+fn export_site(name: &str, output: &str, format: export::Format) -> Result<(), Box<dyn std::error::Error>> {
+    let site = open_storage()?.get(name)?;
+    let file = std::fs::File::create(output)?;
+    export::export(&site, format, file)?;
+    Ok(())
+}
+
+fn build_site(name: &str, source: &str, options: &build::Options) -> Result<(), Box<dyn std::error::Error>> {
+    let site = open_storage()?.get(name)?;
+    let report = build::build(std::path::Path::new(source), &site, options)?;
+    println!("Published {} pages, {} tag pages and {} other files (version {})",
+             report.pages,
+             report.tags,
+             report.files,
+             report.version);
+    if !report.feeds {
+        println!("No feeds were generated; set url in site.toml to get them");
+    }
+    Ok(())
+}
+
+/// List the broken links in the site `name`. Returns whether there were
+/// none.
+fn check_links(name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let site = open_storage()?.get(name)?;
+    let broken = site.broken_links()?;
+    for link in broken.iter() {
+        println!("{}: {}", link.page_url(), link.link);
+    }
+    if broken.len() > 0 {
+        println!("{} broken links", broken.len());
+    }
+    Ok(broken.is_empty())
+}
+
+fn compact_site(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut storage = open_storage()?;
+    // Compacting swaps the database out from under anyone else using it,
+    // so make sure nobody is.
+    storage.get(name)?.claim()?;
+    let compaction = storage.compact(name)?;
+    println!("Reclaimed {} bytes ({} -> {})",
+             compaction.reclaimed(),
+             compaction.before,
+             compaction.after);
+    Ok(())
+}
+
+/// Time reads of every page in the site `name`, first on the event loop
+/// and then on the blocking thread pool.
+fn bench_reads(name: &str, concurrency: usize, rounds: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let site = open_storage()?.get(name)?;
+    let mut rt = tokio::runtime::Runtime::new()?;
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&mut rt, async {
+        for &mode in bench::Mode::all().iter() {
+            let timing = bench::reads(&site, mode, concurrency, rounds).await?;
+            println!("{}: {} reads in {:?} ({:.0}/s); the event loop stalled for up to {:?}",
+                     mode.name(),
+                     timing.reads,
+                     timing.elapsed,
+                     timing.reads_per_sec(),
+                     timing.max_stall);
+        }
+        Ok::<_, Box<dyn std::error::Error>>(())
+    })
+}
+
+/// Parse the number given for `arg`, exiting with an error if it isn't one.
+fn number_arg(matches: &clap::ArgMatches, arg: &str) -> usize {
+    matches.value_of(arg).unwrap().parse().unwrap_or_else(|_| {
+        eprintln!("Error: --{} must be a number", arg);
+        std::process::exit(2)
+    })
+}
+
+/// Exit with an error if `result` is one.
+fn exit_on_error<T>(result: Result<T, Box<dyn std::error::Error>>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1)
     })
 }
 
@@ -80,24 +230,305 @@ fn main() {
         .version("0.1")
         .author("Ian Denhardt <ian@zenhack.net>")
         .subcommand(clap::SubCommand::with_name("upload-fs")
-                    .about("Upload a local directory as a website.")
+                    .about("Upload a local directory or archive as a website.")
                     .arg(clap::Arg::with_name("directory")
                          .short("d")
                          .long("directory")
                          .value_name("PATH")
-                         .required(true)
                          .help("The directory to upload"))
+                    .arg(clap::Arg::with_name("archive")
+                         .short("a")
+                         .long("archive")
+                         .value_name("PATH")
+                         .help("A tar (optionally gzipped) or zip archive to upload. \
+                                Use - to read the archive from stdin; a tar archive read \
+                                that way must have its manifest, .webpubignore and \
+                                _layout.html first, and a zip archive is copied to a \
+                                temporary file"))
+                    .group(clap::ArgGroup::with_name("source")
+                           .args(&["directory", "archive"])
+                           .required(true))
+                    .arg(clap::Arg::with_name("exclude")
+                         .long("exclude")
+                         .value_name("PATTERN")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Skip files matching PATTERN (in .gitignore format). \
+                                Patterns are also read from .webpubignore, if present"))
+                    .arg(clap::Arg::with_name("include")
+                         .long("include")
+                         .value_name("PATTERN")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Upload files matching PATTERN, even if they would otherwise be skipped"))
+                    .arg(clap::Arg::with_name("hidden")
+                         .long("hidden")
+                         .help("Upload hidden files, which are skipped by default"))
+                    .arg(clap::Arg::with_name("mime-types")
+                         .long("mime-types")
+                         .value_name("PATH")
+                         .help("A file of rules overriding the detected mime types. \
+                                Each line holds a glob pattern and a mime type"))
+                    .arg(clap::Arg::with_name("symlinks")
+                         .long("symlinks")
+                         .value_name("POLICY")
+                         .possible_values(&["follow", "skip", "redirect"])
+                         .default_value("follow")
+                         .help("What to do with symbolic links: upload what they point to, \
+                                leave them out, or store them as redirects"))
+                    .arg(clap::Arg::with_name("allow-outside-root")
+                         .long("allow-outside-root")
+                         .help("Follow symbolic links which point outside of the directory"))
+                    .arg(clap::Arg::with_name("jobs")
+                         .short("j")
+                         .long("jobs")
+                         .value_name("N")
+                         .default_value("4")
+                         .help("The maximum number of files to upload at once"))
+                    .arg(clap::Arg::with_name("keep-going")
+                         .short("k")
+                         .long("keep-going")
+                         .help("Carry on uploading the rest of the files if one fails"))
+                    .arg(clap::Arg::with_name("strip-html")
+                         .long("strip-html")
+                         .help("Publish foo.html at foo, redirecting foo.html there"))
+                    .arg(clap::Arg::with_name("trailing-slash")
+                         .long("trailing-slash")
+                         .value_name("POLICY")
+                         .possible_values(&["always", "never"])
+                         .default_value("always")
+                         .help("Whether to publish directories (and stripped .html files) \
+                                with a trailing slash. The other form redirects to it"))
+                    .arg(clap::Arg::with_name("markdown")
+                         .long("markdown")
+                         .help("Render Markdown files to html pages, using the layout in \
+                                _layout.html if there is one"))
+                    .arg(clap::Arg::with_name("markdown-layout")
+                         .long("markdown-layout")
+                         .value_name("PATH")
+                         .requires("markdown")
+                         .help("The layout template to render Markdown into, instead of _layout.html"))
+                    .arg(clap::Arg::with_name("keep-markdown")
+                         .long("keep-markdown")
+                         .requires("markdown")
+                         .help("Also publish the Markdown source of each page, as text/markdown"))
+                    .arg(clap::Arg::with_name("report-json")
+                         .long("report-json")
+                         .value_name("PATH")
+                         .help("Also write a report of the upload to PATH as JSON. \
+                                Use - for stdout, in which case the summary goes to stderr"))
                     .arg(clap::Arg::with_name("restore")
                          .short("r")
                          .long("restore")
                          .value_name("RESTORE_TOKEN")
                          .required(true)
                          .help("A token with which to acquire the website capability")))
+        .subcommand(clap::SubCommand::with_name("export")
+                    .about("Export a website as an archive, which upload-fs can import.")
+                    .arg(clap::Arg::with_name("site")
+                         .short("s")
+                         .long("site")
+                         .value_name("NAME")
+                         .required(true)
+                         .help("The name of the site to export"))
+                    .arg(clap::Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("PATH")
+                         .help("The file to write the archive to. Defaults to \
+                                the site's name, with the format's extension"))
+                    .arg(clap::Arg::with_name("format")
+                         .short("f")
+                         .long("format")
+                         .value_name("FORMAT")
+                         .possible_values(&["tar", "zip"])
+                         .default_value("tar")
+                         .help("The archive format")))
+        .subcommand(clap::SubCommand::with_name("build")
+                    .about("Generate a website from pages and templates, and publish it.")
+                    .arg(clap::Arg::with_name("site")
+                         .short("s")
+                         .long("site")
+                         .value_name("NAME")
+                         .required(true)
+                         .help("The name of the site to publish to"))
+                    .arg(clap::Arg::with_name("source")
+                         .short("d")
+                         .long("source")
+                         .value_name("PATH")
+                         .default_value(".")
+                         .help("The directory holding site.toml, content/, layouts/ and static/"))
+                    .arg(clap::Arg::with_name("drafts")
+                         .long("drafts")
+                         .help("Publish pages marked as drafts"))
+                    .arg(clap::Arg::with_name("check-links")
+                         .long("check-links")
+                         .help("Don't publish this build if it would leave the site with \
+                                broken links which the current version doesn't have. \
+                                Uploads aren't checked; run check-links after them instead")))
+        .subcommand(clap::SubCommand::with_name("check-links")
+                    .about("List the links on a website which lead nowhere.")
+                    .arg(clap::Arg::with_name("site")
+                         .short("s")
+                         .long("site")
+                         .value_name("NAME")
+                         .required(true)
+                         .help("The name of the site to check")))
+        .subcommand(clap::SubCommand::with_name("compact")
+                    .about("Shrink a website's database, freeing the space left over from old versions. \
+                            Nothing else may write to the site meanwhile.")
+                    .arg(clap::Arg::with_name("site")
+                         .short("s")
+                         .long("site")
+                         .value_name("NAME")
+                         .required(true)
+                         .help("The name of the site to compact")))
+        .subcommand(clap::SubCommand::with_name("bench-reads")
+                    .about("Time reading every page of a website: on the event loop, as when \
+                            sites were served from one thread, then on the blocking thread \
+                            pool, then from the runtime's worker threads.")
+                    .arg(clap::Arg::with_name("site")
+                         .short("s")
+                         .long("site")
+                         .value_name("NAME")
+                         .required(true)
+                         .help("The name of the site to read"))
+                    .arg(clap::Arg::with_name("concurrency")
+                         .short("c")
+                         .long("concurrency")
+                         .value_name("N")
+                         .default_value("16")
+                         .help("The number of reads to have in flight at once"))
+                    .arg(clap::Arg::with_name("rounds")
+                         .long("rounds")
+                         .value_name("N")
+                         .default_value("10")
+                         .help("How many times to read each page")))
                     .get_matches();
     if let Some(matches) = matches.subcommand_matches("upload-fs") {
-        let dir = matches.value_of("directory").unwrap();
+        let source = match matches.value_of("directory") {
+            Some(dir) => UploadSource::Path(dir),
+            None => UploadSource::Archive(matches.value_of("archive").unwrap()),
+        };
         let restore = matches.value_of("restore").unwrap();
-        upload_dir(dir, hex::decode(restore).unwrap())
+        let patterns = |name: &str| -> Vec<String> {
+            matches.values_of(name)
+                .map(|values| values.map(String::from).collect())
+                .unwrap_or_default()
+        };
+        let mime = match matches.value_of("mime-types") {
+            Some(path) => {
+                let parsed = std::fs::read_to_string(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| upload_fs::mime::Rules::parse(&text));
+                parsed.unwrap_or_else(|e| {
+                    eprintln!("Error reading {}: {}", path, e);
+                    std::process::exit(2)
+                })
+            },
+            None => Default::default(),
+        };
+        let markdown = if matches.is_present("markdown") {
+            let layout = matches.value_of("markdown-layout").map(|path| {
+                std::fs::read_to_string(path).unwrap_or_else(|e| {
+                    eprintln!("Error reading {}: {}", path, e);
+                    std::process::exit(2)
+                })
+            });
+            Some(upload_fs::markdown::Options {
+                layout: layout,
+                keep_source: matches.is_present("keep-markdown"),
+            })
+        } else {
+            None
+        };
+        let options = upload_fs::Options {
+            filter: upload_fs::filter::Rules {
+                excludes: patterns("exclude"),
+                includes: patterns("include"),
+                include_hidden: matches.is_present("hidden"),
+            },
+            mime: mime,
+            symlinks: match matches.value_of("symlinks").unwrap() {
+                "skip" => upload_fs::SymlinkPolicy::Skip,
+                "redirect" => upload_fs::SymlinkPolicy::Redirect,
+                _ => upload_fs::SymlinkPolicy::Follow,
+            },
+            allow_outside_root: matches.is_present("allow-outside-root"),
+            jobs: matches.value_of("jobs").unwrap().parse().unwrap_or_else(|_| {
+                eprintln!("Error: --jobs must be a number");
+                std::process::exit(2)
+            }),
+            keep_going: matches.is_present("keep-going"),
+            url_policy: url_policy::UrlPolicy {
+                strip_html: matches.is_present("strip-html"),
+                trailing_slash: match matches.value_of("trailing-slash").unwrap() {
+                    "never" => url_policy::TrailingSlash::Never,
+                    _ => url_policy::TrailingSlash::Always,
+                },
+            },
+            markdown: markdown,
+        };
+        let restore = hex::decode(restore).unwrap_or_else(|e| {
+            eprintln!("Error: invalid restore token: {}", e);
+            std::process::exit(2)
+        });
+        let report = upload_dir(source, &restore, &options).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1)
+        });
+        // If the JSON report is going to stdout, keep the summary out of its
+        // way, so the output can still be parsed.
+        let printed = if matches.value_of("report-json") == Some("-") {
+            print_upload_report(&report, &mut std::io::stderr())
+        } else {
+            print_upload_report(&report, &mut std::io::stdout())
+        };
+        if let Err(e) = printed {
+            eprintln!("Error writing report: {}", e);
+            std::process::exit(1)
+        }
+        if let Some(path) = matches.value_of("report-json") {
+            let json = serde_json::to_string_pretty(&report).unwrap();
+            let written = if path == "-" {
+                println!("{}", json);
+                Ok(())
+            } else {
+                std::fs::write(path, json)
+            };
+            if let Err(e) = written {
+                eprintln!("Error writing report to {}: {}", path, e);
+                std::process::exit(1)
+            }
+        }
+        if report.failures.len() > 0 {
+            std::process::exit(1)
+        }
+    } else if let Some(matches) = matches.subcommand_matches("export") {
+        let name = matches.value_of("site").unwrap();
+        let format = export::Format::from_name(matches.value_of("format").unwrap()).unwrap();
+        let output = match matches.value_of("output") {
+            Some(output) => String::from(output),
+            None => format!("{}.{}", name, format.extension()),
+        };
+        exit_on_error(export_site(name, &output, format))
+    } else if let Some(matches) = matches.subcommand_matches("build") {
+        let name = matches.value_of("site").unwrap();
+        let source = matches.value_of("source").unwrap();
+        exit_on_error(build_site(name, source, &build::Options {
+            drafts: matches.is_present("drafts"),
+            check_links: matches.is_present("check-links"),
+        }))
+    } else if let Some(matches) = matches.subcommand_matches("check-links") {
+        if !exit_on_error(check_links(matches.value_of("site").unwrap())) {
+            std::process::exit(1)
+        }
+    } else if let Some(matches) = matches.subcommand_matches("compact") {
+        exit_on_error(compact_site(matches.value_of("site").unwrap()))
+    } else if let Some(matches) = matches.subcommand_matches("bench-reads") {
+        exit_on_error(bench_reads(matches.value_of("site").unwrap(),
+                                  number_arg(matches, "concurrency"),
+                                  number_arg(matches, "rounds")))
     } else {
         run_sandstorm_app()
     }
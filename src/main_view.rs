@@ -33,7 +33,7 @@ impl MainViewImpl {
     }
 
     fn get_site(&mut self, name: &str) -> result::Result<lmdb_web_site::LMDBWebSite, capnp::Error> {
-        self.storage.lock().unwrap().get(name).map_err(lmdb_web_site::db_err)
+        self.storage.lock().unwrap().get(name)
     }
 }
 
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Name of the manifest file at the root of an exported site.
+pub const FILE_NAME: &str = ".webpub-manifest.json";
+
+/// Prefix of the files holding bodies whose usual file names are taken;
+/// see `FileNames`.
+pub const OVERFLOW_PREFIX: &str = ".webpub-overflow-";
+
+/// Describes every path in an exported site, including the things which
+/// can't be recovered from the files alone: redirects, mime types, and so
+/// on. When present, `upload_fs` uses this instead of guessing.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Map from url paths to the entities stored there.
+    pub paths: BTreeMap<String, Vec<Entity>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entity {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub mime_type: String,
+
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub language: String,
+
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub encoding: String,
+
+    /// The file holding the body, relative to the manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_to: Option<String>,
+}
+
+/// Picks the files to store bodies in when exporting a site, so that no
+/// two bodies get the same file, and no file is also needed as a directory.
+/// That would otherwise happen for sites with both `foo` and `foo/bar`, or
+/// both `foo/` and `foo/index.html`; the archive could then not be unpacked.
+///
+/// Each body gets the file from `file_for_path()` if that is free, or else
+/// a numbered file at the root starting with `OVERFLOW_PREFIX`. The manifest records which file
+/// belongs to which path, so nothing is lost either way.
+#[derive(Debug, Default)]
+pub struct FileNames {
+    files: HashSet<String>,
+    dirs: HashSet<String>,
+    overflow: usize,
+}
+
+impl FileNames {
+    /// Get the file to store the `n`th entity at `url_path` in.
+    pub fn assign(&mut self, url_path: &str, n: usize) -> String {
+        let mut file = file_for_path(url_path, n);
+        while !self.is_free(&file) {
+            self.overflow += 1;
+            file = format!("{}{}", OVERFLOW_PREFIX, self.overflow);
+        }
+        let mut dir = &file[..];
+        while let Some(i) = dir.rfind('/') {
+            dir = &dir[..i];
+            self.dirs.insert(String::from(dir));
+        }
+        self.files.insert(file.clone());
+        file
+    }
+
+    fn is_free(&self, file: &str) -> bool {
+        if file == FILE_NAME || self.files.contains(file) || self.dirs.contains(file) {
+            return false
+        }
+        let mut dir = file;
+        while let Some(i) = dir.rfind('/') {
+            dir = &dir[..i];
+            if self.files.contains(dir) {
+                return false
+            }
+        }
+        true
+    }
+}
+
+/// Get the file name under which to store the `n`th entity at `url_path`.
+/// Directories get an `index.html`, as `upload_fs` expects.
+pub fn file_for_path(url_path: &str, n: usize) -> String {
+    let mut file = if url_path == "" || url_path.ends_with('/') {
+        String::from(url_path) + "index.html"
+    } else {
+        String::from(url_path)
+    };
+    if n > 0 {
+        file += &format!("~{}", n);
+    }
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_for_path_gives_directories_an_index() {
+        assert_eq!(file_for_path("", 0), "index.html");
+        assert_eq!(file_for_path("a/", 0), "a/index.html");
+        assert_eq!(file_for_path("a/b.css", 0), "a/b.css");
+        assert_eq!(file_for_path("a/b.css", 2), "a/b.css~2");
+    }
+
+    #[test]
+    fn file_names_never_collide() {
+        let mut names = FileNames::default();
+        assert_eq!(names.assign("a/", 0), "a/index.html");
+        // Taken as a file already.
+        assert_eq!(names.assign("a/index.html", 0), ".webpub-overflow-1");
+        // Needed as a directory.
+        assert_eq!(names.assign("a", 0), ".webpub-overflow-2");
+        assert_eq!(names.assign("b", 0), "b");
+        // Would need a file as a directory.
+        assert_eq!(names.assign("b/c", 0), ".webpub-overflow-3");
+        assert_eq!(names.assign(FILE_NAME, 0), ".webpub-overflow-4");
+        assert_eq!(names.assign("a/", 1), "a/index.html~1");
+    }
+}
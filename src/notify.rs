@@ -0,0 +1,338 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use futures::{channel::mpsc, StreamExt};
+use sandstorm::util_capnp::handle;
+use crate::promise_util::Promise;
+
+/// A change to the entities stored at some path in a site.
+#[derive(Clone, Debug)]
+pub struct Change {
+    /// The path that changed, relative to the site the observer was
+    /// registered on.
+    pub path: String,
+
+    /// The version of the site after the change was committed. Versions
+    /// increase monotonically with each write.
+    pub version: u64,
+}
+
+/// Something that wants to hear about changes to a site.
+pub trait Observer {
+    fn changed(&mut self, change: &Change) -> Promise;
+}
+
+/// Which paths an observer is interested in.
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// Exactly this path.
+    Path(String),
+
+    /// Every path starting with this prefix. The empty prefix watches the
+    /// whole site.
+    Prefix(String),
+}
+
+impl Target {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            Target::Path(p) => path == p,
+            Target::Prefix(p) => path.starts_with(&p[..]),
+        }
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    base: String,
+    target: Target,
+    observer: Box<dyn Observer>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Registry {
+    /// Call every observer interested in `key`, unsubscribing those whose
+    /// calls fail.
+    fn notify(registry: &Rc<RefCell<Registry>>, key: &str, version: u64) {
+        let calls: Vec<(u64, Promise)> = registry.borrow_mut().subscribers.iter_mut().filter_map(|sub| {
+            if !key.starts_with(&sub.base[..]) {
+                return None
+            }
+            let path = &key[sub.base.len()..];
+            if !sub.target.matches(path) {
+                return None
+            }
+            Some((sub.id, sub.observer.changed(&Change {
+                path: String::from(path),
+                version: version,
+            })))
+        }).collect();
+        for (id, call) in calls {
+            let registry = Rc::downgrade(registry);
+            tokio::task::spawn_local(async move {
+                if let Err(e) = call.await {
+                    println!("Error notifying observer, unsubscribing it: {:?}", e);
+                    if let Some(registry) = registry.upgrade() {
+                        registry.borrow_mut().subscribers.retain(|sub| sub.id != id);
+                    }
+                }
+            });
+        }
+    }
+}
+
+thread_local! {
+    /// The registries of the sites with observers on this thread, by the
+    /// id of their `Observers`.
+    static REGISTRIES: RefCell<HashMap<u64, Rc<RefCell<Registry>>>> = RefCell::new(HashMap::new());
+}
+
+static NEXT_OBSERVERS_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Shared {
+    id: u64,
+    changes: mpsc::UnboundedSender<(String, u64)>,
+
+    /// Where changes arrive, until the first observer subscribes and a
+    /// task is spawned to deliver them.
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<(String, u64)>>>,
+
+    /// Whether anyone has subscribed; until then changes aren't sent.
+    subscribed: AtomicBool,
+}
+
+/// The set of observers registered on a site. Clones share the same set.
+///
+/// Observers hold capabilities, which can't leave the thread running the
+/// RPC system, so the registry lives on that thread; this is only a handle
+/// to it, which may be sent to, and notified from, any thread. Changes are
+/// passed to the registry over a channel.
+#[derive(Clone)]
+pub struct Observers(Arc<Shared>);
+
+impl Default for Observers {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        Observers(Arc::new(Shared {
+            id: NEXT_OBSERVERS_ID.fetch_add(1, Ordering::Relaxed),
+            changes: sender,
+            receiver: Mutex::new(Some(receiver)),
+            subscribed: AtomicBool::new(false),
+        }))
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Observers")
+            .field("id", &self.0.id)
+            .field("subscribed", &self.0.subscribed.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl Observers {
+    /// Register `observer` for changes to `target`, which is relative to
+    /// `base`. The observer stays registered until the returned
+    /// `Subscription` is dropped.
+    ///
+    /// Must be called from within the `LocalSet` of the thread running the
+    /// RPC system; every observer of a site is registered on that thread.
+    pub fn subscribe(&self,
+                     base: String,
+                     target: Target,
+                     observer: Box<dyn Observer>) -> Subscription {
+        let registry = REGISTRIES.with(|registries| {
+            registries.borrow_mut().entry(self.0.id).or_insert_with(|| {
+                let receiver = self.0.receiver.lock().unwrap().take()
+                    .expect("observers of a site must be registered on a single thread");
+                let registry = Rc::new(RefCell::new(Registry::default()));
+                tokio::task::spawn_local(deliver(self.0.id, registry.clone(), receiver));
+                self.0.subscribed.store(true, Ordering::SeqCst);
+                registry
+            }).clone()
+        });
+        let mut guard = registry.borrow_mut();
+        let id = guard.next_id;
+        guard.next_id += 1;
+        guard.subscribers.push(Subscriber {
+            id: id,
+            base: base,
+            target: target,
+            observer: observer,
+        });
+        Subscription {
+            registry: Rc::downgrade(&registry),
+            id: id,
+        }
+    }
+
+    /// Tell every interested observer that the entities at `key` have
+    /// changed. This doesn't wait for the observers, so a slow one can't
+    /// hold up whoever made the change; observers whose calls fail are
+    /// unsubscribed. May be called from any thread.
+    pub fn notify(&self, key: &str, version: u64) {
+        if self.0.subscribed.load(Ordering::SeqCst) {
+            // This only fails once the delivering task has gone, along
+            // with the observers.
+            let _ = self.0.changes.unbounded_send((String::from(key), version));
+        }
+    }
+}
+
+/// Pass the changes sent to a site's `Observers` on to its registry, until
+/// every handle to it is gone.
+async fn deliver(id: u64,
+                 registry: Rc<RefCell<Registry>>,
+                 mut changes: mpsc::UnboundedReceiver<(String, u64)>) {
+    while let Some((key, version)) = changes.next().await {
+        Registry::notify(&registry, &key, version);
+    }
+    REGISTRIES.with(|registries| registries.borrow_mut().remove(&id));
+}
+
+/// Keeps an observer registered. Dropping this unsubscribes it.
+pub struct Subscription {
+    registry: Weak<RefCell<Registry>>,
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.upgrade() {
+            let id = self.id;
+            registry.borrow_mut().subscribers.retain(|sub| sub.id != id);
+        }
+    }
+}
+
+impl handle::Server for Subscription {
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capnp::capability;
+    use std::future::Future;
+
+    /// Records the changes it's told about.
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Observer for Recorder {
+        fn changed(&mut self, change: &Change) -> Promise {
+            self.0.lock().unwrap().push(format!("{}@{}", change.path, change.version));
+            crate::promise_util::ok()
+        }
+    }
+
+    fn recorder() -> (Box<dyn Observer>, Arc<Mutex<Vec<String>>>) {
+        let changes = Arc::new(Mutex::new(vec![]));
+        (Box::new(Recorder(changes.clone())), changes)
+    }
+
+    fn run<F: Future>(f: F) -> F::Output {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        tokio::task::LocalSet::new().block_on(&mut rt, f)
+    }
+
+    /// Let the tasks delivering changes run.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Fails every call, after counting it.
+    struct Failing(Arc<Mutex<u32>>);
+
+    impl Observer for Failing {
+        fn changed(&mut self, _change: &Change) -> Promise {
+            *self.0.lock().unwrap() += 1;
+            capability::Promise::err(capnp::Error::failed(String::from("gone away")))
+        }
+    }
+
+    #[test]
+    fn observers_hear_about_matching_paths_under_their_base() {
+        run(async {
+            let observers = Observers::default();
+            let (whole_site, site_changes) = recorder();
+            let (docs, docs_changes) = recorder();
+            let (page, page_changes) = recorder();
+            let _subscriptions = vec![
+                observers.subscribe(String::new(), Target::Prefix(String::new()), whole_site),
+                observers.subscribe(String::from("docs/"), Target::Prefix(String::from("api/")), docs),
+                observers.subscribe(String::from("docs/"), Target::Path(String::from("index.html")), page),
+            ];
+            observers.notify("docs/index.html", 1);
+            observers.notify("docs/api/intro.html", 2);
+            observers.notify("docs/index.html.bak", 3);
+            observers.notify("index.html", 4);
+            settle().await;
+            assert_eq!(*site_changes.lock().unwrap(), vec![
+                "docs/index.html@1", "docs/api/intro.html@2", "docs/index.html.bak@3", "index.html@4",
+            ]);
+            assert_eq!(*docs_changes.lock().unwrap(), vec!["api/intro.html@2"]);
+            assert_eq!(*page_changes.lock().unwrap(), vec!["index.html@1"]);
+        });
+    }
+
+    #[test]
+    fn dropping_a_subscription_unsubscribes_its_observer() {
+        run(async {
+            let observers = Observers::default();
+            let (observer, changes) = recorder();
+            let subscription = observers.subscribe(String::new(), Target::Prefix(String::new()), observer);
+            observers.notify("a.html", 1);
+            settle().await;
+            drop(subscription);
+            observers.notify("b.html", 2);
+            settle().await;
+            assert_eq!(*changes.lock().unwrap(), vec!["a.html@1"]);
+        });
+    }
+
+    #[test]
+    fn observers_whose_calls_fail_are_unsubscribed() {
+        run(async {
+            let observers = Observers::default();
+            let calls = Arc::new(Mutex::new(0));
+            let (observer, changes) = recorder();
+            let _subscriptions = vec![
+                observers.subscribe(String::new(), Target::Prefix(String::new()), Box::new(Failing(calls.clone()))),
+                observers.subscribe(String::new(), Target::Prefix(String::new()), observer),
+            ];
+            observers.notify("a.html", 1);
+            settle().await;
+            observers.notify("b.html", 2);
+            settle().await;
+            assert_eq!(*calls.lock().unwrap(), 1);
+            assert_eq!(*changes.lock().unwrap(), vec!["a.html@1", "b.html@2"]);
+        });
+    }
+
+    #[test]
+    fn changes_can_be_sent_from_other_threads() {
+        run(async {
+            let observers = Observers::default();
+            let (observer, changes) = recorder();
+            let _subscription = observers.subscribe(String::new(), Target::Prefix(String::new()), observer);
+            let sender = observers.clone();
+            std::thread::spawn(move || sender.notify("index.html", 1)).join().unwrap();
+            settle().await;
+            assert_eq!(*changes.lock().unwrap(), vec!["index.html@1"]);
+        });
+    }
+}
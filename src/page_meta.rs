@@ -0,0 +1,97 @@
+use crate::{feed, html::unescape};
+use serde::{Deserialize, Serialize};
+
+/// Metadata about a page, which we read from its html when it is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PageMeta {
+    /// From the `<title>` element.
+    pub title: String,
+
+    /// From `<meta name="date">`, or `<meta property="article:published_time">`.
+    pub date: String,
+
+    /// From `<meta name="description">`.
+    pub summary: String,
+
+    /// When the page's contents last changed, in RFC 3339 format. This
+    /// isn't read from the page; it's set when the page is stored.
+    pub modified: String,
+}
+
+impl PageMeta {
+    /// Pages go in the site's feed if they have a valid date.
+    pub fn is_feed_item(&self) -> bool {
+        feed::parse_date(&self.date).is_some()
+    }
+
+    /// Whether `self` and `other` would give the same feed entry.
+    pub fn same_feed_entry(&self, other: &PageMeta) -> bool {
+        self.title == other.title && self.date == other.date && self.summary == other.summary
+    }
+}
+
+/// Read the metadata from a page. This isn't a real html parser, but
+/// copes with the markup generators and people normally write.
+pub fn extract(html: &str) -> PageMeta {
+    let lower = html.to_ascii_lowercase();
+    let mut meta = PageMeta::default();
+    if let Some(start) = lower.find("<title") {
+        if let Some(open_end) = lower[start..].find('>') {
+            let text_start = start + open_end + 1;
+            if let Some(len) = lower[text_start..].find("</title") {
+                meta.title = unescape(html[text_start..text_start + len].trim());
+            }
+        }
+    }
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("<meta") {
+        let start = offset + start;
+        let end = match lower[start..].find('>') {
+            Some(len) => start + len,
+            None => break,
+        };
+        let tag = &html[start + "<meta".len()..end];
+        let name = attribute(tag, "name").or_else(|| attribute(tag, "property"));
+        let content = attribute(tag, "content");
+        if let (Some(name), Some(content)) = (name, content) {
+            match &name.to_ascii_lowercase()[..] {
+                "date" | "article:published_time" if meta.date == "" => meta.date = content,
+                "description" if meta.summary == "" => meta.summary = content,
+                _ => (),
+            }
+        }
+        offset = end;
+    }
+    meta
+}
+
+/// Get the value of the attribute `name` from the inside of a tag.
+pub(crate) fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(i) = lower[offset..].find(name) {
+        let i = offset + i;
+        offset = i + name.len();
+        let preceded_ok = i == 0 || lower.as_bytes()[i - 1].is_ascii_whitespace();
+        let rest = lower[offset..].trim_start();
+        if !preceded_ok || !rest.starts_with('=') {
+            continue
+        }
+        let value_start = tag.len() - rest[1..].trim_start().len();
+        let value = &tag[value_start..];
+        let value = match value.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let value = &value[1..];
+                &value[..value.find(quote).unwrap_or(value.len())]
+            },
+            _ => {
+                let end = value.find(|c: char| c.is_ascii_whitespace() || c == '/')
+                    .unwrap_or(value.len());
+                &value[..end]
+            },
+        };
+        return Some(unescape(value))
+    }
+    None
+}
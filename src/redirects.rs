@@ -0,0 +1,285 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The name of the file holding a site's redirect rules. The rules in it
+/// apply to the directory the file is in.
+pub const FILE_NAME: &str = "_redirects";
+
+/// One `/`-separated piece of a rule's source pattern.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Segment {
+    /// Matches exactly this text.
+    Literal(String),
+
+    /// `:name`; matches any one segment, which can be used in the target
+    /// as `:name`.
+    Placeholder(String),
+
+    /// `*`; matches the rest of the path, which can be used in the target
+    /// as `:splat`. Only allowed at the end of a pattern.
+    Splat,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub from: Vec<Segment>,
+    pub to: String,
+
+    /// The HTTP status to respond with. 3xx codes redirect the client
+    /// to the target. 200 serves the target in place of the requested
+    /// path, and 404 serves it as a "not found" page.
+    pub status: u16,
+}
+
+/// A site's redirect rules, in order of precedence.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+/// The outcome of matching a request against a site's rules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    /// The target of the rule, with placeholders filled in.
+    pub to: String,
+    pub status: u16,
+}
+
+const STATUSES: &[u16] = &[200, 301, 302, 303, 307, 308, 404];
+
+impl Rules {
+    /// Parse a `_redirects` file. Each non-blank line which doesn't start
+    /// with `#` holds a source pattern, a target and optionally a status
+    /// (301 by default), separated by whitespace, e.g.:
+    ///
+    /// ```text
+    /// /old-page      /new-page
+    /// /blog/*        /news/:splat    302
+    /// /users/:id     /u/:id
+    /// /app/*         /app/index.html 200
+    /// ```
+    ///
+    /// A `!` after the status (as in Netlify's format) is accepted but
+    /// has no effect, since rules always take precedence over stored
+    /// pages. Earlier lines take precedence over later ones.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rules = Rules::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line == "" || line.starts_with('#') {
+                continue
+            }
+            let err = |msg: &str| format!("line {}: {}", i + 1, msg);
+            let mut words = line.split_whitespace();
+            let (from, to, status) = match (words.next(), words.next(), words.next(), words.next()) {
+                (Some(from), Some(to), status, None) => (from, to, status),
+                _ => return Err(err("expected a source, a target and optionally a status")),
+            };
+            let status = match status {
+                None => 301,
+                Some(status) => status.trim_end_matches('!').parse()
+                    .ok()
+                    .filter(|status| STATUSES.contains(status))
+                    .ok_or_else(|| err(&format!("unsupported status {:?}", status)))?,
+            };
+            if !from.starts_with('/') {
+                return Err(err("the source must start with /"))
+            }
+            if (status == 200 || status == 404) && !to.starts_with('/') {
+                return Err(err("the target of a 200 or 404 rule must be a path on this site"))
+            }
+            let from: Vec<Segment> = segments(from).map(|segment| {
+                if segment == "*" {
+                    Segment::Splat
+                } else if segment.starts_with(':') {
+                    Segment::Placeholder(String::from(&segment[1..]))
+                } else {
+                    Segment::Literal(String::from(segment))
+                }
+            }).collect();
+            if from.iter().rev().skip(1).any(|segment| *segment == Segment::Splat) {
+                return Err(err("* may only appear at the end of the source"))
+            }
+            rules.rules.push(Rule {
+                from: from,
+                to: String::from(to),
+                status: status,
+            });
+        }
+        Ok(rules)
+    }
+
+    /// Find the first rule matching `path` (relative to the directory the
+    /// rules came from), ignoring any query string.
+    pub fn resolve(&self, path: &str) -> Option<Match> {
+        let path = match path.find('?') {
+            Some(i) => &path[..i],
+            None => path,
+        };
+        self.rules.iter().find_map(|rule| rule.resolve(path))
+    }
+
+    /// Like `resolve`, for rules from the `_redirects` file in `dir`, which
+    /// encloses `path`. Both are relative to the root of the site, as is
+    /// the target of the match, if it is a path.
+    pub fn resolve_in(&self, dir: &str, path: &str) -> Option<Match> {
+        let mut found = self.resolve(path.get(dir.len()..)?)?;
+        if found.to.starts_with('/') {
+            found.to = format!("/{}{}", dir, &found.to[1..]);
+        }
+        Some(found)
+    }
+}
+
+impl Rule {
+    fn resolve(&self, path: &str) -> Option<Match> {
+        let mut parts = segments(path);
+        let mut params = HashMap::new();
+        for segment in self.from.iter() {
+            match segment {
+                Segment::Splat => {
+                    params.insert("splat", parts.collect::<Vec<_>>().join("/"));
+                    return Some(self.target(&params))
+                },
+                Segment::Literal(literal) => {
+                    if parts.next()? != &literal[..] {
+                        return None
+                    }
+                },
+                Segment::Placeholder(name) => {
+                    params.insert(&name[..], String::from(parts.next()?));
+                },
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(self.target(&params)),
+        }
+    }
+
+    /// Fill the placeholders in the rule's target in from `params`.
+    fn target(&self, params: &HashMap<&str, String>) -> Match {
+        let mut to = String::new();
+        let mut rest = &self.to[..];
+        while let Some(i) = rest.find(':') {
+            to.push_str(&rest[..i]);
+            let name_len = rest[i + 1..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len() - i - 1);
+            let name = &rest[i + 1..i + 1 + name_len];
+            match params.get(name) {
+                Some(value) if name_len > 0 => to.push_str(value),
+                _ => to.push_str(&rest[i..i + 1 + name_len]),
+            }
+            rest = &rest[i + 1 + name_len..];
+        }
+        to.push_str(rest);
+        Match {
+            to: to,
+            status: self.status,
+        }
+    }
+}
+
+/// The directories whose `_redirects` files apply to `path`, nearest
+/// first. Each has a trailing slash, except for the root, which is "".
+pub fn enclosing_dirs(path: &str) -> impl Iterator<Item = &str> {
+    path.rmatch_indices('/')
+        .map(move |(i, _)| &path[..i + 1])
+        .chain(std::iter::once(""))
+}
+
+/// Split a path into its non-empty segments, so that leading and trailing
+/// slashes don't matter.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| *segment != "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(to: &str, status: u16) -> Option<Match> {
+        Some(Match {
+            to: String::from(to),
+            status: status,
+        })
+    }
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let rules = Rules::parse("# comment\n\n  /a /b\n/c /d 302!\n").unwrap();
+        assert_eq!(rules.rules, vec![
+            Rule {
+                from: vec![Segment::Literal(String::from("a"))],
+                to: String::from("/b"),
+                status: 301,
+            },
+            Rule {
+                from: vec![Segment::Literal(String::from("c"))],
+                to: String::from("/d"),
+                status: 302,
+            },
+        ]);
+    }
+
+    #[test]
+    fn parse_rejects_bad_lines() {
+        for (text, line) in [
+            ("/a", 1),
+            ("/a /b 301 extra", 1),
+            ("/a /b 418", 1),
+            ("\na /b", 2),
+            ("/a /b\n/a http://example.com/ 200", 2),
+            ("/*/a /b", 1),
+        ].iter() {
+            let err = Rules::parse(text).unwrap_err();
+            assert!(err.starts_with(&format!("line {}:", line)), "{:?}: {}", text, err);
+        }
+    }
+
+    #[test]
+    fn resolve_fills_in_placeholders_and_splats() {
+        let rules = Rules::parse("\
+            /users/:id/posts/:post /u/:id/:post\n\
+            /blog/* /news/:splat 302\n\
+            /app/* /app/index.html 200\n\
+        ").unwrap();
+        assert_eq!(rules.resolve("/users/7/posts/9"), found("/u/7/9", 301));
+        assert_eq!(rules.resolve("users/7/posts/9/"), found("/u/7/9", 301));
+        assert_eq!(rules.resolve("/blog/2020/hello?x=1"), found("/news/2020/hello", 302));
+        assert_eq!(rules.resolve("/blog"), found("/news/", 302));
+        assert_eq!(rules.resolve("/app/settings"), found("/app/index.html", 200));
+        assert_eq!(rules.resolve("/users/7"), None);
+        assert_eq!(rules.resolve("/users/7/posts/9/10"), None);
+    }
+
+    #[test]
+    fn resolve_leaves_unknown_placeholders_alone() {
+        let rules = Rules::parse("/a/:x http://example.com/:x?port=:8080&y=:y").unwrap();
+        assert_eq!(rules.resolve("/a/b"), found("http://example.com/b?port=:8080&y=:y", 301));
+    }
+
+    #[test]
+    fn earlier_rules_take_precedence() {
+        let rules = Rules::parse("/a /first\n/a /second\n/* /rest").unwrap();
+        assert_eq!(rules.resolve("/a"), found("/first", 301));
+        assert_eq!(rules.resolve("/b"), found("/rest", 301));
+    }
+
+    #[test]
+    fn resolve_in_is_relative_to_the_directory() {
+        let rules = Rules::parse("/old /new\n/ext https://example.com/").unwrap();
+        assert_eq!(rules.resolve_in("docs/", "docs/old"), found("/docs/new", 301));
+        assert_eq!(rules.resolve_in("docs/", "docs/ext"), found("https://example.com/", 301));
+        assert_eq!(rules.resolve_in("", "old"), found("/new", 301));
+        assert_eq!(rules.resolve_in("docs/", "old"), None);
+    }
+
+    #[test]
+    fn enclosing_dirs_are_nearest_first() {
+        assert_eq!(enclosing_dirs("a/b/c").collect::<Vec<_>>(), vec!["a/b/", "a/", ""]);
+        assert_eq!(enclosing_dirs("a/b/").collect::<Vec<_>>(), vec!["a/b/", "a/", ""]);
+        assert_eq!(enclosing_dirs("index.html").collect::<Vec<_>>(), vec![""]);
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{html, page_meta};
+use pulldown_cmark::{Event, Parser, Tag};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// The path, relative to the root of each site, at which we serve search
+/// results. Anything the user publishes there is hidden.
+pub const SEARCH_PATH: &str = "_search";
+
+/// The number of results returned if the client doesn't ask for a
+/// particular number.
+pub const DEFAULT_LIMIT: usize = 10;
+
+/// The most results a client can ask for at once.
+pub const MAX_LIMIT: usize = 100;
+
+/// Words in the title count this many times as often as those in the
+/// body, so pages about a term rank above pages which mention it.
+const TITLE_WEIGHT: u32 = 3;
+
+/// Longer words aren't indexed; they are almost always junk (e.g. hashes),
+/// and would make for oversized database keys.
+const MAX_TERM_LEN: usize = 64;
+
+/// Roughly how long snippets are, in characters.
+const SNIPPET_LEN: usize = 160;
+
+/// What we keep about each page in the search index.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Document {
+    pub title: String,
+
+    /// The page's text, which snippets are taken from.
+    pub text: String,
+
+    /// How many times each term occurs in the page, with title words
+    /// weighted by `TITLE_WEIGHT`.
+    pub terms: BTreeMap<String, u32>,
+}
+
+impl Document {
+    pub fn new(title: String, text: String) -> Self {
+        let mut terms = BTreeMap::new();
+        for (weight, s) in [(TITLE_WEIGHT, &title), (1, &text)].iter() {
+            for (_, word) in words(s) {
+                *terms.entry(word).or_insert(0) += weight;
+            }
+        }
+        Document {
+            title: title,
+            text: text,
+            terms: terms,
+        }
+    }
+
+    /// Index an html page.
+    pub fn from_html(html: &str) -> Self {
+        Document::new(page_meta::extract(html).title, html_text(html))
+    }
+
+    /// Index a Markdown page. Its title is its first top-level heading.
+    pub fn from_markdown(source: &str) -> Self {
+        let mut title = None;
+        let mut in_title = false;
+        let mut text = String::new();
+        for event in Parser::new(source) {
+            match event {
+                Event::Start(Tag::Heading(1)) if title.is_none() => {
+                    in_title = true;
+                    title = Some(String::new());
+                },
+                Event::End(Tag::Heading(1)) => in_title = false,
+                Event::Text(s) | Event::Code(s) if in_title => {
+                    title.as_mut().unwrap().push_str(&s);
+                },
+                Event::Text(s) | Event::Code(s) => push_text(&mut text, &s),
+                Event::SoftBreak | Event::HardBreak | Event::End(_) => push_text(&mut text, " "),
+                _ => (),
+            }
+        }
+        Document::new(title.unwrap_or_default(), text)
+    }
+}
+
+/// One search result.
+#[derive(Clone, Debug, Serialize)]
+pub struct Hit {
+    /// Relative to the root of the site, starting with `/`.
+    pub path: String,
+    pub title: String,
+    pub snippet: String,
+    pub score: f64,
+}
+
+/// Split `text` into lowercase words, with their byte offsets in `text`.
+pub fn words(text: &str) -> impl Iterator<Item = (usize, String)> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && word.chars().count() <= MAX_TERM_LEN)
+        .map(move |word| {
+            let offset = word.as_ptr() as usize - text.as_ptr() as usize;
+            (offset, word.to_lowercase())
+        })
+}
+
+/// The distinct words of a search query, in order. Repeated words are
+/// kept where they last appear, so the final word of the query (which is
+/// matched as a prefix) stays last.
+pub fn query_terms(query: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut terms: Vec<String> = words(query)
+        .map(|(_, word)| word)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .filter(|word| seen.insert(word.clone()))
+        .collect();
+    terms.reverse();
+    terms
+}
+
+/// How much a term matching `count` of `total` documents tells us about
+/// a document which contains it `tf` times.
+pub fn score(tf: u32, count: usize, total: usize) -> f64 {
+    let idf = (1.0 + total as f64 / count.max(1) as f64).ln();
+    idf * (1.0 + (tf as f64).ln())
+}
+
+/// The part of `text` around the first occurrence of any of `terms`, or
+/// the start of `text` if none of them occur in it.
+pub fn snippet(text: &str, terms: &[String]) -> String {
+    let first = words(text)
+        .find(|(_, word)| terms.iter().any(|term| word.starts_with(&term[..])))
+        .map(|(offset, _)| offset)
+        .unwrap_or(0);
+    // Start a little before the match, at the beginning of a word.
+    let mut start = first;
+    for (i, c) in text[..first].char_indices().rev() {
+        if first - i > SNIPPET_LEN / 4 {
+            break
+        }
+        if c.is_whitespace() {
+            start = i + c.len_utf8();
+        }
+    }
+    let mut end = text.len();
+    for (i, c) in text[start..].char_indices() {
+        if i > SNIPPET_LEN && c.is_whitespace() {
+            end = start + i;
+            break
+        }
+    }
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(&text[start..end]);
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Get the text a reader would see on an html page, leaving out the
+/// head, scripts and styles.
+pub fn html_text(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let body_start = lower.find("<body").unwrap_or(0);
+    let mut text = String::new();
+    let mut offset = body_start;
+    while offset < html.len() {
+        let tag_start = match lower[offset..].find('<') {
+            Some(i) => offset + i,
+            None => html.len(),
+        };
+        push_text(&mut text, &html::unescape(&html[offset..tag_start]));
+        if tag_start == html.len() {
+            break
+        }
+        let tag_end = match lower[tag_start..].find('>') {
+            Some(i) => tag_start + i + 1,
+            None => break,
+        };
+        offset = tag_end;
+        for skipped in ["script", "style"].iter() {
+            let tag = &lower[tag_start + 1..tag_end];
+            if tag.starts_with(skipped) {
+                offset = match lower[tag_end..].find(&format!("</{}", skipped)) {
+                    Some(i) => tag_end + i,
+                    None => html.len(),
+                };
+            }
+        }
+        // Tags separate words, e.g. in `<li>one</li><li>two</li>`.
+        push_text(&mut text, " ");
+    }
+    text
+}
+
+/// Append `s` to `text`, collapsing runs of whitespace.
+fn push_text(text: &mut String, s: &str) {
+    for c in s.chars() {
+        if c.is_whitespace() {
+            if text != "" && !text.ends_with(' ') {
+                text.push(' ');
+            }
+        } else {
+            text.push(c);
+        }
+    }
+}
+
+/// Get the value of the parameter `name` from a url's query string.
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next()? == name {
+            Some(percent_decode(&parts.next().unwrap_or("").replace('+', " ")))
+        } else {
+            None
+        }
+    })
+}
+
+/// Escape everything but unreserved characters in `s`, so it can be used
+/// as part of a url.
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Decode the `%XX` escapes in part of a url.
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    },
+                    None => out.push(b'%'),
+                }
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_lowercased_with_offsets() {
+        let words: Vec<_> = words("Hello, wörld!  It's 2020").collect();
+        assert_eq!(words, vec![
+            (0, String::from("hello")),
+            (7, String::from("wörld")),
+            (16, String::from("it")),
+            (19, String::from("s")),
+            (21, String::from("2020")),
+        ]);
+    }
+
+    #[test]
+    fn words_skips_overlong_words() {
+        let long = "x".repeat(MAX_TERM_LEN + 1);
+        let text = format!("a {} b", long);
+        let words: Vec<_> = words(&text).map(|(_, word)| word).collect();
+        assert_eq!(words, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn query_terms_keep_the_last_word_last() {
+        assert_eq!(query_terms("Rust  rust lang"), vec!["rust", "lang"]);
+        assert_eq!(query_terms("lang rust LANG"), vec!["rust", "lang"]);
+        assert_eq!(query_terms("--"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn documents_weight_title_words() {
+        let doc = Document::new(String::from("Rust"), String::from("rust and more Rust"));
+        assert_eq!(doc.terms.get("rust"), Some(&(TITLE_WEIGHT + 2)));
+        assert_eq!(doc.terms.get("more"), Some(&1));
+    }
+
+    #[test]
+    fn html_text_leaves_out_the_head_scripts_and_styles() {
+        let html = "<html><head><title>T</title></head><body>\
+                    <p>One &amp; two</p><script>var x = 1;</script>\
+                    <style>p {}</style><ul><li>three</li><li>four</li></ul></body></html>";
+        assert_eq!(html_text(html), "One & two three four ");
+    }
+
+    #[test]
+    fn markdown_documents_take_their_title_from_the_first_heading() {
+        let doc = Document::from_markdown("Intro\n\n# The `title`\n\nBody text\n\n# Another\n");
+        assert_eq!(doc.title, "The title");
+        assert!(doc.text.contains("Intro"));
+        assert!(doc.text.contains("Another"));
+        assert!(!doc.text.contains("title"));
+    }
+
+    #[test]
+    fn snippet_starts_near_the_first_match() {
+        let text = format!("{} needle {}", "hay ".repeat(50), "hay ".repeat(50));
+        let snippet = snippet(&text, &[String::from("need")]);
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("needle"));
+        assert_eq!(super::snippet("short text", &[String::from("missing")]), "short text");
+    }
+
+    #[test]
+    fn query_param_decodes_its_value() {
+        assert_eq!(query_param("q=caf%C3%A9+au+lait&limit=5", "q").as_deref(), Some("café au lait"));
+        assert_eq!(query_param("q=a&limit=5", "limit").as_deref(), Some("5"));
+        assert_eq!(query_param("flag&q=a", "flag").as_deref(), Some(""));
+        assert_eq!(query_param("q=a", "limit"), None);
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        let s = "a b/c?d=é%";
+        assert_eq!(percent_encode(s), "a%20b%2Fc%3Fd%3D%C3%A9%25");
+        assert_eq!(percent_decode(&percent_encode(s)), s);
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+}
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+/// What to serve for a page which doesn't exist.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Fallback {
+    /// A plain "not found" error.
+    None,
+
+    /// Serve the page at `path` with status 200, so client-side routing
+    /// in single-page apps can take over.
+    Spa { path: String },
+
+    /// Serve the page at `path` with status 404.
+    NotFound { path: String },
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Fallback::None
+    }
+}
+
+/// Where and how to publish a feed of the site's dated pages. The feed is
+/// kept up to date as pages change, as long as `Settings::site_url` is
+/// set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FeedSettings {
+    pub title: String,
+
+    /// Where to publish an Atom feed, relative to the root of the site.
+    /// Empty for none.
+    pub atom_path: String,
+
+    /// Likewise for an RSS feed.
+    pub rss_path: String,
+}
+
+/// Settings which apply to a whole site, configured via the admin UI.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Settings {
+    /// The absolute url the site is published at, which feeds and the
+    /// sitemap need for their links.
+    pub site_url: String,
+
+    /// Only used for requests which accept html, and whose path doesn't
+    /// look like that of some other kind of file, so missing images,
+    /// scripts etc. still get a real 404.
+    pub fallback: Fallback,
+
+    /// The language to serve pages in when we have no translation in any
+    /// of the languages the client asked for. Pages without a language
+    /// are served if this is empty, or the page isn't available in it.
+    pub default_language: String,
+
+    pub feed: FeedSettings,
+}
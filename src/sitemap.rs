@@ -0,0 +1,72 @@
+use crate::{
+    feed::absolute_url,
+    html::escape,
+};
+
+/// Where the generated sitemap is published, relative to the root of the
+/// site.
+pub const SITEMAP_PATH: &str = "sitemap.xml";
+
+/// Where the generated robots.txt is published.
+pub const ROBOTS_PATH: &str = "robots.txt";
+
+/// Write a sitemap listing `pages`, which are pairs of paths (relative to
+/// the root of the site) and the RFC 3339 times they were last modified.
+pub fn sitemap<'a, I>(site_url: &str, pages: I) -> String
+    where I: IntoIterator<Item = (&'a str, &'a str)>
+{
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for (path, modified) in pages {
+        out.push_str("  <url>\n");
+        out.push_str(&format!("    <loc>{}</loc>\n", escape(&absolute_url(site_url, path))));
+        if modified != "" {
+            out.push_str(&format!("    <lastmod>{}</lastmod>\n", escape(modified)));
+        }
+        out.push_str("  </url>\n");
+    }
+    out.push_str("</urlset>\n");
+    out
+}
+
+/// A robots.txt which lets crawlers see everything, and points them at
+/// the sitemap if we know where it is.
+pub fn robots(site_url: &str) -> String {
+    let mut out = String::from("User-agent: *\nAllow: /\n");
+    if site_url != "" {
+        out.push_str(&format!("\nSitemap: {}\n", absolute_url(site_url, SITEMAP_PATH)));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sitemaps_have_absolute_escaped_urls() {
+        let pages = vec![
+            ("index.html", "2020-01-02T03:04:05+00:00"),
+            ("/search.html?q=a&b", ""),
+        ];
+        assert_eq!(sitemap("https://example.com/", pages), "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">
+  <url>
+    <loc>https://example.com/index.html</loc>
+    <lastmod>2020-01-02T03:04:05+00:00</lastmod>
+  </url>
+  <url>
+    <loc>https://example.com/search.html?q=a&amp;b</loc>
+  </url>
+</urlset>
+");
+    }
+
+    #[test]
+    fn robots_txt_points_at_the_sitemap_if_it_can() {
+        assert_eq!(robots(""), "User-agent: *\nAllow: /\n");
+        assert_eq!(robots("https://example.com/blog/"),
+                   "User-agent: *\nAllow: /\n\nSitemap: https://example.com/blog/sitemap.xml\n");
+    }
+}
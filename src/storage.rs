@@ -1,14 +1,25 @@
 use crate::lmdb_web_site;
+use capnp::Error;
 use std::{
-    collections::HashMap,
     io,
     fs,
     path,
 };
 
+/// How many sites' databases `Storage` keeps open by default, besides those
+/// in use.
+pub const DEFAULT_MAX_OPEN: usize = 16;
+
 pub struct Storage {
     path: path::PathBuf,
-    dbs: HashMap<String, lmdb_web_site::LMDBWebSite>,
+
+    /// The sites we have open, least recently used first.
+    dbs: Vec<(String, lmdb_web_site::LMDBWebSite)>,
+
+    /// How many sites to keep open once nothing is using them; see
+    /// `Storage::get()`.
+    max_open: usize,
+    map_size: lmdb_web_site::MapSize,
 }
 
 impl Storage {
@@ -18,36 +29,191 @@ impl Storage {
         // failure we'll hit it later anyway, so ignore the result:
         let _ = fs::create_dir_all(&path);
 
+        // The number of sites to keep open can be overridden with
+        // WEBPUB_MAX_OPEN_SITES:
+        let max_open = std::env::var("WEBPUB_MAX_OPEN_SITES").ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_OPEN);
+
         Storage {
             path: path,
-            dbs: HashMap::new(),
+            dbs: Vec::new(),
+            max_open: max_open,
+            map_size: lmdb_web_site::MapSize::from_env(),
         }
     }
 
-    pub fn get(&mut self, name: &str) -> Result<lmdb_web_site::LMDBWebSite, lmdb::Error> {
-        match self.dbs.get(name) {
-            Some(db) => Ok(db.clone()),
+    /// Get a handle to the site `name`, opening its database if need be.
+    ///
+    /// At most `max_open` databases are kept open; past that, the least
+    /// recently used ones are closed, unless something is still using
+    /// them. Those stay open, since LMDB doesn't allow opening the same
+    /// database twice in one process.
+    pub fn get(&mut self, name: &str) -> Result<lmdb_web_site::LMDBWebSite, Error> {
+        let site = match self.position(name) {
+            Some(i) => self.dbs.remove(i).1,
             None => {
                 let mut path = self.path.clone();
                 path.push(path::Path::new(name));
 
-                let lmdb_site = lmdb_web_site::LMDBWebSite::open(
-                    String::from("site"),
+                lmdb_web_site::LMDBWebSite::open(
                     String::from("http://example.com"),
                     &path,
-                )?;
-                self.dbs.insert(String::from(name), lmdb_site.clone());
-                Ok(lmdb_site)
+                    self.map_size,
+                )?
+            }
+        };
+        self.dbs.push((String::from(name), site.clone()));
+        self.evict();
+        Ok(site)
+    }
+
+    /// Delete the site `name`. Capabilities to it keep working, but
+    /// nothing they store can be seen by anyone else.
+    pub fn delete(&mut self, name: &str) -> Result<(), Error> {
+        if let Some(i) = self.position(name) {
+            self.dbs.remove(i);
+        }
+        let path = self.path.join(name);
+        fs::remove_dir_all(&path)?;
+        remove_if_exists(&lmdb_web_site::sibling(&path, ".lock"))?;
+        Ok(())
+    }
+
+    /// Rename the site `from` to `to`. This fails if the site is in use,
+    /// since the open database would still be under its old name.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        let from_path = self.path.join(from);
+        let to_path = self.path.join(to);
+        if to_path.exists() {
+            return Err(Error::failed(format!("There is already a site named {}", to)))
+        }
+        if let Some(i) = self.position(from) {
+            if self.dbs[i].1.in_use() {
+                return Err(Error::failed(format!("The site {} is in use", from)))
             }
+            self.dbs.remove(i);
         }
+        fs::rename(&from_path, &to_path)?;
+        // The lock is only held while the site is open, so the new name
+        // can start with a fresh one.
+        remove_if_exists(&lmdb_web_site::sibling(&from_path, ".lock"))?;
+        Ok(())
+    }
+
+    /// Compact the database of the site `name`; see
+    /// `LMDBWebSite::compact()`. Cached handles to the site stay valid.
+    pub fn compact(&mut self, name: &str) -> Result<lmdb_web_site::Compaction, capnp::Error> {
+        self.get(name)?.compact()
     }
 
     pub fn list_sites(&self) -> io::Result<Vec<String>> {
-        fs::read_dir(&self.path)?.map(|r| r.map(|item| {
+        let names: io::Result<Vec<String>> = fs::read_dir(&self.path)?.map(|r| r.map(|item| {
             item.path()
                 .file_name().unwrap()
                 .to_os_string()
                 .into_string().unwrap()
-        })).collect()
+        })).collect();
+        // Hidden directories are left over from compacting a site.
+        Ok(names?.into_iter().filter(|name| !name.starts_with('.')).collect())
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.dbs.iter().position(|(open, _)| open == name)
+    }
+
+    /// Close the least recently used sites nothing is using, until no more
+    /// than `max_open` are open.
+    fn evict(&mut self) {
+        let mut excess = self.dbs.len().saturating_sub(self.max_open);
+        self.dbs.retain(|(_, site)| {
+            if excess > 0 && !site.in_use() {
+                excess -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Remove the file at `path`, if there is one.
+fn remove_if_exists(path: &path::Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Storage` in a directory of its own, removed again when dropped.
+    struct TempStorage(Storage);
+
+    impl TempStorage {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("webpub-storage-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempStorage(Storage::new(dir))
+        }
+    }
+
+    impl Drop for TempStorage {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.path);
+        }
+    }
+
+    /// Make a site called `name`, and open it.
+    fn create(storage: &mut Storage, name: &str) -> lmdb_web_site::LMDBWebSite {
+        fs::create_dir(storage.path.join(name)).unwrap();
+        storage.get(name).unwrap()
+    }
+
+    #[test]
+    fn deleting_a_site_closes_it() {
+        let mut storage = TempStorage::new("delete");
+        let storage = &mut storage.0;
+        create(storage, "a");
+        create(storage, "b");
+        storage.delete("a").unwrap();
+        assert_eq!(storage.list_sites().unwrap(), vec!["b"]);
+        assert!(storage.position("a").is_none());
+        assert!(!lmdb_web_site::sibling(&storage.path.join("a"), ".lock").exists());
+
+        // A new site by the same name starts out empty.
+        let site = create(storage, "a");
+        assert!(site.list("", None, 10).unwrap().paths.is_empty());
+        assert!(storage.delete("missing").is_err());
+    }
+
+    #[test]
+    fn renaming_a_site_closes_it() {
+        let mut storage = TempStorage::new("rename");
+        let storage = &mut storage.0;
+        create(storage, "a");
+        storage.rename("a", "b").unwrap();
+        assert_eq!(storage.list_sites().unwrap(), vec!["b"]);
+        assert!(storage.position("a").is_none());
+        storage.get("b").unwrap();
+    }
+
+    #[test]
+    fn renaming_refuses_to_replace_sites_or_move_open_ones() {
+        let mut storage = TempStorage::new("rename-refused");
+        let storage = &mut storage.0;
+        create(storage, "a");
+        create(storage, "b");
+        assert!(storage.rename("a", "b").is_err());
+
+        let site = storage.get("a").unwrap();
+        assert!(storage.rename("a", "c").is_err());
+        drop(site);
+        storage.rename("a", "c").unwrap();
+        let mut names = storage.list_sites().unwrap();
+        names.sort();
+        assert_eq!(names, vec!["b", "c"]);
     }
 }
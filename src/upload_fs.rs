@@ -1,20 +1,127 @@
-use crate::shortcuts;
+use crate::{
+    language,
+    manifest::{self, Manifest},
+    redirects,
+    shortcuts,
+    url_policy::UrlPolicy,
+    web_site_session,
+};
+use capnp::{any_pointer, capability::FromClientHook};
+use futures::{
+    channel::mpsc,
+    future::{self, Either, Future, FutureExt, LocalBoxFuture},
+    sink::SinkExt,
+    stream::{self, FuturesUnordered, Stream, StreamExt},
+};
+use serde::{Serialize, Serializer};
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
     fs,
-    io,
+    os::unix::fs::MetadataExt,
+    io::{self, Read, Seek},
     path,
     result,
+    time,
 };
 use sandstorm::{
-    util_capnp::assignable::setter,
+    util_capnp::assignable::{self, setter},
     web_publishing_capnp::web_site,
 };
 
+pub mod filter;
+pub mod markdown;
+pub mod mime;
+
+#[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Capnp(capnp::Error),
     StripPrefix(path::StripPrefixError),
+    Json(serde_json::Error),
+    Zip(zip::result::ZipError),
+    Ignore(ignore::Error),
+    Glob(globset::Error),
+    Template(tera::Error),
     NonUnicodePath,
+    /// A manifest or archive refers to a file outside of the root of
+    /// the site.
+    PathOutsideRoot(String),
+    /// A `_redirects` file couldn't be parsed.
+    Redirects(String),
+    /// In an archive read from a stream, a file saying how to upload the
+    /// others came after some of them.
+    LateControlFile(String),
+    /// Uploading a particular file failed.
+    File(Box<Failure>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Capnp(e) => write!(f, "{}", e),
+            Error::StripPrefix(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "invalid manifest: {}", e),
+            Error::Zip(e) => write!(f, "{}", e),
+            Error::Ignore(e) => write!(f, "invalid ignore pattern: {}", e),
+            Error::Glob(e) => write!(f, "invalid mime type pattern: {}", e),
+            Error::Template(e) => write!(f, "rendering markdown: {}", e),
+            Error::NonUnicodePath => write!(f, "path is not valid unicode"),
+            Error::PathOutsideRoot(p) => write!(f, "{}: path is outside of the upload", p),
+            Error::Redirects(e) => write!(f, "invalid {}: {}", redirects::FILE_NAME, e),
+            Error::LateControlFile(name) => {
+                write!(f, "{}: must come before the other files when the archive is streamed", name)
+            },
+            Error::File(failure) => write!(f, "{}", failure),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Capnp(e) => Some(e),
+            Error::StripPrefix(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Zip(e) => Some(e),
+            Error::Ignore(e) => Some(e),
+            Error::Glob(e) => Some(e),
+            Error::Template(e) => Some(e),
+            Error::File(failure) => Some(&failure.error),
+            Error::NonUnicodePath |
+            Error::PathOutsideRoot(_) |
+            Error::Redirects(_) |
+            Error::LateControlFile(_) => None,
+        }
+    }
+}
+
+/// A file which could not be uploaded.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Failure {
+    /// The file (or archive member) we were uploading.
+    pub local_path: String,
+
+    /// The path in the site we were uploading it to.
+    pub url_path: String,
+
+    #[serde(serialize_with = "serialize_display")]
+    pub error: Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (to /{}): {}", self.local_path, self.url_path, self.error)
+    }
+}
+
+fn serialize_display<T: fmt::Display, S: Serializer>(value: &T, serializer: S)
+    -> result::Result<S::Ok, S::Error>
+{
+    serializer.collect_str(value)
 }
 
 impl From<io::Error> for Error {
@@ -35,15 +142,727 @@ impl From<path::StripPrefixError> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        Error::Zip(e)
+    }
+}
+
+impl From<ignore::Error> for Error {
+    fn from(e: ignore::Error) -> Self {
+        Error::Ignore(e)
+    }
+}
+
+impl From<globset::Error> for Error {
+    fn from(e: globset::Error) -> Self {
+        Error::Glob(e)
+    }
+}
+
+impl From<tera::Error> for Error {
+    fn from(e: tera::Error) -> Self {
+        Error::Template(e)
+    }
+}
+
 type Result<T> = core::result::Result<T, Error>;
 
+/// What to do with symbolic links found while walking a directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Upload whatever the link points to, as if it were at the link's
+    /// path.
+    Follow,
+
+    /// Leave the link out of the site.
+    Skip,
+
+    /// Store the link as a redirect to the path it points to.
+    Redirect,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> Self {
+        SymlinkPolicy::Follow
+    }
+}
+
+/// Options controlling what gets uploaded, and how.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub filter: filter::Rules,
+
+    pub mime: mime::Rules,
+
+    pub symlinks: SymlinkPolicy,
+
+    /// Follow symlinks which point outside of the directory being
+    /// uploaded. Without this, such links are skipped with a warning.
+    pub allow_outside_root: bool,
+
+    /// The maximum number of uploads to have in flight at once.
+    pub jobs: usize,
+
+    /// Carry on with the rest of the upload if a file fails, recording
+    /// the failure in the report, rather than stopping.
+    pub keep_going: bool,
+
+    /// Which urls files are published at.
+    pub url_policy: UrlPolicy,
+
+    /// If set, render Markdown files to html pages, which are published
+    /// at clean urls regardless of `url_policy.strip_html`.
+    pub markdown: Option<markdown::Options>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            filter: Default::default(),
+            mime: Default::default(),
+            symlinks: Default::default(),
+            allow_outside_root: false,
+            jobs: 4,
+            keep_going: false,
+            url_policy: Default::default(),
+            markdown: None,
+        }
+    }
+}
+
+/// A summary of what happened during an upload.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// The number of paths whose contents were uploaded.
+    pub uploaded: usize,
+
+    /// The total size of the bodies uploaded.
+    pub bytes: u64,
+
+    /// How long the upload took.
+    pub elapsed: time::Duration,
+
+    /// Paths (relative to the root of the upload) which were not uploaded
+    /// because of the filter rules or symlink policy, or because they are
+    /// archive entries we can't upload, like symlinks.
+    pub skipped: Vec<String>,
+
+    /// Problems which caused files to be left out, but which weren't
+    /// serious enough to stop the upload.
+    pub warnings: Vec<String>,
+
+    /// Files which failed to upload. This can only be non-empty if
+    /// `Options::keep_going` was set.
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    /// The upload rate, in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(0.001)
+    }
+
+    fn warn(&mut self, path: &path::Path, msg: &str) {
+        self.warnings.push(format!("{}: {}", path.display(), msg));
+    }
+
+    /// Record that the archive entry `name` was skipped, since it is a
+    /// `kind` rather than a regular file.
+    fn skip_entry(&mut self, name: String, kind: &str) {
+        self.warnings.push(format!("{}: {} in archive; skipping", name, kind));
+        self.skipped.push(name);
+    }
+}
+
 /// Helper for uploading files into a website.
-pub async fn upload_path(path: &path::Path, site: &web_site::Client) -> Result<()> {
+///
+/// If `path` is a directory containing a manifest (as written by the
+/// `export` module), the manifest says exactly what to upload. Otherwise
+/// every file allowed by the filter is uploaded, with mime types guessed
+/// from the extension.
+pub async fn upload_path(path: &path::Path,
+                         site: &web_site::Client,
+                         options: &Options) -> Result<Report> {
+    let start = time::Instant::now();
+    let mut report = Report::default();
+    let manifest_path = path.join(manifest::FILE_NAME);
+    let manifest: Option<Manifest> = if path.is_dir() && manifest_path.is_file() {
+        Some(serde_json::from_slice(&fs::read(manifest_path)?)?)
+    } else {
+        None
+    };
+    let detector = mime::Detector::new(&options.mime)?;
+    let mut uploads = Uploads::new(options);
     if path.is_dir() {
-        upload_dir(path, site).await
+        if let Some(ref manifest) = manifest {
+            upload_manifest(manifest, |file| {
+                Ok(fs::read(path.join(relative_path(path::Path::new(file))?))?)
+            }, site, &mut uploads).await?;
+        } else {
+            let ignore_path = path.join(filter::IGNORE_FILE_NAME);
+            let ignore_file = if ignore_path.is_file() {
+                Some(fs::read_to_string(ignore_path)?)
+            } else {
+                None
+            };
+            let filter = filter::Filter::new(&options.filter, ignore_file.as_ref().map(|s| &s[..]))?;
+            let layout_path = path.join(markdown::LAYOUT_FILE_NAME);
+            let layout = if options.markdown.is_some() && layout_path.is_file() {
+                Some(fs::read_to_string(layout_path)?)
+            } else {
+                None
+            };
+            let renderer = new_renderer(options, layout.as_ref().map(|s| &s[..]))?;
+            let context = Context {
+                filter: &filter,
+                detector: &detector,
+                renderer: renderer.as_ref(),
+                options: options,
+            };
+            let mut pages = Pages::default();
+            upload_dir(path, site, &context, &mut report, &mut pages, &mut uploads).await?;
+            upload_pages(pages, site, options.url_policy, &mut uploads).await?;
+        }
+    } else if path.is_file() {
+        let contents = fs::read(path)?;
+        let size = contents.len();
+        let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let mime_type = detector.detect(&file_name, &contents);
+        let policy = options.url_policy;
+        uploads.push(async move {
+            upload_contents(UrlPath::from_str(""), &mime_type, &contents, policy, site).await
+        }, size, path.display().to_string(), String::new()).await?;
     } else {
-        upload_file(path, path, site).await
+        report.warn(path, "not a regular file");
     }
+    uploads.finish(&mut report).await?;
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Upload the contents of an archive, without unpacking it to disk. The
+/// archive may be a tar file (optionally gzipped) or a zip file; the
+/// format is detected automatically. Paths in the archive are taken to be
+/// relative to the root of the site, and are otherwise treated just like
+/// files in a directory passed to `upload_path`. Entries which aren't
+/// regular files or directories, like symlinks, are skipped.
+///
+/// The archive is read twice: first for the files which say how to upload
+/// the rest (the manifest, ignore file and layout), then to upload
+/// everything, one file at a time, so only the uploads in flight are held
+/// in memory. Use `upload_stream()` for archives which can't be seeked.
+pub async fn upload_archive<R>(mut reader: R,
+                               site: &web_site::Client,
+                               options: &Options) -> Result<Report>
+    where R: Read + io::Seek + Send + 'static
+{
+    let start = time::Instant::now();
+    let mut control_files = BTreeMap::new();
+    scan_archive(&mut reader, |name, kind, contents| {
+        if kind == EntryKind::File && is_control_file(&name, options) {
+            let mut bytes = vec![];
+            contents.read_to_end(&mut bytes)?;
+            control_files.insert(name, bytes);
+        }
+        Ok(())
+    })?;
+    reader.seek(io::SeekFrom::Start(0))?;
+    let entries = read_entries(move |f| scan_archive(reader, f), options.jobs.max(1));
+    upload_entries(control_files, entries, false, site, options, start).await
+}
+
+/// Like `upload_archive()`, but the archive is read just once, as it
+/// arrives, so it can come from a pipe. For that, the files which say how
+/// to upload the rest must come first in the archive; it is an error for
+/// one to turn up after any other file.
+///
+/// Zip archives keep their index at the end, so they can't be read this
+/// way. They are copied to a temporary file with `spool()` first.
+pub async fn upload_stream<R>(mut reader: R,
+                              site: &web_site::Client,
+                              options: &Options) -> Result<Report>
+    where R: Read + Send + 'static
+{
+    let start = time::Instant::now();
+    let mut magic = vec![];
+    (&mut reader).take(4).read_to_end(&mut magic)?;
+    let reader = io::Cursor::new(magic.clone()).chain(reader);
+    if magic.starts_with(ZIP_MAGIC) {
+        return upload_archive(spool(reader)?, site, options).await
+    }
+    let mut entries = read_entries(move |f| {
+        if magic.starts_with(GZIP_MAGIC) {
+            scan_tar(flate2::read::GzDecoder::new(reader), f)
+        } else {
+            scan_tar(reader, f)
+        }
+    }, options.jobs.max(1));
+    let mut control_files = BTreeMap::new();
+    let first = loop {
+        match entries.next().await {
+            Some(Ok((name, Entry::File(contents)))) if is_control_file(&name, options) => {
+                control_files.insert(name, contents);
+            },
+            entry => break entry,
+        }
+    };
+    let entries = stream::iter(first).chain(entries);
+    upload_entries(control_files, entries, true, site, options, start).await
+}
+
+/// Whether `name` is one of the files in an archive which say how to
+/// upload the rest. The layout is only one of them when rendering
+/// Markdown; otherwise it is just another page.
+fn is_control_file(name: &str, options: &Options) -> bool {
+    name == manifest::FILE_NAME
+        || name == filter::IGNORE_FILE_NAME
+        || (name == markdown::LAYOUT_FILE_NAME && options.markdown.is_some())
+}
+
+/// Upload `entries`, from an archive whose control files (see
+/// `is_control_file()`) are `control_files`. If `streamed`, those were
+/// taken from the start of the archive, and mustn't turn up again.
+async fn upload_entries<S>(control_files: BTreeMap<String, Vec<u8>>,
+                           mut entries: S,
+                           streamed: bool,
+                           site: &web_site::Client,
+                           options: &Options,
+                           start: time::Instant) -> Result<Report>
+    where S: Stream<Item = Result<(String, Entry)>> + Unpin
+{
+    let mut report = Report::default();
+    let manifest: Option<Manifest> = match control_files.get(manifest::FILE_NAME) {
+        Some(manifest_bytes) => Some(serde_json::from_slice(manifest_bytes)?),
+        None => None,
+    };
+    let detector = mime::Detector::new(&options.mime)?;
+    let mut uploads = Uploads::new(options);
+    match manifest {
+        Some(ref manifest) => {
+            let mut pending = PendingPaths::new(manifest);
+            for (url_path, entities, bodies) in pending.take_complete() {
+                push_manifest_path(url_path, entities, bodies, site, &mut uploads).await?;
+            }
+            while let Some(entry) = uploads.alongside(entries.next()).await? {
+                let (name, entry) = entry?;
+                if streamed && is_control_file(&name, options) {
+                    return Err(Error::LateControlFile(name))
+                }
+                match entry {
+                    Entry::File(contents) => {
+                        pending.add_file(&name, contents);
+                        for (url_path, entities, bodies) in pending.take_complete() {
+                            push_manifest_path(url_path, entities, bodies, site, &mut uploads).await?;
+                        }
+                    },
+                    Entry::Unsupported(kind) => report.skip_entry(name, kind),
+                }
+            }
+            for (url_path, file) in pending.missing() {
+                uploads.fail(String::from(file), String::from(url_path), Error::Io(
+                    io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the archive", file))
+                ))?;
+            }
+        },
+        None => {
+            let ignore_file = match control_files.get(filter::IGNORE_FILE_NAME) {
+                Some(bytes) => Some(std::str::from_utf8(bytes).map_err(|_| Error::NonUnicodePath)?),
+                None => None,
+            };
+            let filter = filter::Filter::new(&options.filter, ignore_file)?;
+            let layout = match control_files.get(markdown::LAYOUT_FILE_NAME) {
+                Some(bytes) if options.markdown.is_some() => {
+                    Some(std::str::from_utf8(bytes).map_err(|e| {
+                        Error::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+                    })?)
+                },
+                _ => None,
+            };
+            let renderer = new_renderer(options, layout)?;
+            let mut pages = Pages::default();
+            while let Some(entry) = uploads.alongside(entries.next()).await? {
+                let (name, entry) = entry?;
+                if streamed && is_control_file(&name, options) {
+                    return Err(Error::LateControlFile(name))
+                }
+                let contents = match entry {
+                    Entry::File(contents) => contents,
+                    Entry::Unsupported(kind) => {
+                        report.skip_entry(name, kind);
+                        continue
+                    },
+                };
+                if !filter.allows_with_parents(path::Path::new(&name)) {
+                    report.skipped.push(name);
+                    continue
+                }
+                if renderer.is_some() && name == markdown::LAYOUT_FILE_NAME {
+                    report.skipped.push(name);
+                    continue
+                }
+                let mime_type = detector.detect(&name, &contents);
+                if is_page(renderer.as_ref(), &name) {
+                    if let Err(e) = add_page(&mut pages,
+                                             renderer.as_ref(),
+                                             &name,
+                                             mime_type,
+                                             contents,
+                                             name.clone()) {
+                        uploads.fail(name.clone(), name, e)?;
+                    }
+                    continue
+                }
+                add_untagged(&mut pages, &name);
+                let policy = options.url_policy;
+                let size = contents.len();
+                let url_path = name.clone();
+                uploads.push(async move {
+                    upload_contents(UrlPath::from_str(&url_path), &mime_type, &contents, policy, site).await
+                },
+                             size,
+                             name.clone(),
+                             name).await?;
+            }
+            upload_pages(pages, site, options.url_policy, &mut uploads).await?;
+        },
+    }
+    uploads.finish(&mut report).await?;
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Copy everything from `reader` into an anonymous temporary file, so it
+/// can be passed to `upload_archive()`.
+pub fn spool<R: Read>(mut reader: R) -> io::Result<fs::File> {
+    let path = std::env::temp_dir().join(format!("webpub-upload-{}", std::process::id()));
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    // We have the file open, so it will stick around until we close it.
+    fs::remove_file(&path)?;
+    io::copy(&mut reader, &mut file)?;
+    file.seek(io::SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// The files a manifest refers to which we haven't seen yet, while
+/// uploading an archive. Each path is uploaded once all of its files have
+/// turned up.
+struct PendingPaths<'a> {
+    /// The paths each file is used by, and for which of their entities.
+    owners: HashMap<&'a str, Vec<(&'a str, usize)>>,
+    paths: BTreeMap<&'a str, PendingPath<'a>>,
+}
+
+struct PendingPath<'a> {
+    entities: &'a [manifest::Entity],
+    bodies: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+impl<'a> PendingPaths<'a> {
+    fn new(manifest: &'a Manifest) -> Self {
+        let mut pending = PendingPaths {
+            owners: HashMap::new(),
+            paths: BTreeMap::new(),
+        };
+        for (url_path, entities) in manifest.paths.iter() {
+            let mut missing = 0;
+            for (i, entity) in entities.iter().enumerate() {
+                if let Some(ref file) = entity.file {
+                    pending.owners.entry(&file[..]).or_default().push((&url_path[..], i));
+                    missing += 1;
+                }
+            }
+            pending.paths.insert(url_path, PendingPath {
+                entities: entities,
+                bodies: vec![None; entities.len()],
+                missing: missing,
+            });
+        }
+        pending
+    }
+
+    fn add_file(&mut self, file: &str, contents: Vec<u8>) {
+        if let Some(owners) = self.owners.remove(file) {
+            for (url_path, i) in owners {
+                if let Some(path) = self.paths.get_mut(url_path) {
+                    path.bodies[i] = Some(contents.clone());
+                    path.missing -= 1;
+                }
+            }
+        }
+    }
+
+    /// Remove and return the paths which have all of their files.
+    fn take_complete(&mut self) -> Vec<(&'a str, &'a [manifest::Entity], Vec<Option<Vec<u8>>>)> {
+        let complete: Vec<&'a str> = self.paths.iter()
+            .filter(|(_, path)| path.missing == 0)
+            .map(|(url_path, _)| *url_path)
+            .collect();
+        complete.into_iter().filter_map(|url_path| {
+            self.paths.remove(url_path).map(|path| (url_path, path.entities, path.bodies))
+        }).collect()
+    }
+
+    /// The paths still waiting on files, along with one of the files each
+    /// is missing.
+    fn missing(&self) -> Vec<(&'a str, &'a str)> {
+        self.paths.iter().filter_map(|(url_path, path)| {
+            path.entities.iter().zip(path.bodies.iter())
+                .find(|(entity, body)| entity.file.is_some() && body.is_none())
+                .and_then(|(entity, _)| entity.file.as_ref())
+                .map(|file| (*url_path, &file[..]))
+        }).collect()
+    }
+}
+
+/// Uploads which are in flight. We keep up to `max` of them going at once,
+/// so we aren't waiting on a round trip for every file.
+struct Uploads<'a> {
+    in_flight: FuturesUnordered<LocalBoxFuture<'a, Result<u64>>>,
+    max: usize,
+    keep_going: bool,
+    count: usize,
+    bytes: u64,
+    failures: Vec<Failure>,
+}
+
+impl<'a> Uploads<'a> {
+    fn new(options: &Options) -> Self {
+        Uploads {
+            in_flight: FuturesUnordered::new(),
+            max: options.jobs.max(1),
+            keep_going: options.keep_going,
+            count: 0,
+            bytes: 0,
+            failures: vec![],
+        }
+    }
+
+    /// Start `upload`, which sends `bytes` bytes of content from
+    /// `local_path` to `url_path`. If there are already `max` uploads in
+    /// flight, first wait for one to finish.
+    async fn push<F>(&mut self,
+                     upload: F,
+                     bytes: usize,
+                     local_path: String,
+                     url_path: String) -> Result<()>
+        where F: Future<Output = Result<()>> + 'a
+    {
+        while self.in_flight.len() >= self.max {
+            if let Some(result) = self.in_flight.next().await {
+                self.done(result)?;
+            }
+        }
+        self.in_flight.push(upload.map(move |result| match result {
+            Ok(()) => Ok(bytes as u64),
+            Err(e) => Err(Error::File(Box::new(Failure {
+                local_path: local_path,
+                url_path: url_path,
+                error: e,
+            }))),
+        }).boxed_local());
+        // Get the new upload started (and collect any which are done) now,
+        // rather than whenever we next have to wait.
+        while let Some(Some(result)) = self.in_flight.next().now_or_never() {
+            self.done(result)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for `waiting`, while keeping the uploads in flight going.
+    async fn alongside<F: Future>(&mut self, waiting: F) -> Result<F::Output> {
+        futures::pin_mut!(waiting);
+        loop {
+            if self.in_flight.is_empty() {
+                return Ok(waiting.await)
+            }
+            match future::select(&mut waiting, self.in_flight.next()).await {
+                Either::Left((output, _)) => return Ok(output),
+                Either::Right((Some(result), _)) => self.done(result)?,
+                Either::Right((None, _)) => {},
+            }
+        }
+    }
+
+    /// Record an error that happened while uploading `local_path` to
+    /// `url_path`. Unless we're keeping going, this returns the error.
+    fn fail(&mut self, local_path: String, url_path: String, error: Error) -> Result<()> {
+        self.done(Err(Error::File(Box::new(Failure {
+            local_path: local_path,
+            url_path: url_path,
+            error: error,
+        }))))
+    }
+
+    fn done(&mut self, result: Result<u64>) -> Result<()> {
+        match result {
+            Ok(bytes) => {
+                self.count += 1;
+                self.bytes += bytes;
+                Ok(())
+            },
+            Err(Error::File(failure)) if self.keep_going => {
+                self.failures.push(*failure);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait for the uploads in flight to finish.
+    async fn flush(&mut self) -> Result<()> {
+        while let Some(result) = self.in_flight.next().await {
+            self.done(result)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for all of the uploads to finish, and record how they went in
+    /// `report`.
+    async fn finish(mut self, report: &mut Report) -> Result<()> {
+        self.flush().await?;
+        report.uploaded += self.count;
+        report.bytes += self.bytes;
+        report.failures.append(&mut self.failures);
+        Ok(())
+    }
+}
+
+/// What kind of thing an entry in an archive is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EntryKind {
+    File,
+
+    /// Anything else but a directory, described for the report.
+    Other(&'static str),
+}
+
+/// An entry in an archive, as passed on by `read_entries`.
+enum Entry {
+    File(Vec<u8>),
+    Unsupported(&'static str),
+}
+
+/// The first bytes of a zip file.
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// The first bytes of a gzipped file.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Call `f` on each entry in an archive, other than directories, with its
+/// (normalized) path, its kind, and a reader for its contents.
+fn scan_archive<R, F>(mut reader: R, mut f: F) -> Result<()>
+    where R: Read + io::Seek,
+          F: FnMut(String, EntryKind, &mut dyn Read) -> Result<()>
+{
+    let mut magic = vec![];
+    (&mut reader).take(4).read_to_end(&mut magic)?;
+    reader.seek(io::SeekFrom::Start(0))?;
+    if magic.starts_with(ZIP_MAGIC) {
+        let mut archive = zip::ZipArchive::new(reader)?;
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if file.is_dir() {
+                continue
+            }
+            // Zip files made on unix record the file's type in its mode.
+            let kind = match file.unix_mode().map(|mode| mode & 0o170000) {
+                None | Some(0) | Some(0o100000) => EntryKind::File,
+                Some(0o120000) => EntryKind::Other("symlink"),
+                Some(_) => EntryKind::Other("special file"),
+            };
+            let name = path_str(&relative_path(path::Path::new(file.name()))?)?.to_string();
+            f(name, kind, &mut file)?;
+        }
+        Ok(())
+    } else if magic.starts_with(GZIP_MAGIC) {
+        scan_tar(flate2::read::GzDecoder::new(reader), f)
+    } else {
+        scan_tar(reader, f)
+    }
+}
+
+fn scan_tar<R, F>(reader: R, mut f: F) -> Result<()>
+    where R: Read,
+          F: FnMut(String, EntryKind, &mut dyn Read) -> Result<()>
+{
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        let kind = if entry_type.is_file() {
+            EntryKind::File
+        } else if entry_type.is_dir() || entry_type.is_pax_global_extensions() {
+            continue
+        } else if entry_type.is_symlink() {
+            EntryKind::Other("symlink")
+        } else if entry_type.is_hard_link() {
+            EntryKind::Other("hard link")
+        } else {
+            EntryKind::Other("special file")
+        };
+        let name = path_str(&relative_path(&entry.path()?)?)?.to_string();
+        f(name, kind, &mut entry)?;
+    }
+    Ok(())
+}
+
+/// Read the entries of an archive on another thread, passing them on as
+/// they are read. `scan` reads the archive, calling the function it is
+/// given on each entry, like `scan_archive()`. No more than `buffer`
+/// entries are read ahead of the receiver.
+fn read_entries<S>(scan: S, buffer: usize) -> mpsc::Receiver<Result<(String, Entry)>>
+    where S: FnOnce(&mut dyn FnMut(String, EntryKind, &mut dyn Read) -> Result<()>) -> Result<()>
+             + Send + 'static
+{
+    let (mut sender, receiver) = mpsc::channel(buffer);
+    std::thread::spawn(move || {
+        let result = scan(&mut |name, kind, contents: &mut dyn Read| {
+            let entry = match kind {
+                EntryKind::File => {
+                    let mut bytes = vec![];
+                    contents.read_to_end(&mut bytes)?;
+                    Entry::File(bytes)
+                },
+                EntryKind::Other(kind) => Entry::Unsupported(kind),
+            };
+            // If the receiver is gone, the upload has stopped, so there's
+            // no point reading further.
+            futures::executor::block_on(sender.send(Ok((name, entry))))
+                .map_err(|_| Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "upload stopped")))
+        });
+        if let Err(e) = result {
+            let _ = futures::executor::block_on(sender.send(Err(e)));
+        }
+    });
+    receiver
+}
+
+/// Normalize a path which is supposed to be relative to the root of the
+/// site, dropping any `.` components. Fails if the path could refer to
+/// something outside the root.
+fn relative_path(p: &path::Path) -> Result<path::PathBuf> {
+    let mut ret = path::PathBuf::new();
+    for component in p.components() {
+        match component {
+            path::Component::Normal(part) => ret.push(part),
+            path::Component::CurDir => (),
+            _ => return Err(Error::PathOutsideRoot(p.to_string_lossy().into_owned())),
+        }
+    }
+    Ok(ret)
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -64,37 +883,158 @@ impl <'a> UrlPath<'a> {
     fn from_str(s: &'a str) -> Self {
         UrlPath { s: s }
     }
+
+    /// Whether this is the path of a file of redirect rules.
+    fn is_redirects_file(&self) -> bool {
+        self.s == redirects::FILE_NAME || self.s.ends_with(&format!("/{}", redirects::FILE_NAME))
+    }
 }
 
-fn guess_mime_type(path: &path::Path) -> Result<String> {
-    let ext = match path.extension() {
-        None => {
-            return Ok(String::from("application/octet-stream"))
-        },
-        Some(os_str) => {
-            os_str.to_str().ok_or(Error::NonUnicodePath)?
-        }
-    };
-    let mime = mime_guess::from_ext(ext).first_or_octet_stream();
-    Ok(String::from(mime.essence_str()))
+/// Identifies a directory, so we can detect symlink loops.
+pub(crate) type DirId = (u64, u64);
+
+pub(crate) fn dir_id(metadata: &fs::Metadata) -> DirId {
+    (metadata.dev(), metadata.ino())
 }
 
-async fn upload_dir(root: &path::Path,
-                    site: &web_site::Client) -> Result<()> {
-    let path = root.to_path_buf();
+/// The rules for a walk of a directory by `upload_dir`.
+struct Context<'r> {
+    filter: &'r filter::Filter,
+    detector: &'r mime::Detector,
+    renderer: Option<&'r markdown::Renderer>,
+    options: &'r Options,
+}
+
+async fn upload_dir<'a>(root: &path::Path,
+                        site: &'a web_site::Client,
+                        context: &Context<'_>,
+                        report: &mut Report,
+                        pages: &mut Pages,
+                        uploads: &mut Uploads<'a>) -> Result<()> {
+    let real_root = fs::canonicalize(root)?;
     // Use an explicit stack to recursively walk the file tree, because
     // I(zenhack) can't figure out how to write a recursive async function.
-    let mut stack = vec![path];
+    // Alongside each directory we keep the ids of it and its ancestors; if
+    // we find one of those again, we've hit a loop.
+    let mut stack = vec![(root.to_path_buf(), vec![dir_id(&fs::metadata(root)?)])];
     loop {
         match stack.pop() {
             None => break,
-            Some(path) => {
-                if path.is_dir() {
-                    for entry in fs::read_dir(path)? {
-                        stack.push(entry?.path())
+            Some((dir, ancestors)) => {
+                let entries = match fs::read_dir(&dir) {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        report.warn(&dir, "permission denied");
+                        continue
+                    },
+                    Err(e) => return Err(e.into()),
+                };
+                for entry in entries {
+                    let path = entry?.path();
+                    let rel_path = path.strip_prefix(root)?;
+                    let mut metadata = fs::symlink_metadata(&path)?;
+                    if metadata.file_type().is_symlink() {
+                        if context.options.symlinks == SymlinkPolicy::Skip {
+                            report.skipped.push(path_str(rel_path)?.to_string());
+                            continue
+                        }
+                        let target = match fs::canonicalize(&path) {
+                            Ok(target) => target,
+                            Err(_) => {
+                                report.warn(rel_path, "broken symlink");
+                                continue
+                            },
+                        };
+                        let inside_root = target.starts_with(&real_root);
+                        let may_follow = context.options.symlinks == SymlinkPolicy::Follow
+                            && context.options.allow_outside_root;
+                        if !inside_root && !may_follow {
+                            report.warn(rel_path, "symlink points outside of the upload");
+                            continue
+                        }
+                        metadata = fs::metadata(&target)?;
+                        if context.options.symlinks == SymlinkPolicy::Redirect {
+                            if !context.filter.allows(rel_path, metadata.is_dir()) {
+                                report.skipped.push(path_str(rel_path)?.to_string());
+                                continue
+                            }
+                            let from = String::from(UrlPath::new(root, &path)?.to_str());
+                            // Point the redirect at wherever the target itself
+                            // gets published, so we don't chain redirects.
+                            let mut to = String::from(path_str(target.strip_prefix(&real_root)?)?);
+                            if metadata.is_dir() {
+                                to = if to.is_empty() {
+                                    String::from("index.html")
+                                } else {
+                                    to + "/index.html"
+                                };
+                            }
+                            let to = if context.renderer.is_some() && markdown::is_markdown(&to) {
+                                let policy = UrlPolicy { strip_html: true, ..context.options.url_policy };
+                                policy.place(&markdown::html_path(&to)).canonical
+                            } else {
+                                context.options.url_policy.place(&to).canonical
+                            };
+                            let local_path = path.display().to_string();
+                            let url_path = from.clone();
+                            uploads.push(async move {
+                                upload_redirect(UrlPath::from_str(&from),
+                                                UrlPath::from_str(&to),
+                                                site).await
+                            }, 0, local_path, url_path).await?;
+                            continue
+                        }
+                    }
+                    let is_layout = context.renderer.is_some()
+                        && rel_path == path::Path::new(markdown::LAYOUT_FILE_NAME);
+                    if !context.filter.allows(rel_path, metadata.is_dir()) || is_layout {
+                        report.skipped.push(path_str(rel_path)?.to_string());
+                    } else if metadata.is_dir() {
+                        let id = dir_id(&metadata);
+                        if ancestors.contains(&id) {
+                            report.warn(rel_path, "symlink loop");
+                        } else {
+                            let mut dir_ancestors = ancestors.clone();
+                            dir_ancestors.push(id);
+                            stack.push((path.clone(), dir_ancestors));
+                        }
+                    } else if metadata.is_file() {
+                        let url_path = String::from(UrlPath::new(root, &path)?.to_str());
+                        let local_path = path.display().to_string();
+                        match fs::read(&path) {
+                            Ok(contents) => {
+                                let size = contents.len();
+                                let upload_path = url_path.clone();
+                                let mime_type = context.detector.detect(&url_path, &contents);
+                                if is_page(context.renderer, &url_path) {
+                                    if let Err(e) = add_page(pages,
+                                                             context.renderer,
+                                                             &url_path,
+                                                             mime_type,
+                                                             contents,
+                                                             local_path.clone()) {
+                                        uploads.fail(local_path, url_path, e)?;
+                                    }
+                                    continue
+                                }
+                                add_untagged(pages, &url_path);
+                                let policy = context.options.url_policy;
+                                uploads.push(async move {
+                                    upload_contents(UrlPath::from_str(&upload_path),
+                                                    &mime_type,
+                                                    &contents,
+                                                    policy,
+                                                    site).await
+                                }, size, local_path, url_path).await?;
+                            },
+                            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                                report.warn(rel_path, "permission denied");
+                            },
+                            Err(e) => uploads.fail(local_path, url_path, e.into())?,
+                        }
+                    } else {
+                        report.warn(rel_path, "not a regular file or directory; skipping");
                     }
-                } else {
-                    upload_file(&root, &path, site).await?;
                 }
             }
         }
@@ -106,49 +1046,230 @@ fn path_str(p: &path::Path) -> Result<&str> {
     p.to_str().ok_or(Error::NonUnicodePath)
 }
 
-async fn upload_file(root: &path::Path,
-                     path: &path::Path,
-                     site: &web_site::Client) -> Result<()> {
-    let mime_type = guess_mime_type(path)?;
-    if path.ends_with("index.html") {
-        let parent = UrlPath::new(root, path.parent().expect("non-empty path"))?;
-        let parent_string_with_slash = String::from(parent.to_str()) + "/";
-        let parent_with_slash = UrlPath::from_str(&parent_string_with_slash);
-        upload_file_contents(&mime_type,
-                             path,
-                             parent_with_slash,
-                             site).await?;
-        upload_redirect(parent,
-                        parent_with_slash,
+/// Upload `contents` as the file at `url_path`, publishing it wherever
+/// `policy` says and redirecting the other spellings of its url there.
+async fn upload_contents<'a>(url_path: UrlPath<'a>,
+                             mime_type: &str,
+                             contents: &[u8],
+                             policy: UrlPolicy,
+                             site: &web_site::Client) -> Result<()> {
+    if url_path.is_redirects_file() {
+        // The server checks this too, but we can give a clearer error.
+        let text = std::str::from_utf8(contents).map_err(|e| Error::Redirects(e.to_string()))?;
+        redirects::Rules::parse(text).map_err(Error::Redirects)?;
+        return upload_file_contents(mime_type, contents, url_path, site).await
+    }
+    let placement = policy.place(url_path.to_str());
+    let canonical = UrlPath::from_str(&placement.canonical);
+    upload_file_contents(mime_type,
+                         contents,
+                         canonical,
+                         site).await?;
+    for alias in placement.aliases.iter() {
+        upload_redirect(UrlPath::from_str(alias),
+                        canonical,
                         site).await?;
-        upload_redirect(UrlPath::new(root, path)?,
-                        parent_with_slash,
-                        site).await
-    } else {
-        upload_file_contents(&mime_type,
-                             path,
-                             UrlPath::new(root, path)?,
-                             site).await
     }
+    Ok(())
+}
+
+/// One language variant of a page.
+struct Variant {
+    /// Empty if the page doesn't say what language it is in.
+    language: String,
+    mime_type: String,
+    contents: Vec<u8>,
+    local_path: String,
+
+    /// Rendered from Markdown, so the page has no `.html` url of its own
+    /// to keep.
+    rendered: bool,
+}
+
+/// The pages found during an upload. We upload these once we've found them
+/// all, since every variant of a page has to be set at once.
+#[derive(Default)]
+struct Pages {
+    /// The variants of each page, keyed by its path without any language
+    /// tag.
+    variants: BTreeMap<String, Vec<Variant>>,
+
+    /// The pages without a language tag which were uploaded by themselves.
+    /// Most pages have no other variants, so we don't hold on to these; if
+    /// one does, it is fetched back from the site to be set along with the
+    /// others.
+    untagged: HashSet<String>,
+}
+
+fn new_renderer(options: &Options, site_layout: Option<&str>) -> Result<Option<markdown::Renderer>> {
+    match options.markdown {
+        Some(ref markdown) => Ok(Some(markdown::Renderer::new(markdown, site_layout)?)),
+        None => Ok(None),
+    }
+}
+
+/// Whether the file at `url_path` should go in `Pages`, rather than being
+/// uploaded by itself: Markdown to be rendered, and pages tagged with a
+/// language.
+fn is_page(renderer: Option<&markdown::Renderer>, url_path: &str) -> bool {
+    (renderer.is_some() && markdown::is_markdown(url_path))
+        || language::split_variant(url_path).map_or(false, |(_, lang)| !lang.is_empty())
+}
+
+/// Note that the file at `url_path` is being uploaded by itself, in case
+/// it is the untagged variant of a page which turns out to have others.
+fn add_untagged(pages: &mut Pages, url_path: &str) {
+    if language::split_variant(url_path).is_some() {
+        pages.untagged.insert(String::from(url_path));
+    }
+}
+
+/// Add the file at `url_path` to `pages`, rendering it first if it is
+/// Markdown (and `renderer` is given).
+fn add_page(pages: &mut Pages,
+            renderer: Option<&markdown::Renderer>,
+            url_path: &str,
+            mime_type: String,
+            contents: Vec<u8>,
+            local_path: String) -> Result<()> {
+    let renderer = match renderer {
+        Some(renderer) if markdown::is_markdown(url_path) => renderer,
+        _ => {
+            if let Some((page, language)) = language::split_variant(url_path) {
+                pages.variants.entry(page).or_default().push(Variant {
+                    language: language,
+                    mime_type: mime_type,
+                    contents: contents,
+                    local_path: local_path,
+                    rendered: false,
+                });
+            }
+            return Ok(())
+        },
+    };
+    let html = renderer.render(url_path, &String::from_utf8_lossy(&contents))?;
+    let html_path = markdown::html_path(url_path);
+    let (page, language) = language::split_variant(&html_path).unwrap_or((html_path, String::new()));
+    let variants = pages.variants.entry(page).or_default();
+    variants.push(Variant {
+        language: language.clone(),
+        mime_type: String::from("text/html; charset=utf-8"),
+        contents: html.into_bytes(),
+        local_path: local_path.clone(),
+        rendered: true,
+    });
+    if renderer.keep_source {
+        variants.push(Variant {
+            language: language,
+            mime_type: String::from("text/markdown; charset=utf-8"),
+            contents: contents,
+            local_path: local_path,
+            rendered: true,
+        });
+    }
+    Ok(())
+}
+
+async fn upload_pages<'a>(pages: Pages,
+                          site: &'a web_site::Client,
+                          policy: UrlPolicy,
+                          uploads: &mut Uploads<'a>) -> Result<()> {
+    let Pages { variants: pages, untagged } = pages;
+    let lacks_untagged = |url_path: &String, variants: &[Variant]| {
+        untagged.contains(url_path) && !variants.iter().any(|variant| variant.language.is_empty())
+    };
+    if pages.iter().any(|(url_path, variants)| lacks_untagged(url_path, variants)) {
+        // The untagged variants have to be in the site before we can
+        // fetch them back.
+        uploads.flush().await?;
+    }
+    for (url_path, mut variants) in pages {
+        if lacks_untagged(&url_path, &variants) {
+            variants.extend(fetch_untagged(&url_path, policy, site).await?);
+        }
+        let policy = if variants.iter().any(|variant| variant.rendered) {
+            UrlPolicy { strip_html: true, ..policy }
+        } else {
+            policy
+        };
+        let size = variants.iter().map(|variant| variant.contents.len()).sum();
+        let local_path = variants.iter()
+            .map(|variant| &variant.local_path[..])
+            .collect::<Vec<_>>()
+            .join(", ");
+        let upload_path = url_path.clone();
+        uploads.push(async move {
+            upload_page(UrlPath::from_str(&upload_path), &variants, policy, site).await
+        }, size, local_path, url_path).await?;
+    }
+    Ok(())
+}
+
+/// Fetch back the page without a language tag which was uploaded by itself
+/// to `url_path`, so it can be set again along with the page's variants.
+async fn fetch_untagged(url_path: &str, policy: UrlPolicy, site: &web_site::Client) -> Result<Option<Variant>> {
+    let response = web_site_session::lookup(site, &policy.place(url_path).canonical).await?;
+    for entity in response.get()?.get_value()?.iter() {
+        if entity.get_language()? != "" {
+            continue
+        }
+        let contents = match entity.get_body().which()? {
+            web_site::entity::body::Bytes(bytes) => bytes?.to_vec(),
+            web_site::entity::body::Blob(_) => continue,
+        };
+        return Ok(Some(Variant {
+            language: String::new(),
+            mime_type: String::from(entity.get_mime_type()?),
+            contents: contents,
+            local_path: String::from(url_path),
+            rendered: false,
+        }))
+    }
+    Ok(None)
+}
+
+/// Like `upload_contents`, but for all of the variants of a page at once.
+async fn upload_page<'a>(url_path: UrlPath<'a>,
+                         variants: &[Variant],
+                         policy: UrlPolicy,
+                         site: &web_site::Client) -> Result<()> {
+    let placement = policy.place(url_path.to_str());
+    let canonical = UrlPath::from_str(&placement.canonical);
+    let mut req = setter_for_path(canonical, site).set_request();
+    let mut entities = req.get().initn_value(variants.len() as u32);
+    for (i, variant) in variants.iter().enumerate() {
+        let mut entity = entities.reborrow().get(i as u32);
+        entity.reborrow().get_body().set_bytes(&variant.contents);
+        entity.set_mime_type(&variant.mime_type);
+        entity.set_language(&variant.language);
+    }
+    req.send().promise.await?.get()?;
+    for alias in placement.aliases.iter() {
+        upload_redirect(UrlPath::from_str(alias),
+                        canonical,
+                        site).await?;
+    }
+    Ok(())
 }
 
-async fn setter_for_path<'a>(url_path: UrlPath<'a>, site: &web_site::Client)
-    -> result::Result<setter::Client<shortcuts::entity_list::Owned>, capnp::Error>
+fn setter_for_path<'a>(url_path: UrlPath<'a>, site: &web_site::Client)
+    -> setter::Client<shortcuts::entity_list::Owned>
 {
     let mut req = site.get_entities_request();
     req.get().set_path(url_path.to_str());
-    req.send()
-        .pipeline.get_entities()
-        .as_setter_request().send()
-        // We should be able to pipeline this, but I(zenhack) am getting an error
-        // I don't understand:
-        .promise.await?.get()?.get_setter()
+    // The generated pipeline only has `get_setter()` when the list type is
+    // `Pipelined`, which lists aren't; so ask for the setter as an
+    // `AnyPointer` setter, and give it back its type afterwards.
+    let entities: assignable::Client<any_pointer::Owned> =
+        FromClientHook::new(req.send().pipeline.get_entities().client.hook);
+    let setter = entities.as_setter_request().send().pipeline.get_setter();
+    FromClientHook::new(setter.client.hook)
 }
 
 async fn upload_redirect<'a>(from: UrlPath<'a>,
                              to: UrlPath<'a>,
                              site: &web_site::Client) -> Result<()> {
-    let mut req = setter_for_path(from, site).await?.set_request();
+    let mut req = setter_for_path(from, site).set_request();
     let entities = req.get().initn_value(1);
     let mut entity = entities.get(0);
     entity.set_redirect_to(to.to_str());
@@ -157,14 +1278,331 @@ async fn upload_redirect<'a>(from: UrlPath<'a>,
 }
 
 async fn upload_file_contents<'a>(mime_type: &str,
-                                  file_path: &path::Path,
+                                  contents: &[u8],
                                   url_path: UrlPath<'a>,
                                   site: &web_site::Client) -> Result<()> {
-    let mut req = setter_for_path(url_path, site).await?.set_request();
+    let mut req = setter_for_path(url_path, site).set_request();
     let entities = req.get().initn_value(1);
     let mut entity = entities.get(0);
-    entity.reborrow().get_body().set_bytes(&fs::read(file_path)?);
+    entity.reborrow().get_body().set_bytes(contents);
     entity.set_mime_type(mime_type);
     req.send().promise.await?.get()?;
     Ok(())
 }
+
+/// Upload exactly what `manifest` describes. `read_file` fetches the
+/// contents of the files it refers to.
+async fn upload_manifest<'a, F>(manifest: &'a Manifest,
+                                mut read_file: F,
+                                site: &'a web_site::Client,
+                                uploads: &mut Uploads<'a>) -> Result<()>
+    where F: FnMut(&str) -> Result<Vec<u8>>
+{
+    for (url_path, entities) in manifest.paths.iter() {
+        let mut bodies = Vec::with_capacity(entities.len());
+        let mut local_paths = vec![];
+        let mut read_error = None;
+        for src in entities.iter() {
+            bodies.push(match src.file {
+                Some(ref file) => {
+                    local_paths.push(file.clone());
+                    match read_file(file) {
+                        Ok(body) => Some(body),
+                        Err(e) => {
+                            read_error = Some(e);
+                            break
+                        },
+                    }
+                },
+                None => None,
+            });
+        }
+        let local_path = local_paths.join(", ");
+        if let Some(e) = read_error {
+            uploads.fail(local_path, url_path.clone(), e)?;
+            continue
+        }
+        push_manifest_path(url_path, entities, bodies, site, uploads).await?;
+    }
+    Ok(())
+}
+
+/// Start uploading `entities` to `url_path`, as described by a manifest,
+/// with the given bodies for those stored in files.
+async fn push_manifest_path<'a>(url_path: &'a str,
+                                entities: &'a [manifest::Entity],
+                                bodies: Vec<Option<Vec<u8>>>,
+                                site: &'a web_site::Client,
+                                uploads: &mut Uploads<'a>) -> Result<()> {
+    let local_path = entities.iter()
+        .filter_map(|src| src.file.clone())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let size = bodies.iter().map(|body| body.as_ref().map(|b| b.len()).unwrap_or(0)).sum();
+    uploads.push(async move {
+        let mut req = setter_for_path(UrlPath::from_str(url_path), site).set_request();
+        let mut list = req.get().initn_value(entities.len() as u32);
+        for (i, (src, body)) in entities.iter().zip(bodies.iter()).enumerate() {
+            let mut entity = list.reborrow().get(i as u32);
+            if let Some(ref to) = src.redirect_to {
+                entity.set_redirect_to(to);
+            }
+            if let Some(ref body) = body {
+                entity.reborrow().get_body().set_bytes(body);
+            }
+            entity.set_mime_type(&src.mime_type);
+            entity.set_language(&src.language);
+            entity.set_encoding(&src.encoding);
+        }
+        req.send().promise.await?.get()?;
+        Ok(())
+    }, size, local_path, String::from(url_path)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lmdb_web_site::{LMDBWebSite, MapSize};
+    use std::{cell::Cell, os::unix::fs::symlink, rc::Rc};
+
+    /// A directory of files to upload, and a site to upload them to, both
+    /// removed again when dropped.
+    struct Fixture {
+        dir: path::PathBuf,
+        site: LMDBWebSite,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("webpub-upload-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("files")).unwrap();
+            fs::create_dir_all(dir.join("site")).unwrap();
+            let site = LMDBWebSite::open(String::from("http://example.com/"), &dir.join("site"), MapSize::default())
+                .unwrap();
+            Fixture {
+                dir: dir,
+                site: site,
+            }
+        }
+
+        fn files(&self) -> path::PathBuf {
+            self.dir.join("files")
+        }
+
+        fn write(&self, rel_path: &str, contents: &str) {
+            let path = self.files().join(rel_path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        fn link(&self, rel_path: &str, target: &str) {
+            symlink(target, self.files().join(rel_path)).unwrap();
+        }
+
+        fn upload(&self, options: &Options) -> Result<Report> {
+            let client: web_site::Client = capnp_rpc::new_client(self.site.clone());
+            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            tokio::task::LocalSet::new().block_on(&mut runtime, upload_path(&self.files(), &client, options))
+        }
+
+        /// The paths stored in the site, and where they redirect to.
+        fn stored(&self) -> Vec<(String, Option<String>)> {
+            self.site.list("", None, 1000).unwrap().paths.into_iter().map(|path| {
+                let redirect_to = path.entities.into_iter().find_map(|entity| entity.redirect_to);
+                (path.path, redirect_to)
+            }).collect()
+        }
+
+        fn paths(&self) -> Vec<String> {
+            self.stored().into_iter().map(|(path, _)| path).collect()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Files with symlinks to a file, a directory, the root (making a
+    /// loop) and a file outside of the upload.
+    fn with_symlinks(name: &str) -> Fixture {
+        let fixture = Fixture::new(name);
+        fixture.write("a.txt", "a");
+        fixture.write("dir/b.txt", "b");
+        fs::write(fixture.dir.join("secret.txt"), "secret").unwrap();
+        fixture.link("link.txt", "a.txt");
+        fixture.link("dirlink", "dir");
+        fixture.link("dir/up", "..");
+        fixture.link("out.txt", "../secret.txt");
+        fixture.link("broken.txt", "missing.txt");
+        fixture
+    }
+
+    #[test]
+    fn symlinks_are_followed_within_the_upload() {
+        let fixture = with_symlinks("follow");
+        let report = fixture.upload(&Options::default()).unwrap();
+        assert_eq!(fixture.paths(), vec!["a.txt", "dir/b.txt", "dirlink/b.txt", "link.txt"]);
+        let mut warnings = report.warnings.clone();
+        warnings.sort();
+        assert_eq!(warnings, vec![
+            "broken.txt: broken symlink",
+            "dir/up: symlink loop",
+            "dirlink/up: symlink loop",
+            "out.txt: symlink points outside of the upload",
+        ]);
+
+        let fixture = with_symlinks("follow-outside");
+        fixture.upload(&Options { allow_outside_root: true, ..Default::default() }).unwrap();
+        assert!(fixture.paths().contains(&String::from("out.txt")));
+    }
+
+    #[test]
+    fn symlinks_can_be_skipped_or_stored_as_redirects() {
+        let fixture = with_symlinks("skip");
+        let report = fixture.upload(&Options { symlinks: SymlinkPolicy::Skip, ..Default::default() }).unwrap();
+        assert_eq!(fixture.paths(), vec!["a.txt", "dir/b.txt"]);
+        let mut skipped = report.skipped.clone();
+        skipped.sort();
+        assert_eq!(skipped, vec!["broken.txt", "dir/up", "dirlink", "link.txt", "out.txt"]);
+
+        let fixture = with_symlinks("redirect");
+        let options = Options {
+            symlinks: SymlinkPolicy::Redirect,
+            allow_outside_root: true,
+            ..Default::default()
+        };
+        fixture.upload(&options).unwrap();
+        let redirect = |to: &str| Some(String::from(to));
+        assert_eq!(fixture.stored(), vec![
+            (String::from("a.txt"), None),
+            (String::from("dir/b.txt"), None),
+            (String::from("dir/up"), redirect("/")),
+            (String::from("dirlink"), redirect("dir/")),
+            (String::from("link.txt"), redirect("a.txt")),
+        ]);
+    }
+
+    #[test]
+    fn special_files_are_skipped_with_a_warning() {
+        let fixture = Fixture::new("special");
+        fixture.write("a.txt", "a");
+        let status = std::process::Command::new("mkfifo").arg(fixture.files().join("fifo")).status().unwrap();
+        assert!(status.success());
+        let report = fixture.upload(&Options::default()).unwrap();
+        assert_eq!(fixture.paths(), vec!["a.txt"]);
+        assert_eq!(report.warnings, vec!["fifo: not a regular file or directory; skipping"]);
+    }
+
+    /// An upload which takes a little while, counting how many uploads
+    /// are in flight in `in_flight`, and the most there have been in
+    /// `most`.
+    async fn counted(in_flight: Rc<Cell<usize>>, most: Rc<Cell<usize>>, result: Result<()>) -> Result<()> {
+        in_flight.set(in_flight.get() + 1);
+        most.set(most.get().max(in_flight.get()));
+        tokio::task::yield_now().await;
+        in_flight.set(in_flight.get() - 1);
+        result
+    }
+
+    #[test]
+    fn no_more_than_jobs_uploads_are_in_flight() {
+        let in_flight = Rc::new(Cell::new(0));
+        let most = Rc::new(Cell::new(0));
+        let mut report = Report::default();
+        futures::executor::block_on(async {
+            let mut uploads = Uploads::new(&Options { jobs: 3, ..Default::default() });
+            for i in 0..10 {
+                let upload = counted(in_flight.clone(), most.clone(), Ok(()));
+                uploads.push(upload, i, i.to_string(), i.to_string()).await.unwrap();
+                assert!(uploads.in_flight.len() <= 3);
+            }
+            uploads.finish(&mut report).await.unwrap();
+        });
+        assert_eq!(most.get(), 3);
+        assert_eq!(in_flight.get(), 0);
+        assert_eq!(report.uploaded, 10);
+        assert_eq!(report.bytes, 45);
+    }
+
+    #[test]
+    fn failures_stop_the_upload_unless_keeping_going() {
+        let upload = |keep_going: bool| {
+            let in_flight = Rc::new(Cell::new(0));
+            let most = Rc::new(Cell::new(0));
+            let mut report = Report::default();
+            let result: Result<()> = futures::executor::block_on(async {
+                let mut uploads = Uploads::new(&Options { jobs: 2, keep_going: keep_going, ..Default::default() });
+                for (i, name) in ["a.txt", "b.txt", "c.txt"].iter().enumerate() {
+                    let result = if i == 1 { Err(Error::NonUnicodePath) } else { Ok(()) };
+                    let upload = counted(in_flight.clone(), most.clone(), result);
+                    uploads.push(upload, 1, format!("files/{}", name), name.to_string()).await?;
+                }
+                uploads.finish(&mut report).await
+            });
+            (result, report)
+        };
+
+        let (result, report) = upload(true);
+        result.unwrap();
+        assert_eq!(report.uploaded, 2);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].local_path, "files/b.txt");
+        assert_eq!(report.failures[0].url_path, "b.txt");
+        assert_eq!(report.failures[0].to_string(), "files/b.txt (to /b.txt): path is not valid unicode");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["uploaded"], 2);
+        assert_eq!(json["failures"], serde_json::json!([{
+            "localPath": "files/b.txt",
+            "urlPath": "b.txt",
+            "error": "path is not valid unicode",
+        }]));
+
+        match upload(false) {
+            (Err(e @ Error::File(_)), report) => {
+                assert_eq!(e.to_string(), "files/b.txt (to /b.txt): path is not valid unicode");
+                assert_eq!(std::error::Error::source(&e).unwrap().to_string(), "path is not valid unicode");
+                assert!(report.failures.is_empty());
+            },
+            (result, _) => panic!("expected b.txt to fail, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn only_pages_tagged_with_a_language_are_held_back() {
+        assert!(is_page(None, "about/index.de.html"));
+        assert!(is_page(None, "index.fr.htm"));
+        assert!(!is_page(None, "index.html"));
+        assert!(!is_page(None, "jquery.min.html"));
+        assert!(!is_page(None, "style.css"));
+        assert!(!is_page(None, "notes.md"));
+
+        let mut pages = Pages::default();
+        add_untagged(&mut pages, "index.html");
+        add_untagged(&mut pages, "style.css");
+        assert_eq!(pages.untagged.into_iter().collect::<Vec<_>>(), vec!["index.html"]);
+    }
+
+    #[test]
+    fn markdown_sources_are_kept_only_when_asked() {
+        let mime_types = |keep_source: bool| {
+            let options = markdown::Options {
+                keep_source: keep_source,
+                ..Default::default()
+            };
+            let renderer = markdown::Renderer::new(&options, None).unwrap();
+            let mut pages = Pages::default();
+            add_page(&mut pages, Some(&renderer), "docs/intro.de.md", String::from("text/markdown"),
+                     b"# Hallo\n".to_vec(), String::from("docs/intro.de.md")).unwrap();
+            pages.variants.into_iter().map(|(page, variants)| {
+                (page, variants.into_iter().map(|v| (v.language, v.mime_type)).collect::<Vec<_>>())
+            }).collect::<Vec<_>>()
+        };
+        let html = (String::from("de"), String::from("text/html; charset=utf-8"));
+        let source = (String::from("de"), String::from("text/markdown; charset=utf-8"));
+        assert_eq!(mime_types(false), vec![(String::from("docs/intro.html"), vec![html.clone()])]);
+        assert_eq!(mime_types(true), vec![(String::from("docs/intro.html"), vec![html, source])]);
+    }
+}
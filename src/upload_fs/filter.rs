@@ -0,0 +1,153 @@
+use ignore::{
+    Match,
+    gitignore::{Gitignore, GitignoreBuilder},
+};
+use std::path;
+
+/// Name of the file (at the root of the upload) holding exclude patterns,
+/// in the same format as `.gitignore`.
+pub const IGNORE_FILE_NAME: &str = ".webpubignore";
+
+/// User-supplied rules for which files to upload.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    /// Patterns for files to skip, in `.gitignore` format.
+    pub excludes: Vec<String>,
+
+    /// Patterns for files to upload even if they would otherwise be
+    /// skipped. These take precedence over everything else.
+    pub includes: Vec<String>,
+
+    /// Upload hidden files (those whose names start with a `.`). By
+    /// default they are skipped.
+    pub include_hidden: bool,
+}
+
+/// Decides which files to upload, based on `Rules` and the ignore file.
+pub struct Filter {
+    ignore: Gitignore,
+    include_hidden: bool,
+}
+
+impl Filter {
+    /// Build a filter from `rules`, and the contents of the ignore file at
+    /// the root of the upload, if any. Patterns from `rules` take
+    /// precedence over those in the ignore file.
+    pub fn new(rules: &Rules, ignore_file: Option<&str>) -> Result<Self, ignore::Error> {
+        let mut builder = GitignoreBuilder::new("");
+        if let Some(contents) = ignore_file {
+            for line in contents.lines() {
+                builder.add_line(Some(path::PathBuf::from(IGNORE_FILE_NAME)), line)?;
+            }
+        }
+        for pattern in rules.excludes.iter() {
+            builder.add_line(None, pattern)?;
+        }
+        for pattern in rules.includes.iter() {
+            builder.add_line(None, &format!("!{}", pattern))?;
+        }
+        Ok(Filter {
+            ignore: builder.build()?,
+            include_hidden: rules.include_hidden,
+        })
+    }
+
+    /// Should the file or directory at `rel_path` (relative to the root of
+    /// the upload) be uploaded? This only looks at the last component of
+    /// the path; the caller is responsible for not descending into
+    /// directories which are skipped.
+    pub fn allows(&self, rel_path: &path::Path, is_dir: bool) -> bool {
+        if rel_path == path::Path::new(IGNORE_FILE_NAME) {
+            return false
+        }
+        match self.ignore.matched(rel_path, is_dir) {
+            Match::Whitelist(_) => true,
+            Match::Ignore(_) => false,
+            Match::None => self.include_hidden || !is_hidden(rel_path),
+        }
+    }
+
+    /// Like `allows`, but also checks each of the directories containing
+    /// `rel_path`. Use this when there's no directory tree to walk, e.g.
+    /// for archives.
+    pub fn allows_with_parents(&self, rel_path: &path::Path) -> bool {
+        let mut parent = path::PathBuf::new();
+        let mut components = rel_path.components().peekable();
+        while let Some(component) = components.next() {
+            parent.push(component);
+            let is_dir = components.peek().is_some();
+            if !self.allows(&parent, is_dir) {
+                return false
+            }
+        }
+        true
+    }
+}
+
+fn is_hidden(rel_path: &path::Path) -> bool {
+    rel_path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with('.'))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(excludes: &[&str], includes: &[&str]) -> Rules {
+        Rules {
+            excludes: excludes.iter().map(|s| String::from(*s)).collect(),
+            includes: includes.iter().map(|s| String::from(*s)).collect(),
+            include_hidden: false,
+        }
+    }
+
+    fn allows(filter: &Filter, rel_path: &str) -> bool {
+        filter.allows_with_parents(path::Path::new(rel_path))
+    }
+
+    #[test]
+    fn hidden_files_are_skipped_unless_asked_for() {
+        let filter = Filter::new(&Rules::default(), None).unwrap();
+        assert!(allows(&filter, "index.html"));
+        assert!(!allows(&filter, ".DS_Store"));
+        assert!(!allows(&filter, ".git/config"));
+        assert!(!allows(&filter, "css/.style.css.swp"));
+
+        let filter = Filter::new(&Rules { include_hidden: true, ..Default::default() }, None).unwrap();
+        assert!(allows(&filter, ".well-known/security.txt"));
+        assert!(!allows(&filter, IGNORE_FILE_NAME));
+    }
+
+    #[test]
+    fn the_ignore_file_is_read_like_a_gitignore() {
+        let ignore_file = "# build output\n/target\n*.bak\ndrafts/\n!keep.bak\n.well-known\n!.well-known\n";
+        let filter = Filter::new(&Rules::default(), Some(ignore_file)).unwrap();
+        assert!(!allows(&filter, "target/site.html"));
+        assert!(allows(&filter, "docs/target/index.html"));
+        assert!(!allows(&filter, "docs/index.html.bak"));
+        assert!(allows(&filter, "keep.bak"));
+        assert!(!allows(&filter, "drafts/post.html"));
+        assert!(allows(&filter, "drafts.html"));
+        assert!(allows(&filter, ".well-known/security.txt"));
+        assert!(!allows(&filter, IGNORE_FILE_NAME));
+
+        assert!(Filter::new(&Rules::default(), Some("a{b\n")).is_err());
+    }
+
+    #[test]
+    fn excludes_override_the_ignore_file_and_includes_override_everything() {
+        let ignore_file = "!*.log\n";
+        let filter = Filter::new(&rules(&["*.log", "private/"], &[]), Some(ignore_file)).unwrap();
+        assert!(!allows(&filter, "debug.log"));
+        assert!(!allows(&filter, "private/notes.html"));
+
+        let filter = Filter::new(&rules(&["*.log"], &["important.log", ".htaccess"]), Some("*.html\n")).unwrap();
+        assert!(!allows(&filter, "debug.log"));
+        assert!(allows(&filter, "important.log"));
+        assert!(!allows(&filter, "index.html"));
+        assert!(allows(&filter, ".htaccess"));
+        assert!(!allows(&filter, ".env"));
+    }
+}
@@ -0,0 +1,210 @@
+use pulldown_cmark::{html, Event, Parser, Tag};
+use serde::Deserialize;
+
+/// The layout used for rendered Markdown if the site has one at the root
+/// of the upload. It isn't uploaded itself.
+pub const LAYOUT_FILE_NAME: &str = "_layout.html";
+
+/// Used if neither the user nor the site supplies a layout.
+pub(crate) const DEFAULT_LAYOUT: &str = r#"<!doctype html>
+<html>
+	<head>
+		<meta charset="utf-8" />
+		<title>{{ title }}</title>
+	</head>
+	<body>
+		{{ content|safe }}
+	</body>
+</html>
+"#;
+
+const TEMPLATE_NAME: &str = "layout.html";
+
+/// Options for rendering Markdown files to html on upload.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The source of the layout template, which takes precedence over the
+    /// site's `_layout.html`.
+    pub layout: Option<String>,
+
+    /// Also publish the Markdown itself, as a `text/markdown` variant of
+    /// the page.
+    pub keep_source: bool,
+}
+
+/// Renders Markdown pages into a layout. The layout uses the same syntax
+/// as our own templates; it gets the page's `title`, its `path` relative
+/// to the root of the site, and the rendered `content`, which should be
+/// included with `{{ content|safe }}`. Pages may start with front matter,
+/// which isn't rendered; a `title` there takes precedence over the page's
+/// first heading.
+pub struct Renderer {
+    templates: tera::Tera,
+    pub keep_source: bool,
+}
+
+impl Renderer {
+    /// `site_layout` is the contents of the site's `_layout.html`, if any.
+    pub fn new(options: &Options, site_layout: Option<&str>) -> Result<Self, tera::Error> {
+        let layout = options.layout.as_ref().map(|s| &s[..])
+            .or(site_layout)
+            .unwrap_or(DEFAULT_LAYOUT);
+        let mut templates = tera::Tera::default();
+        templates.add_raw_template(TEMPLATE_NAME, layout)?;
+        Ok(Renderer {
+            templates: templates,
+            keep_source: options.keep_source,
+        })
+    }
+
+    /// Render the Markdown file at `path`, which holds `source`.
+    pub fn render(&self, path: &str, source: &str) -> Result<String, tera::Error> {
+        let (front_matter, body) = split_front_matter(source)
+            .ok_or_else(|| tera::Error::msg(format!("{}: front matter is not terminated", path)))?;
+        let meta: Meta = match front_matter {
+            None => Meta::default(),
+            Some(FrontMatter::Yaml(raw)) if raw.trim() == "" => Meta::default(),
+            Some(FrontMatter::Yaml(raw)) => serde_yaml::from_str(raw)
+                .map_err(|e| tera::Error::msg(format!("{}: invalid front matter: {}", path, e)))?,
+            Some(FrontMatter::Toml(raw)) => toml::from_str(raw)
+                .map_err(|e| tera::Error::msg(format!("{}: invalid front matter: {}", path, e)))?,
+        };
+        let mut content = String::new();
+        html::push_html(&mut content, Parser::new_ext(body, pulldown_cmark::Options::all()));
+        let title = meta.title
+            .or_else(|| title(body))
+            .unwrap_or_else(|| stem(path));
+        let mut context = tera::Context::new();
+        context.insert("title", &title);
+        context.insert("path", &html_path(path));
+        context.insert("content", &content);
+        self.templates.render(TEMPLATE_NAME, &context)
+    }
+}
+
+/// The parts of a page's front matter used when rendering it on upload.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Meta {
+    title: Option<String>,
+}
+
+/// The raw front matter at the start of a page.
+pub(crate) enum FrontMatter<'a> {
+    /// Between `---` lines.
+    Yaml(&'a str),
+    /// Between `+++` lines.
+    Toml(&'a str),
+}
+
+/// Split the front matter, if any, off the start of `text`, returning it
+/// and the rest of the page. Returns `None` if the front matter isn't
+/// terminated.
+pub(crate) fn split_front_matter<'a>(text: &'a str) -> Option<(Option<FrontMatter<'a>>, &'a str)> {
+    let first_end = text.find('\n').map(|i| i + 1).unwrap_or(text.len());
+    let fence = text[..first_end].trim_end();
+    if fence != "---" && fence != "+++" {
+        return Some((None, text))
+    }
+    let rest = &text[first_end..];
+    let mut offset = 0;
+    while offset < rest.len() {
+        let line_end = rest[offset..].find('\n').map(|i| offset + i + 1).unwrap_or(rest.len());
+        if rest[offset..line_end].trim_end() == fence {
+            let raw = &rest[..offset];
+            let front_matter = if fence == "+++" { FrontMatter::Toml(raw) } else { FrontMatter::Yaml(raw) };
+            return Some((Some(front_matter), &rest[line_end..]))
+        }
+        offset = line_end;
+    }
+    None
+}
+
+/// Whether the file at `path` is Markdown.
+pub fn is_markdown(path: &str) -> bool {
+    path.ends_with(".md") || path.ends_with(".markdown")
+}
+
+/// The path of the html file rendered from the Markdown file at `path`,
+/// e.g. `docs/intro.md` becomes `docs/intro.html`. `README.md` becomes
+/// `index.html`, so it's served for the directory.
+pub fn html_path(path: &str) -> String {
+    let (dir, name) = match path.rfind('/') {
+        Some(i) => path.split_at(i + 1),
+        None => ("", path),
+    };
+    let stem = match name.rfind('.') {
+        Some(i) => &name[..i],
+        None => name,
+    };
+    let stem = if stem.eq_ignore_ascii_case("readme") { "index" } else { stem };
+    format!("{}{}.html", dir, stem)
+}
+
+fn stem(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    String::from(name.split('.').next().unwrap_or(name))
+}
+
+/// The text of the first top-level heading in `source`, if any.
+fn title(source: &str) -> Option<String> {
+    let mut in_heading = false;
+    let mut title = String::new();
+    for event in Parser::new(source) {
+        match event {
+            Event::Start(Tag::Heading(1)) => in_heading = true,
+            Event::End(Tag::Heading(1)) => return Some(title),
+            Event::Text(text) | Event::Code(text) if in_heading => title.push_str(&text),
+            _ => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(options: &Options, site_layout: Option<&str>, path: &str, source: &str) -> String {
+        Renderer::new(options, site_layout).unwrap().render(path, source).unwrap()
+    }
+
+    #[test]
+    fn front_matter_is_stripped_and_its_title_used() {
+        let options = Options {
+            layout: Some(String::from("{{ title }}|{{ content|safe }}")),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&options, None, "a.md", "---\ntitle: From YAML\ndate: 2020-01-02\n---\n# Heading\n"),
+            "From YAML|<h1>Heading</h1>\n",
+        );
+        assert_eq!(
+            render(&options, None, "a.md", "+++\ntitle = \"From TOML\"\n+++\ntext\n"),
+            "From TOML|<p>text</p>\n",
+        );
+        assert_eq!(render(&options, None, "a.md", "---\n---\n# Heading\n"), "Heading|<h1>Heading</h1>\n");
+        assert_eq!(render(&options, None, "docs/intro.md", "text\n\n---\n"), "intro|<p>text</p>\n<hr />\n");
+
+        let renderer = Renderer::new(&options, None).unwrap();
+        assert!(renderer.render("a.md", "---\ntitle: Oops\n# Heading\n").is_err());
+        assert!(renderer.render("a.md", "---\ntitle: [\n---\n").is_err());
+    }
+
+    #[test]
+    fn the_layout_option_takes_precedence_over_the_sites_layout() {
+        let source = "# Hello\n";
+        let site_layout = Some("site: {{ title }} at {{ path }}");
+        let options = Options {
+            layout: Some(String::from("option: {{ title }} at {{ path }}")),
+            ..Default::default()
+        };
+        assert_eq!(render(&options, site_layout, "README.md", source), "option: Hello at index.html");
+        assert_eq!(render(&Options::default(), site_layout, "README.md", source), "site: Hello at index.html");
+
+        let page = render(&Options::default(), None, "hello.markdown", source);
+        assert!(page.starts_with("<!doctype html>"));
+        assert!(page.contains("<title>Hello</title>"));
+        assert!(page.contains("<h1>Hello</h1>"));
+    }
+}
@@ -0,0 +1,207 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::path;
+
+/// A user-supplied rule forcing the mime type of some files.
+#[derive(Clone, Debug)]
+pub struct Override {
+    pub pattern: String,
+    pub mime_type: String,
+}
+
+/// User-supplied rules for detecting mime types.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    pub overrides: Vec<Override>,
+}
+
+impl Rules {
+    /// Parse a mime types config file. Each non-blank line which doesn't
+    /// start with `#` holds a glob pattern and a mime type, separated by
+    /// whitespace, e.g.:
+    ///
+    /// ```text
+    /// *.webmanifest  application/manifest+json
+    /// LICENSE        text/plain
+    /// ```
+    ///
+    /// Earlier lines take precedence.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rules = Rules::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line == "" || line.starts_with('#') {
+                continue
+            }
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(pattern), Some(mime_type), None) => {
+                    rules.overrides.push(Override {
+                        pattern: String::from(pattern),
+                        mime_type: String::from(mime_type),
+                    });
+                },
+                _ => return Err(format!("line {}: expected a pattern and a mime type", i + 1)),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+/// Works out the mime type of a file. In order, we try:
+///
+/// 1. The user's override rules.
+/// 2. The file's extension.
+/// 3. Sniffing the contents for well-known formats.
+///
+/// Text types get `; charset=utf-8` added if the contents are valid UTF-8.
+pub struct Detector {
+    overrides: GlobSet,
+    mime_types: Vec<String>,
+}
+
+impl Detector {
+    pub fn new(rules: &Rules) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for rule in rules.overrides.iter() {
+            builder.add(Glob::new(&rule.pattern)?);
+        }
+        Ok(Detector {
+            overrides: builder.build()?,
+            mime_types: rules.overrides.iter().map(|rule| rule.mime_type.clone()).collect(),
+        })
+    }
+
+    /// Get the mime type for the file at `rel_path` (relative to the root
+    /// of the upload), which holds `contents`.
+    pub fn detect(&self, rel_path: &str, contents: &[u8]) -> String {
+        if let Some(&i) = self.overrides.matches(rel_path).iter().min() {
+            return self.mime_types[i].clone()
+        }
+        let mime_type = from_extension(rel_path)
+            .or_else(|| sniff(contents))
+            .unwrap_or("application/octet-stream");
+        if is_text(mime_type) && std::str::from_utf8(contents).is_ok() {
+            format!("{}; charset=utf-8", mime_type)
+        } else {
+            String::from(mime_type)
+        }
+    }
+}
+
+fn from_extension(rel_path: &str) -> Option<&'static str> {
+    let ext = path::Path::new(rel_path).extension()?.to_str()?;
+    mime_guess::from_ext(ext).first_raw()
+}
+
+/// Guess the mime type from the first few bytes of the contents.
+fn sniff(contents: &[u8]) -> Option<&'static str> {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"%PDF-", "application/pdf"),
+        (b"\0asm", "application/wasm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+    ];
+    for &(magic, mime_type) in MAGIC {
+        if contents.starts_with(magic) {
+            return Some(mime_type)
+        }
+    }
+
+    let prefix = &contents[..contents.len().min(512)];
+    let text = match std::str::from_utf8(prefix) {
+        Ok(text) => text,
+        // We may have cut a character in half at the end of the prefix:
+        Err(e) if e.error_len().is_none() && prefix.len() < contents.len() => {
+            std::str::from_utf8(&prefix[..e.valid_up_to()]).unwrap()
+        },
+        Err(_) => return None,
+    };
+    let start = text.trim_start_matches('\u{feff}').trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("text/html")
+    } else if !text.contains('\0') {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+fn is_text(mime_type: &str) -> bool {
+    mime_type.starts_with("text/") || match mime_type {
+        "application/javascript"
+            | "application/json"
+            | "application/xml"
+            | "image/svg+xml" => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(rules: &str) -> Detector {
+        Detector::new(&Rules::parse(rules).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rules_are_parsed_skipping_comments_and_blank_lines() {
+        let rules = Rules::parse("# mime types\n\n*.webmanifest  application/manifest+json\n  LICENSE\ttext/plain  \n").unwrap();
+        let parsed: Vec<_> = rules.overrides.iter()
+            .map(|rule| (&rule.pattern[..], &rule.mime_type[..]))
+            .collect();
+        assert_eq!(parsed, vec![("*.webmanifest", "application/manifest+json"), ("LICENSE", "text/plain")]);
+
+        assert_eq!(Rules::parse("*.a  text/plain\n*.b\n").unwrap_err(), "line 2: expected a pattern and a mime type");
+        assert_eq!(Rules::parse("\n\n*.c text/plain extra\n").unwrap_err(), "line 3: expected a pattern and a mime type");
+    }
+
+    #[test]
+    fn earlier_overrides_take_precedence_over_later_ones_and_extensions() {
+        let detector = detector("
+            LICENSE          text/x-license
+            *.webmanifest    application/manifest+json
+            static/**/*.js   text/x-static
+            *.js             text/x-script
+        ");
+        assert_eq!(detector.detect("LICENSE", b"MIT"), "text/x-license");
+        assert_eq!(detector.detect("icons/site.webmanifest", b"{}"), "application/manifest+json");
+        assert_eq!(detector.detect("static/lib/app.js", b""), "text/x-static");
+        assert_eq!(detector.detect("app.js", b""), "text/x-script");
+        // Overrides are used as given, without a charset:
+        assert_eq!(detector.detect("site.webmanifest", b"{}"), "application/manifest+json");
+        assert!(Detector::new(&Rules::parse("a[  text/plain").unwrap()).is_err());
+    }
+
+    #[test]
+    fn extensions_take_precedence_over_sniffing() {
+        let detector = detector("");
+        assert_eq!(detector.detect("style.css", b"<html>"), "text/css; charset=utf-8");
+        assert_eq!(detector.detect("logo.png", b"not a png"), "image/png");
+        assert_eq!(detector.detect("data.json", b"\xff\xfe"), "application/json");
+    }
+
+    #[test]
+    fn files_without_a_known_extension_are_sniffed() {
+        let detector = detector("");
+        assert_eq!(detector.detect("logo", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(detector.detect("paper", b"%PDF-1.4\n%\xe2\xe3"), "application/pdf");
+        assert_eq!(detector.detect("module", b"\0asm\x01\0\0\0"), "application/wasm");
+        assert_eq!(detector.detect("about", b"\xef\xbb\xbf\n  <!DOCTYPE html><p>Hi"), "text/html; charset=utf-8");
+        assert_eq!(detector.detect("contact", b"<HTML lang=en>"), "text/html; charset=utf-8");
+        assert_eq!(detector.detect("README", b"Read me"), "text/plain; charset=utf-8");
+        assert_eq!(detector.detect("blob", b"\x01\x02\0\x03"), "application/octet-stream");
+        assert_eq!(detector.detect("latin1", b"caf\xe9"), "application/octet-stream");
+    }
+
+    #[test]
+    fn text_is_sniffed_even_if_a_character_is_cut_off() {
+        let mut contents = vec![b'a'; 511];
+        contents.extend_from_slice("é and more".as_bytes());
+        assert_eq!(detector("").detect("notes", &contents), "text/plain; charset=utf-8");
+    }
+}
@@ -0,0 +1,129 @@
+/// Whether pages (directories, and `.html` files if stripped) should be
+/// published with a trailing slash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Publish `dir/index.html` at `dir/`.
+    Always,
+
+    /// Publish `dir/index.html` at `dir`.
+    Never,
+}
+
+/// Rules for which url a file gets published at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UrlPolicy {
+    /// Publish `foo.html` at `foo` (or `foo/`), rather than `foo.html`.
+    pub strip_html: bool,
+
+    pub trailing_slash: TrailingSlash,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        UrlPolicy {
+            strip_html: false,
+            trailing_slash: TrailingSlash::Always,
+        }
+    }
+}
+
+/// Where a file should be published.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    /// The path to store the file's contents at.
+    pub canonical: String,
+
+    /// Other paths which should redirect to `canonical`.
+    pub aliases: Vec<String>,
+}
+
+impl UrlPolicy {
+    /// Work out where to publish the file at `file_path`, relative to the
+    /// root of the site.
+    pub fn place(&self, file_path: &str) -> Placement {
+        let page = if file_path == "index.html" {
+            Some("")
+        } else if file_path.ends_with("/index.html") {
+            Some(&file_path[..file_path.len() - "/index.html".len()])
+        } else if self.strip_html && file_path.ends_with(".html") {
+            Some(&file_path[..file_path.len() - ".html".len()])
+        } else {
+            None
+        };
+        match page {
+            None => Placement {
+                canonical: String::from(file_path),
+                aliases: vec![],
+            },
+            Some(base) => {
+                let with_slash = String::from(base) + "/";
+                let without_slash = String::from(base);
+                let (canonical, other) = match self.trailing_slash {
+                    TrailingSlash::Always => (with_slash, without_slash),
+                    TrailingSlash::Never => (without_slash, with_slash),
+                };
+                Placement {
+                    canonical: canonical,
+                    aliases: vec![other, String::from(file_path)],
+                }
+            },
+        }
+    }
+}
+
+/// Other paths which a request for `path` may have meant, most likely
+/// first. If nothing is stored at `path`, we redirect to the first of
+/// these which exists, so sites published without the matching aliases
+/// still work.
+pub fn alternatives(path: &str) -> Vec<String> {
+    if path.ends_with('/') {
+        let base = &path[..path.len() - 1];
+        vec![String::from(base), String::from(base) + ".html"]
+    } else if path.ends_with(".html") {
+        let base = &path[..path.len() - ".html".len()];
+        vec![String::from(base), String::from(base) + "/"]
+    } else {
+        vec![String::from(path) + "/", String::from(path) + ".html"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placement(canonical: &str, aliases: &[&str]) -> Placement {
+        Placement {
+            canonical: String::from(canonical),
+            aliases: aliases.iter().map(|alias| String::from(*alias)).collect(),
+        }
+    }
+
+    #[test]
+    fn index_pages_go_at_their_directory() {
+        let policy = UrlPolicy::default();
+        assert_eq!(policy.place("index.html"), placement("/", &["", "index.html"]));
+        assert_eq!(policy.place("a/index.html"), placement("a/", &["a", "a/index.html"]));
+        assert_eq!(policy.place("a/b.html"), placement("a/b.html", &[]));
+        assert_eq!(policy.place("style.css"), placement("style.css", &[]));
+    }
+
+    #[test]
+    fn stripped_pages_follow_the_trailing_slash_policy() {
+        let mut policy = UrlPolicy {
+            strip_html: true,
+            trailing_slash: TrailingSlash::Always,
+        };
+        assert_eq!(policy.place("a/b.html"), placement("a/b/", &["a/b", "a/b.html"]));
+        policy.trailing_slash = TrailingSlash::Never;
+        assert_eq!(policy.place("a/b.html"), placement("a/b", &["a/b/", "a/b.html"]));
+        assert_eq!(policy.place("a/index.html"), placement("a", &["a/", "a/index.html"]));
+        assert_eq!(policy.place("a/b.htm"), placement("a/b.htm", &[]));
+    }
+
+    #[test]
+    fn alternatives_cover_the_other_forms() {
+        assert_eq!(alternatives("a/"), vec!["a", "a.html"]);
+        assert_eq!(alternatives("a.html"), vec!["a", "a/"]);
+        assert_eq!(alternatives("a"), vec!["a/", "a.html"]);
+    }
+}
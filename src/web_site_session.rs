@@ -3,19 +3,32 @@ use std::{
     collections::{HashMap, HashSet},
 };
 use sandstorm::{
+    util_capnp::assignable::getter,
     web_publishing_capnp::web_site,
     grain_capnp::ui_session,
     web_session_capnp::web_session,
 };
 use capnp;
-use capnp::capability::Promise;
+use capnp::capability::{Promise, Response};
+use crate::{
+    language,
+    lmdb_web_site::{self, LMDBWebSite},
+    search,
+    shortcuts::entity_list,
+    site_settings::Fallback,
+    url_policy,
+};
 
 pub struct WebSessionImpl {
+    site: LMDBWebSite,
     client: web_site::Client,
 }
 
-pub fn new(client: web_site::Client) -> WebSessionImpl {
-    WebSessionImpl { client: client }
+pub fn new(site: LMDBWebSite) -> WebSessionImpl {
+    WebSessionImpl {
+        client: capnp_rpc::new_client(site.clone()),
+        site: site,
+    }
 }
 
 impl ui_session::Server for WebSessionImpl {
@@ -25,46 +38,162 @@ impl web_session::Server for WebSessionImpl {
     fn get(&mut self,
            params: web_session::GetParams,
            mut results: web_session::GetResults) -> Promise<(), capnp::Error> {
+        let site = self.site.clone();
         let client = self.client.clone();
         Promise::from_future(async move {
             let params = params.get()?;
             let context = params.get_context()?;
             let ignore_body = params.get_ignore_body();
 
-            let response = results.get();
-
-            let mut req = client.get_entities_request();
-            req.get().set_path(params.get_path()?);
-            let result = req.send()
-                .pipeline.get_entities()
-                // We haven't actually implemented Assignable.get(), so
-                // convert it into a getter first. The latter has some
-                // optimistic concurrency stuff that we don't actually need.
-                .as_getter_request().send()
-                // Ideally we'd pipeline this request too, but I'm getting
-                // an error from the compiler about the trait bound for Pipeline
-                // not being satisfied for the struct list.
-                .promise.await?.get()?
-                .get_getter()?.get_request().send()
-                .promise.await?;
-            let value = result.get()?.get_value()?;
+            let mut response = results.get();
+            let settings = site.settings()?;
+
+            let requested = params.get_path()?;
+            let (requested_path, query) = match requested.find('?') {
+                Some(i) => (&requested[..i], &requested[i + 1..]),
+                None => (requested, ""),
+            };
+            if requested_path == search::SEARCH_PATH {
+                let q = search::query_param(query, "q").unwrap_or_default();
+                let limit = search::query_param(query, "limit")
+                    .and_then(|limit| limit.parse().ok())
+                    .unwrap_or(search::DEFAULT_LIMIT)
+                    .min(search::MAX_LIMIT);
+                let results = serde_json::json!({
+                    "query": q,
+                    "results": site.search(&q, limit)?,
+                });
+                let mut content = response.init_content();
+                content.set_status_code(web_session::response::SuccessCode::Ok);
+                content.set_mime_type("application/json");
+                if !ignore_body {
+                    content.get_body().set_bytes(results.to_string().as_bytes());
+                }
+                return Ok(())
+            }
+            if requested_path == lmdb_web_site::LISTING_PATH {
+                // The listing is for holders of the site's capability, not
+                // its visitors.
+                let mut client_error = response.init_client_error();
+                client_error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                if !ignore_body {
+                    client_error.set_description_html("404 Not found");
+                }
+                return Ok(())
+            }
+
+            let mut path = String::from(requested_path);
+            let mut rewritten = false;
+            let mut not_found = false;
+            // The site's redirect rules take precedence over anything stored.
+            if let Some(rule) = site.resolve_redirect(requested_path)? {
+                match rule.status {
+                    200 | 404 => {
+                        path = String::from(rule.to.trim_start_matches('/'));
+                        rewritten = true;
+                        not_found = rule.status == 404;
+                    },
+                    status => {
+                        set_redirect(response.reborrow(), &rule.to, status);
+                        return Ok(())
+                    },
+                }
+            }
+            let path = &path[..];
+            let mut result = lookup(&client, path).await?;
+            if result.get()?.get_value()?.len() == 0 && !rewritten {
+                // Nothing here; if the page was published under another
+                // spelling of its url (e.g. with or without a trailing
+                // slash), send the client there.
+                for alternative in url_policy::alternatives(path) {
+                    let found = lookup(&client, &alternative).await?;
+                    let found_value = found.get()?.get_value()?;
+                    // If the alternative just redirects back here, sending
+                    // the client there would go round in circles.
+                    let redirects_here = redirect_target(found_value)?
+                        .map_or(false, |target| same_url(path, &target));
+                    if found_value.len() > 0 && !redirects_here {
+                        if !same_url(path, &alternative) {
+                            set_redirect(response.reborrow(), &path_location(&alternative), 301);
+                            return Ok(())
+                        }
+                        result = found;
+                        break
+                    }
+                }
+            }
+            let target = redirect_target(result.get()?.get_value()?)?;
+            if let Some(target) = target {
+                if !same_url(path, &target) {
+                    set_redirect(response.reborrow(), &path_location(&target), 301);
+                    return Ok(())
+                }
+                // The root of the site is stored at "/", but the client can't
+                // ask for that separately from "", so serve it directly.
+                result = lookup(&client, &target).await?;
+            }
             let accept = context.get_accept()?;
+            let missing = result.get()?.get_value()?.len() == 0;
+            if missing && !rewritten && accepts_html(accept) && is_page(path) {
+                match settings.fallback {
+                    Fallback::None => (),
+                    Fallback::Spa { path: fallback } => {
+                        result = lookup_page(&client, &fallback).await?;
+                    },
+                    Fallback::NotFound { path: fallback } => {
+                        result = lookup_page(&client, &fallback).await?;
+                        not_found = true;
+                    },
+                }
+            }
+            let value = result.get()?.get_value()?;
             let accept_encoding = context.get_accept_encoding()?;
-            match match_content(value, accept, accept_encoding) {
+            let mut available = vec![];
+            for entity in value.iter() {
+                let language = entity.get_language()?;
+                if !available.contains(&language) {
+                    available.push(language);
+                }
+            }
+            let language = if available.len() > 1 {
+                let accepted = request_header(context, "Accept-Language")?
+                    .map(language::parse_accept_language)
+                    .unwrap_or_default();
+                let mut headers = response.reborrow().init_additional_headers(1);
+                headers.reborrow().get(0).set_name("Vary");
+                headers.reborrow().get(0).set_value("Accept-Language");
+                // Pages without a language are the fallback for anyone we
+                // don't have a translation for.
+                let tagged: Vec<&str> = available.iter().cloned().filter(|l| *l != "").collect();
+                let default = if available.contains(&"") { "" } else { available[0] };
+                Some(language::choose(&tagged, &accepted, &settings.default_language).unwrap_or(default))
+            } else {
+                None
+            };
+            match match_content(value, accept, accept_encoding, language) {
+                Some(ref entity) if not_found => {
+                    let mut client_error = response.init_client_error();
+                    client_error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                    if !ignore_body {
+                        client_error.set_description_html(&String::from_utf8_lossy(body(entity)?));
+                    }
+                },
                 Some(ref entity) => {
                     let mut content = response.init_content();
                     content.set_status_code(web_session::response::SuccessCode::Ok);
                     content.set_mime_type(entity.get_mime_type()?);
+                    let language = entity.get_language()?;
+                    if language != "" {
+                        content.set_language(language);
+                    }
                     if !ignore_body {
-                        // TODO: copy over the body properly.
-                        content.get_body().set_bytes(b"Test.");
+                        content.get_body().set_bytes(body(entity)?);
                     }
                 },
                 None => {
                     let mut client_error = response.init_client_error();
                     client_error.set_status_code(web_session::response::ClientErrorCode::NotFound);
                     if !ignore_body {
-                        // TODO: get the response type & body from the web_site's not found handlers.
                         client_error.set_description_html("404 Not found");
                     }
                 }
@@ -74,9 +203,124 @@ impl web_session::Server for WebSessionImpl {
     }
 }
 
+/// Fetch the entities stored at `path`.
+pub(crate) async fn lookup(client: &web_site::Client, path: &str)
+    -> Result<Response<getter::get_results::Owned<entity_list::Owned>>, capnp::Error>
+{
+    let mut req = client.get_entities_request();
+    req.get().set_path(path);
+    req.send()
+        .pipeline.get_entities()
+        // We haven't actually implemented Assignable.get(), so
+        // convert it into a getter first. The latter has some
+        // optimistic concurrency stuff that we don't actually need.
+        .as_getter_request().send()
+        // Ideally we'd pipeline this request too, but I'm getting
+        // an error from the compiler about the trait bound for Pipeline
+        // not being satisfied for the struct list.
+        .promise.await?.get()?
+        .get_getter()?.get_request().send()
+        .promise.await
+}
+
+/// Fetch the page at `path`, following a redirect if there is one there
+/// (e.g. from `index.html` to the directory).
+async fn lookup_page(client: &web_site::Client, path: &str)
+    -> Result<Response<getter::get_results::Owned<entity_list::Owned>>, capnp::Error>
+{
+    let path = path.trim_start_matches('/');
+    let result = lookup(client, path).await?;
+    match redirect_target(result.get()?.get_value()?)? {
+        Some(target) => lookup(client, &target).await,
+        None => Ok(result),
+    }
+}
+
+/// Whether the client listed html as something it accepts, as browsers
+/// do when loading a page (but not e.g. an image).
+fn accepts_html(accepted_types: capnp::struct_list::Reader<web_session::accepted_type::Owned>) -> bool {
+    accepted_types.iter().any(|typ| {
+        typ.get_q_value() > 0.0 && typ.get_mime_type()
+            .map(|mime_type| mime_essence(mime_type) == "text/html")
+            .unwrap_or(false)
+    })
+}
+
+/// Whether `path` could be a page, rather than some other kind of file.
+/// We take anything with an extension other than `.html` to be the
+/// latter.
+pub(crate) fn is_page(path: &str) -> bool {
+    let path = match path.find('?') {
+        Some(i) => &path[..i],
+        None => path,
+    };
+    let name = path.rsplit('/').next().unwrap_or("");
+    match name.rfind('.') {
+        Some(i) => {
+            let ext = &name[i + 1..];
+            ext == "html" || ext == "htm"
+        },
+        None => true,
+    }
+}
+
+/// If `entities` is a redirect, get the path it points to.
+fn redirect_target(entities: entity_list::Reader) -> capnp::Result<Option<String>> {
+    for entity in entities.iter() {
+        if entity.has_redirect_to() {
+            return Ok(Some(String::from(entity.get_redirect_to()?)))
+        }
+    }
+    Ok(None)
+}
+
+/// Whether two paths name the same url, as far as the client can tell.
+pub(crate) fn same_url(a: &str, b: &str) -> bool {
+    a.trim_start_matches('/') == b.trim_start_matches('/')
+}
+
+/// The location to redirect to for `path`, which is relative to the root
+/// of the site.
+fn path_location(path: &str) -> String {
+    format!("/{}", path.trim_start_matches('/'))
+}
+
+/// Redirect the client to `location`, with the given HTTP status.
+fn set_redirect(response: web_session::response::Builder, location: &str, status: u16) {
+    let mut redirect = response.init_redirect();
+    redirect.set_is_permanent(status == 301 || status == 308);
+    redirect.set_switch_to_get(status != 307 && status != 308);
+    redirect.set_location(location);
+}
+
+/// Get the body of an entity.
+fn body<'a>(entity: &web_site::entity::Reader<'a>) -> capnp::Result<&'a [u8]> {
+    match entity.get_body().which()? {
+        web_site::entity::body::Bytes(bytes) => bytes,
+        web_site::entity::body::Blob(_) => {
+            Err(capnp::Error::unimplemented(String::from("Blob bodies are not supported")))
+        },
+    }
+}
+
+/// Get the value of the request header `name`, if the client sent it.
+fn request_header<'a>(context: web_session::context::Reader<'a>, name: &str)
+    -> capnp::Result<Option<&'a str>>
+{
+    for header in context.get_additional_headers()?.iter() {
+        if header.get_name()?.eq_ignore_ascii_case(name) {
+            return Ok(Some(header.get_value()?))
+        }
+    }
+    Ok(None)
+}
+
+/// Pick the entity to serve. If `language` is given, only entities in
+/// that language are considered.
 fn match_content<'a>(entities: capnp::struct_list::Reader<'a, web_site::entity::Owned>,
                  accepted_types: capnp::struct_list::Reader<'a, web_session::accepted_type::Owned>,
-                 accepted_encodings: capnp::struct_list::Reader<'a, web_session::accepted_encoding::Owned>
+                 accepted_encodings: capnp::struct_list::Reader<'a, web_session::accepted_encoding::Owned>,
+                 language: Option<&str>,
                  ) -> Option<web_site::entity::Reader<'a>> {
 
     let mut accepted_types: Vec<_> = accepted_types.into_iter().collect();
@@ -95,18 +339,26 @@ fn match_content<'a>(entities: capnp::struct_list::Reader<'a, web_site::entity::
         })
         .collect();
 
-    let entities: HashMap<_, _> = entities.into_iter().filter_map(|entity| {
+    // Match on the essence of the type, ignoring parameters like charset.
+    let entities: HashMap<_, _> = entities.into_iter().filter(|entity| {
+        match language {
+            Some(language) => entity.get_language().map(|l| l == language).unwrap_or(false),
+            None => true,
+        }
+    }).filter_map(|entity| {
         entity.get_mime_type()
-            .map(|mime_type| Some((mime_type, entity)))
+            .map(|mime_type| Some((mime_essence(mime_type), entity)))
             .unwrap_or(None)
     }).collect();
 
     for typ in accepted_types {
         if let Ok(mime_type) = typ.get_mime_type() {
-            if let Some(entity) = entities.get(mime_type)  {
+            if let Some(entity) = entities.get(mime_essence(mime_type))  {
+                // An empty encoding means the body isn't encoded at all,
+                // which every client accepts.
                 let encoding_ok = entity
                     .get_encoding()
-                    .map(|enc| accepted_encodings.contains(enc))
+                    .map(|enc| enc == "" || accepted_encodings.contains(enc))
                     .unwrap_or(false);
                 if encoding_ok {
                     return Some(*entity);
@@ -116,3 +368,11 @@ fn match_content<'a>(entities: capnp::struct_list::Reader<'a, web_site::entity::
     }
     return None
 }
+
+/// Strip any parameters (e.g. `; charset=utf-8`) from a mime type.
+fn mime_essence(mime_type: &str) -> &str {
+    match mime_type.find(';') {
+        Some(i) => mime_type[..i].trim(),
+        None => mime_type.trim(),
+    }
+}
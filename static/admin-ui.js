@@ -14,6 +14,62 @@ function post(url, data) {
   })
 }
 
+// Like `post()`, but for GET requests. The promise is rejected if the
+// request fails outright.
+function get(url) {
+  return new Promise((resolve, reject) => {
+    const xhr = new XMLHttpRequest();
+    xhr.onload = function () {
+      resolve(xhr)
+    }
+    xhr.onerror = reject;
+
+    xhr.open('GET', url);
+    xhr.send();
+  })
+}
+
+// Reload the page whenever the site changes, so it stays up to date.
+function watchSite(site) {
+  const retry = () => setTimeout(() => watchSite(site), 5000);
+  get("/changes/" + site).then((xhr) => {
+    if (xhr.status === 200) {
+      location.reload();
+    } else {
+      retry();
+    }
+  }, retry)
+}
+
 function offerSite(site) {
   post("/offer-site", site)
 }
+
+function saveSettings(site) {
+  const kind = document.getElementById("fallback-kind").value;
+  const path = document.getElementById("fallback-path").value;
+  const fallback = kind === "none" ? { kind } : { kind, path };
+  const defaultLanguage = document.getElementById("default-language").value;
+  const feed = {
+    title: document.getElementById("feed-title").value,
+    atomPath: document.getElementById("feed-atom-path").value,
+    rssPath: document.getElementById("feed-rss-path").value,
+  };
+  const siteUrl = document.getElementById("site-url").value;
+  const settings = { siteUrl, fallback, defaultLanguage, feed };
+  post("/settings/" + site, JSON.stringify(settings)).then((xhr) => {
+    if (xhr.status !== 200) {
+      alert("Saving settings failed: " + xhr.responseText);
+    }
+  })
+}
+
+function compactSite(site) {
+  post("/compact/" + site, "").then((xhr) => {
+    if (xhr.status === 200) {
+      alert(xhr.responseText);
+    } else {
+      alert("Compacting the site failed: " + xhr.responseText);
+    }
+  })
+}